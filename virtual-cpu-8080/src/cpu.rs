@@ -1,8 +1,8 @@
 use crate::instructions::*;
-use crate::machine::Machine;
+use crate::memory::Memory8080;
 use crate::registers::{Name16, Name8};
-use crate::state::State8080 as State;
-use virtual_cpu_core::{Program, Registers16, Registers8, Stack};
+use crate::state::{State8080 as State, INTERRUPT_TIMING};
+use virtual_cpu_core::{Bus, Cpu, Program, Registers16, Registers8, Stack};
 
 static OPCODE_TIMING: [usize; 256] = [
     4, 10, 7, 5, 5, 5, 7, 4, 4, 10, 7, 5, 5, 5, 7, 4, //0x00..0x0f
@@ -20,7 +20,7 @@ static OPCODE_TIMING: [usize; 256] = [
     11, 5, 10, 5, 17, 17, 7, 11, 11, 10, 10, 4, 17, 11, 7, 11, 11, 5, 10, 4, 17, 17, 7, 11,
 ];
 
-fn get_operand(state: &State, opcode: u8) -> u8 {
+fn get_operand<B: Bus<Address = u16>>(state: &State<B>, opcode: u8) -> u8 {
     let operand_code = opcode & 0x07;
     if operand_code == 0x06 {
         state.get_indirect8(Name16::HL)
@@ -29,7 +29,7 @@ fn get_operand(state: &State, opcode: u8) -> u8 {
     }
 }
 
-fn mov_for(state: &mut State, opcode: u8) {
+fn mov_for<B: Bus<Address = u16>>(state: &mut State<B>, opcode: u8) {
     let input_code = opcode & 0x07;
     let output_code = (opcode >> 3) & 0x07;
 
@@ -41,7 +41,7 @@ fn mov_for(state: &mut State, opcode: u8) {
     }
 }
 
-fn operate8(state: &mut State, opcode: u8, operand: u8) {
+fn operate8<B: Bus<Address = u16>>(state: &mut State<B>, opcode: u8, operand: u8) {
     match (opcode >> 3) & 0x07 {
         0x0 => state.add_ri8(operand),
         0x1 => state.adc_ri8(operand),
@@ -55,17 +55,17 @@ fn operate8(state: &mut State, opcode: u8, operand: u8) {
     }
 }
 
-fn unimplemented_instruction(s: &mut State, opcode: u8) {
+fn unimplemented_instruction<B: Bus<Address = u16>>(s: &mut State<B>, opcode: u8) {
     println!(
         "Error: unimplemented instruction 0x{:02x} at 0x{:04x}",
         opcode,
         s.p.get_pc()
     );
-    println!("{:?}", s);
+    println!("{:?}\n{:?}\n{:?}", s.r, s.s, s.p);
     panic!("unimplemented");
 }
 
-pub fn emulate_group0(instruction: &[u8], s: &mut State) {
+pub fn emulate_group0<B: Bus<Address = u16>>(instruction: &[u8], s: &mut State<B>) {
     let opcode = instruction[0];
 
     match opcode & 0x3f {
@@ -184,7 +184,7 @@ pub fn emulate_group0(instruction: &[u8], s: &mut State) {
     }
 }
 
-fn emulate_group3(instruction: &[u8], s: &mut State, m: &mut impl Machine) {
+fn emulate_group3<B: Bus<Address = u16>>(instruction: &[u8], s: &mut State<B>) {
     let opcode = instruction[0];
     match opcode & 0x7 {
         0x0 => s.ret_if(instruction),
@@ -202,8 +202,12 @@ fn emulate_group3(instruction: &[u8], s: &mut State, m: &mut impl Machine) {
         0x2 => s.jump_if(instruction),
         0x3 => match (opcode >> 3) & 0x7 {
             0x0 | 0x1 => s.jump_a(word_arg_from(instruction)), // JMP a16
-            0x2 => m.output(byte_arg_from(instruction), s.r.get8(Name8::A)), // OUT byte
-            0x3 => s.mov_ri8(Name8::A, m.input(byte_arg_from(instruction))), // IN byte
+            0x2 => s.m.output(byte_arg_from(instruction), s.r.get8(Name8::A)), // OUT byte
+            0x3 => {
+                // IN byte
+                let val = s.m.input(byte_arg_from(instruction));
+                s.mov_ri8(Name8::A, val);
+            }
             0x4 => {
                 // XTHL
                 let new_hl = s.pop_word();
@@ -237,7 +241,10 @@ fn emulate_group3(instruction: &[u8], s: &mut State, m: &mut impl Machine) {
     }
 }
 
-pub fn emulate_instruction(s: &mut State, m: &mut impl Machine) -> usize {
+pub fn emulate_instruction<B: Bus<Address = u16>>(s: &mut State<B>) -> usize {
+    if let Some(cycles) = s.service_interrupts() {
+        return cycles;
+    }
     let instruction = s.get_instruction();
     let opcode = instruction[0];
 
@@ -245,11 +252,229 @@ pub fn emulate_instruction(s: &mut State, m: &mut impl Machine) -> usize {
         0x00..=0x3f => emulate_group0(&instruction, s),
         0x40..=0x7f => mov_for(s, opcode),
         0x80..=0xbf => operate8(s, opcode, get_operand(s, opcode)),
-        0xc0..=0xff => emulate_group3(&instruction, s, m),
-
-        _ => unimplemented_instruction(s, opcode),
+        0xc0..=0xff => emulate_group3(&instruction, s),
     }
 
     s.p.advance();
-    OPCODE_TIMING[opcode as usize]
+    let cycles = OPCODE_TIMING[opcode as usize];
+    s.m.tick(cycles);
+    cycles
+}
+
+// An 8080 and the bus it runs against
+pub struct Cpu8080<B: Bus<Address = u16> = Memory8080> {
+    pub state: State<B>,
+    cycles: u64,
+}
+
+impl<B: Bus<Address = u16>> Cpu8080<B> {
+    pub fn new(state: State<B>) -> Cpu8080<B> {
+        Cpu8080 { state, cycles: 0 }
+    }
+}
+
+impl<B: Bus<Address = u16>> Cpu for Cpu8080<B> {
+    type Address = u16;
+    type State = State<B>;
+    type Bus = B;
+    type Register = Name16;
+
+    fn state(&self) -> &State<B> {
+        &self.state
+    }
+
+    fn state_mut(&mut self) -> &mut State<B> {
+        &mut self.state
+    }
+
+    fn bus(&self) -> &B {
+        &self.state.m
+    }
+
+    fn bus_mut(&mut self) -> &mut B {
+        &mut self.state.m
+    }
+
+    fn step(&mut self) -> usize {
+        let cycles = emulate_instruction(&mut self.state);
+        self.cycles += cycles as u64;
+        cycles
+    }
+
+    // RESET only clears PC and the interrupt enable
+    fn reset(&mut self) {
+        self.state.p.jump(0x0000);
+        self.state.set_interrupt_flag(false);
+        self.cycles = 0;
+    }
+
+    fn pc(&self) -> u16 {
+        self.state.p.get_pc()
+    }
+
+    fn set_pc(&mut self, addr: u16) {
+        self.state.p.jump(addr);
+    }
+
+    fn register(&self, reg: Name16) -> u16 {
+        self.state.r.get16(reg)
+    }
+
+    fn set_register(&mut self, reg: Name16, val: u16) {
+        self.state.r.set16(reg, val);
+    }
+
+    fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    fn interrupts_enabled(&self) -> bool {
+        self.state.int_enable
+    }
+
+    // Runs RST n straight away, unless interrupts are disabled. Devices on
+    // the bus can raise their own through acknowledge_interrupt instead.
+    fn interrupt(&mut self, n: u8) {
+        if self.state.int_enable {
+            self.state.trigger_interrupt(u16::from(n & 0x07));
+            self.state.m.tick(INTERRUPT_TIMING);
+            self.cycles += INTERRUPT_TIMING as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use virtual_cpu_core::{MappedMemory, Memory, Region};
+
+    // RAM with a device that echoes ports and can raise an interrupt
+    #[derive(Default)]
+    struct Board {
+        ram: Memory8080,
+        last_output: Option<(u8, u8)>,
+        interrupt: Option<u8>,
+        cycles: usize,
+    }
+
+    impl Memory for Board {
+        type Address = u16;
+
+        fn get_byte(&self, addr: u16) -> u8 {
+            self.ram.get_byte(addr)
+        }
+
+        fn set_byte(&mut self, addr: u16, val: u8) {
+            self.ram.set_byte(addr, val);
+        }
+
+        fn load(&mut self, addr: u16, data: &[u8]) {
+            self.ram.load(addr, data);
+        }
+
+        fn view(&self, start: u16, end: u16) -> &[u8] {
+            self.ram.view(start, end)
+        }
+    }
+
+    impl Bus for Board {
+        fn input(&mut self, port: u8) -> u8 {
+            port
+        }
+
+        fn output(&mut self, port: u8, val: u8) {
+            self.last_output = Some((port, val));
+        }
+
+        fn acknowledge_interrupt(&mut self) -> Option<u8> {
+            self.interrupt.take()
+        }
+
+        fn tick(&mut self, cycles: usize) {
+            self.cycles += cycles;
+        }
+    }
+
+    #[test]
+    fn steps_through_the_cpu_trait() {
+        let mut state = State::with_memory(Board::default());
+        state.s.set_sp(0x2000);
+        // IN 0x42; OUT 0x10; EI
+        state.m.load(0x0000, &[0xdb, 0x42, 0xd3, 0x10, 0xfb]);
+        let mut cpu = Cpu8080::new(state);
+        for _ in 0..3 {
+            cpu.step();
+        }
+        assert_eq!(cpu.bus().last_output, Some((0x10, 0x42)));
+        assert_eq!(cpu.cycles(), 24);
+        assert_eq!(cpu.bus().cycles, 24);
+
+        cpu.interrupt(7);
+        assert_eq!(cpu.pc(), 0x0038);
+        assert_eq!(cpu.state.pop_word(), 0x0005);
+        assert!(!cpu.interrupts_enabled());
+        cpu.reset();
+        assert_eq!(cpu.pc(), 0x0000);
+    }
+
+    #[test]
+    fn bus_raises_interrupts() {
+        let mut state = State::with_memory(Board::default());
+        state.s.set_sp(0x2000);
+        // EI; NOP
+        state.m.load(0x0100, &[0xfb, 0x00]);
+        state.p.jump(0x0100);
+        state.m.interrupt = Some(2);
+        // Nothing is taken while interrupts are off
+        emulate_instruction(&mut state);
+        assert_eq!(state.m.interrupt, Some(2));
+
+        assert_eq!(emulate_instruction(&mut state), INTERRUPT_TIMING);
+        assert_eq!(state.m.interrupt, None);
+        assert_eq!(state.p.get_pc(), 0x0010);
+        assert_eq!(state.pop_word(), 0x0101);
+        assert_eq!(state.m.cycles, 4 + INTERRUPT_TIMING);
+    }
+
+    #[test]
+    fn runs_from_mapped_rom() {
+        let mut m = MappedMemory::new();
+        // MVI A,0x42; STA 0x0000; STA 0x4800
+        m.map_rom(0x0000, vec![0x3e, 0x42, 0x32, 0x00, 0x00, 0x32, 0x00, 0x48]);
+        // 1K of RAM repeated over 4K, and all of it again at 0x6000
+        m.map(0x4000, 0x4fff, Region::Ram(vec![0; 0x400]));
+        m.map_mirror(0x6000, 0x6fff, 0x4000);
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        let trapped = writes.clone();
+        m.trap_rom_writes(move |addr, val| trapped.borrow_mut().push((addr, val)));
+
+        let mut cpu = Cpu8080::new(State::with_memory(m));
+        for _ in 0..3 {
+            cpu.step();
+        }
+        assert_eq!(cpu.bus().get_byte(0x0000), 0x3e);
+        assert_eq!(*writes.borrow(), vec![(0x0000, 0x42)]);
+        assert_eq!(cpu.bus().get_byte(0x4000), 0x42);
+        assert_eq!(cpu.bus().get_byte(0x6c00), 0x42);
+        assert_eq!(cpu.bus().get_byte(0x8000), 0xff);
+    }
+
+    #[test]
+    fn fetches_operands_across_regions() {
+        let mut m = MappedMemory::new();
+        // NOPs up to MVI A at 0x00ff, whose operand comes from open bus
+        let mut rom = vec![0x00; 0x100];
+        rom[0xff] = 0x3e;
+        m.map_rom(0x0000, rom);
+        m.set_open_bus(0x5a);
+
+        let mut cpu = Cpu8080::new(State::with_memory(m));
+        for _ in 0..0x100 {
+            cpu.step();
+        }
+        assert_eq!(cpu.state.r.a, 0x5a);
+        assert_eq!(cpu.pc(), 0x0101);
+    }
 }
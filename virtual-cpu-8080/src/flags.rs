@@ -90,6 +90,7 @@ impl Flags for Flags8080 {
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
 
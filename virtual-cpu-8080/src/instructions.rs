@@ -17,7 +17,7 @@ pub fn register_for_code(code: u8) -> Name8 {
     }
 }
 
-pub fn predicate_for(opcode: u8) -> impl (Fn(&Flags8080) -> bool) {
+pub fn predicate_for(opcode: u8) -> impl Fn(&Flags8080) -> bool {
     match (opcode >> 3) & 0x07 {
         0x0 => Flags8080::is_nz,
         0x1 => Flags8080::is_z,
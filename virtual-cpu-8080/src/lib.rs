@@ -1,7 +1,6 @@
 pub mod cpu;
 pub mod flags;
 pub mod instructions;
-pub mod machine;
 pub mod memory;
 pub mod program;
 pub mod registers;
@@ -9,6 +8,6 @@ pub mod stack;
 pub mod state;
 
 pub use self::{
-    flags::Flags8080, machine::Machine, memory::Memory8080, program::Program8080,
+    cpu::Cpu8080, flags::Flags8080, memory::Memory8080, program::Program8080,
     registers::Registers8080, stack::Stack8080, state::State8080,
 };
@@ -1,5 +1,5 @@
 use std::fmt;
-use virtual_cpu_core::Memory;
+use virtual_cpu_core::{Bus, Memory};
 
 pub struct Memory8080 {
     m: [u8; 65536],
@@ -38,6 +38,9 @@ impl Memory for Memory8080 {
     }
 }
 
+// Plain RAM, with nothing on the ports
+impl Bus for Memory8080 {}
+
 impl fmt::Debug for Memory8080 {
     fn fmt(&self, _f: &mut fmt::Formatter) -> fmt::Result {
         Ok(())
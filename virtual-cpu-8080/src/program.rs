@@ -1,8 +1,10 @@
+use std::fmt;
+use std::marker::PhantomData;
 use virtual_cpu_core::{Memory, Program, Stack};
 
+use crate::instructions::apply_offset;
 use crate::memory::Memory8080;
 use crate::stack::Stack8080;
-use crate::instructions::apply_offset;
 
 static INSTRUCTION_LENGTH: [u16; 256] = [
     1, 3, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1, // 0x00..0x0f
@@ -23,36 +25,62 @@ static INSTRUCTION_LENGTH: [u16; 256] = [
     1, 1, 3, 1, 3, 1, 2, 1, 1, 1, 3, 1, 3, 3, 2, 1, // 0xc0..0xcf
 ];
 
-#[derive(Default, Debug)]
-pub struct Program8080 {
+pub struct Program8080<M: Memory<Address = u16> = Memory8080> {
     pc: u16,
     instruction_length: u16,
+    m: PhantomData<M>,
 }
 
-impl Program8080 {
-    pub fn new() -> Program8080 {
-        Program8080::default()
+impl<M: Memory<Address = u16>> Program8080<M> {
+    pub fn new() -> Program8080<M> {
+        Program8080 {
+            pc: 0,
+            instruction_length: 0,
+            m: PhantomData,
+        }
     }
 
     pub fn jr(&mut self, offset: u8) {
         self.jump(apply_offset(self.pc, offset));
     }
+
+    // Where execution carries on after the current instruction
+    pub fn next_pc(&self) -> u16 {
+        self.pc + self.instruction_length
+    }
+}
+
+impl<M: Memory<Address = u16>> Default for Program8080<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: Memory<Address = u16>> fmt::Debug for Program8080<M> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Program8080")
+            .field("pc", &self.pc)
+            .field("instruction_length", &self.instruction_length)
+            .finish()
+    }
 }
 
-impl Program for Program8080 {
+impl<M: Memory<Address = u16>> Program for Program8080<M> {
     type Address = u16;
-    type Mem = Memory8080;
-    type Stk = Stack8080;
+    type Mem = M;
+    type Stk = Stack8080<M>;
 
     fn get_pc(&self) -> u16 {
         self.pc
     }
 
-    fn get_instruction(&mut self, m: &Memory8080) -> Vec<u8> {
+    fn get_instruction(&mut self, m: &M) -> Vec<u8> {
         let opcode = m.get_byte(self.pc);
         self.instruction_length = INSTRUCTION_LENGTH[opcode as usize];
-        m.view(self.pc, self.pc + self.instruction_length - 1)
-            .to_vec()
+        // A byte at a time, as the operands can sit in another region
+        (0..self.instruction_length)
+            .map(|i| m.get_byte(self.pc.wrapping_add(i)))
+            .collect()
     }
 
     fn advance(&mut self) {
@@ -65,12 +93,12 @@ impl Program for Program8080 {
         self.instruction_length = 0;
     }
 
-    fn call(&mut self, m: &mut Memory8080, s: &mut Stack8080, addr: u16) {
-        s.push_word(m, self.pc + self.instruction_length);
+    fn call(&mut self, m: &mut M, s: &mut Stack8080<M>, addr: u16) {
+        s.push_word(m, self.next_pc());
         self.jump(addr);
     }
 
-    fn ret(&mut self, m: &mut Memory8080, s: &mut Stack8080) {
+    fn ret(&mut self, m: &mut M, s: &mut Stack8080<M>) {
         self.jump(s.pop_word(m));
     }
 }
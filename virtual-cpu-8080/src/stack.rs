@@ -1,22 +1,40 @@
+use std::fmt;
+use std::marker::PhantomData;
 use virtual_cpu_core::bytes::*;
 use virtual_cpu_core::{Memory, Stack};
 
 use crate::memory::Memory8080;
 
-#[derive(Default, Debug)]
-pub struct Stack8080 {
+// The stack lives in whatever memory M the state has
+pub struct Stack8080<M: Memory<Address = u16> = Memory8080> {
     sp: u16,
+    m: PhantomData<M>,
 }
 
-impl Stack8080 {
-    pub fn new() -> Stack8080 {
-        Stack8080::default()
+impl<M: Memory<Address = u16>> Stack8080<M> {
+    pub fn new() -> Stack8080<M> {
+        Stack8080 {
+            sp: 0,
+            m: PhantomData,
+        }
     }
 }
 
-impl Stack for Stack8080 {
+impl<M: Memory<Address = u16>> Default for Stack8080<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: Memory<Address = u16>> fmt::Debug for Stack8080<M> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Stack8080").field("sp", &self.sp).finish()
+    }
+}
+
+impl<M: Memory<Address = u16>> Stack for Stack8080<M> {
     type Address = u16;
-    type Mem = Memory8080;
+    type Mem = M;
 
     fn get_sp(&self) -> u16 {
         self.sp
@@ -25,24 +43,24 @@ impl Stack for Stack8080 {
         self.sp = val;
     }
 
-    fn pop_byte(&mut self, m: &mut Memory8080) -> u8 {
+    fn pop_byte(&mut self, m: &mut M) -> u8 {
         self.sp += 1;
         m.get_byte(self.sp - 1)
     }
 
-    fn push_byte(&mut self, m: &mut Memory8080, val: u8) {
+    fn push_byte(&mut self, m: &mut M, val: u8) {
         self.sp -= 1;
         m.set_byte(self.sp, val);
     }
 
-    fn pop_word(&mut self, m: &mut Memory8080) -> u16 {
+    fn pop_word(&mut self, m: &mut M) -> u16 {
         let low_order = self.pop_byte(m);
         let high_order = self.pop_byte(m);
 
         assemble_word(high_order, low_order)
     }
 
-    fn push_word(&mut self, m: &mut Memory8080, val: u16) {
+    fn push_word(&mut self, m: &mut M, val: u16) {
         self.push_byte(m, high_order_byte(val));
         self.push_byte(m, low_order_byte(val));
     }
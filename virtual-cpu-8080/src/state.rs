@@ -1,4 +1,4 @@
-use virtual_cpu_core::{bytes::*, Memory, Program, Registers16, Registers8, Stack};
+use virtual_cpu_core::{bytes::*, Bus, Memory, Program, Registers16, Registers8, Stack};
 
 use crate::flags::Flags8080;
 use crate::instructions::{predicate_for, word_arg_from};
@@ -7,19 +7,28 @@ use crate::program::Program8080;
 use crate::registers::*;
 use crate::stack::Stack8080;
 
+// Cycles taken to accept an interrupt, which runs the RST placed on the bus
+pub const INTERRUPT_TIMING: usize = 11;
+
 #[derive(Debug, Default)]
-pub struct State8080 {
-    pub m: Memory8080,
-    pub s: Stack8080,
-    pub p: Program8080,
+pub struct State8080<M: Memory<Address = u16> = Memory8080> {
+    pub m: M,
+    pub s: Stack8080<M>,
+    pub p: Program8080<M>,
     pub r: Registers8080,
     pub int_enable: bool,
 }
 
 impl State8080 {
     pub fn new() -> State8080 {
+        State8080::with_memory(Memory8080::new())
+    }
+}
+
+impl<M: Memory<Address = u16>> State8080<M> {
+    pub fn with_memory(m: M) -> State8080<M> {
         State8080 {
-            m: Memory8080::new(),
+            m,
             s: Stack8080::new(),
             p: Program8080::new(),
             r: Registers8080::new(),
@@ -122,7 +131,7 @@ impl State8080 {
         }
     }
 
-    pub fn jr_if(&mut self, offset: u8, predicate: impl (Fn(&Flags8080) -> bool)) {
+    pub fn jr_if(&mut self, offset: u8, predicate: impl Fn(&Flags8080) -> bool) {
         if self.test_flags(predicate) {
             self.jr_o(offset);
         }
@@ -254,3 +263,97 @@ impl State8080 {
         self.int_enable = false;
     }
 }
+
+impl<B: Bus<Address = u16>> State8080<B> {
+    // Asks the bus for an interrupt when they're enabled, returning the
+    // cycles spent taking it if there was one
+    pub fn service_interrupts(&mut self) -> Option<usize> {
+        if !self.int_enable {
+            return None;
+        }
+        let n = self.m.acknowledge_interrupt()?;
+        self.trigger_interrupt(u16::from(n & 0x07));
+        self.m.tick(INTERRUPT_TIMING);
+        Some(INTERRUPT_TIMING)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 32K fixed, then a 32K window onto one of two banks picked by writing
+    // to 0xffff
+    struct Banked {
+        fixed: Vec<u8>,
+        banks: [Vec<u8>; 2],
+        bank: usize,
+    }
+
+    impl Banked {
+        fn new() -> Banked {
+            Banked {
+                fixed: vec![0; 0x8000],
+                banks: [vec![0; 0x8000], vec![0; 0x8000]],
+                bank: 0,
+            }
+        }
+    }
+
+    impl Memory for Banked {
+        type Address = u16;
+
+        fn get_byte(&self, addr: u16) -> u8 {
+            match addr {
+                0x0000..=0x7fff => self.fixed[addr as usize],
+                _ => self.banks[self.bank][(addr - 0x8000) as usize],
+            }
+        }
+
+        fn set_byte(&mut self, addr: u16, val: u8) {
+            match addr {
+                0x0000..=0x7fff => self.fixed[addr as usize] = val,
+                0xffff => self.bank = (val & 0x01) as usize,
+                _ => self.banks[self.bank][(addr - 0x8000) as usize] = val,
+            }
+        }
+
+        fn load(&mut self, addr: u16, data: &[u8]) {
+            for (i, &val) in data.iter().enumerate() {
+                self.set_byte(addr + i as u16, val);
+            }
+        }
+
+        fn view(&self, start: u16, end: u16) -> &[u8] {
+            match start {
+                0x0000..=0x7fff => &self.fixed[start as usize..=end as usize],
+                _ => &self.banks[self.bank][(start - 0x8000) as usize..=(end - 0x8000) as usize],
+            }
+        }
+    }
+
+    #[test]
+    fn runs_on_any_memory() {
+        let mut s = State8080::with_memory(Banked::new());
+        s.s.set_sp(0x8000);
+        s.m.set_byte(0xffff, 1);
+        s.mov_ri8(Name8::A, 0x42);
+        s.mov_ar8(0x9000, Name8::A);
+        s.m.set_byte(0xffff, 0);
+        assert_eq!(s.m.get_byte(0x9000), 0x00);
+        s.m.set_byte(0xffff, 1);
+        assert_eq!(s.m.get_byte(0x9000), 0x42);
+
+        s.p.jump(0x1000);
+        s.m.load(0x1000, &[0xcd, 0x00, 0x20]);
+        assert_eq!(s.get_instruction(), vec![0xcd, 0x00, 0x20]);
+        s.call_a(0x2000);
+        assert_eq!(s.p.get_pc(), 0x2000);
+        assert_eq!(s.m.get_word(0x7ffe), 0x1003);
+        s.push_r16(Name16::BC);
+        s.pop_r16(Name16::HL);
+        s.ret();
+        assert_eq!(s.p.get_pc(), 0x1003);
+        assert_eq!(s.s.get_sp(), 0x8000);
+    }
+}
@@ -0,0 +1,24 @@
+use crate::memory::Memory;
+
+// Everything a CPU talks to: memory, and optionally I/O ports, devices
+// raising interrupts and hardware that runs off the CPU clock. Memory-mapped
+// hardware goes behind get_byte and set_byte. The defaults suit a bus with
+// nothing but memory on it.
+pub trait Bus: Memory {
+    // Ports nothing answers float high
+    fn input(&mut self, _port: u8) -> u8 {
+        0xff
+    }
+
+    fn output(&mut self, _port: u8, _val: u8) {}
+
+    // Called when the CPU is ready to take an interrupt. Returns the number
+    // of the interrupt to take, acknowledging it, or None if nothing is
+    // asking for one.
+    fn acknowledge_interrupt(&mut self) -> Option<u8> {
+        None
+    }
+
+    // Called with the cycles the CPU has just run
+    fn tick(&mut self, _cycles: usize) {}
+}
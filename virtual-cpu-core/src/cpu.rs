@@ -0,0 +1,36 @@
+use crate::bus::Bus;
+
+// A CPU together with everything it needs to run, so debuggers, runners and
+// test tools can be written once for any of them
+pub trait Cpu {
+    type Address: Copy;
+    type State;
+    type Bus: Bus<Address = Self::Address>;
+    type Register: Copy;
+
+    fn state(&self) -> &Self::State;
+    fn state_mut(&mut self) -> &mut Self::State;
+    fn bus(&self) -> &Self::Bus;
+    fn bus_mut(&mut self) -> &mut Self::Bus;
+
+    // Runs one instruction, or the dispatch of an interrupt, and returns the
+    // cycles it took
+    fn step(&mut self) -> usize;
+
+    // Back to the power-on state, leaving memory alone
+    fn reset(&mut self);
+
+    fn pc(&self) -> Self::Address;
+    fn set_pc(&mut self, addr: Self::Address);
+    fn register(&self, reg: Self::Register) -> u16;
+    fn set_register(&mut self, reg: Self::Register, val: u16);
+
+    // Cycles run since creation or the last reset
+    fn cycles(&self) -> u64;
+
+    fn interrupts_enabled(&self) -> bool;
+
+    // Raises interrupt n. Each CPU takes it the way its hardware would,
+    // either straight away or on a later step.
+    fn interrupt(&mut self, n: u8);
+}
@@ -1,11 +1,17 @@
 pub mod bytes;
+mod bus;
+mod cpu;
 mod flags;
+mod mapped;
 mod memory;
 mod program;
 mod registers;
 mod stack;
 
+pub use self::bus::Bus;
+pub use self::cpu::Cpu;
 pub use self::flags::Flags;
+pub use self::mapped::{Device, MappedMemory, Region};
 pub use self::memory::Memory;
 pub use self::program::Program;
 pub use self::registers::{Registers16, Registers8};
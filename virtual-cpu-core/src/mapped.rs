@@ -0,0 +1,389 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::bus::Bus;
+use crate::memory::Memory;
+
+// How many mirrors of mirrors an address can pass through. Mapping one that
+// goes further, or loops, is refused.
+const MAX_MIRROR_DEPTH: usize = 16;
+
+// Hardware answering for a range of addresses. Offsets count from the start
+// of the range it's mapped at.
+pub trait Device {
+    fn read(&self, offset: u16) -> u8;
+    fn write(&mut self, offset: u16, val: u8);
+
+    // Called with the cycles the CPU has just run
+    fn tick(&mut self, _cycles: usize) {}
+}
+
+// Lets the caller keep a handle on a device once it's mapped
+impl<D: Device> Device for Rc<RefCell<D>> {
+    fn read(&self, offset: u16) -> u8 {
+        self.borrow().read(offset)
+    }
+
+    fn write(&mut self, offset: u16, val: u8) {
+        self.borrow_mut().write(offset, val)
+    }
+
+    fn tick(&mut self, cycles: usize) {
+        self.borrow_mut().tick(cycles)
+    }
+}
+
+pub enum Region {
+    Rom(Vec<u8>),
+    Ram(Vec<u8>),
+    // The same range again starting at another address, e.g. echo RAM
+    Mirror(u16),
+    Device(Box<dyn Device>),
+}
+
+struct Mapping {
+    start: u16,
+    end: u16,
+    region: Region,
+}
+
+// Memory put together from regions. Regions mapped later cover earlier ones
+// where they overlap, ROM and RAM smaller than their range repeat to fill it,
+// and addresses nothing answers read as the open-bus value. Writes to ROM
+// are dropped, or handed to the trap if one is set.
+pub struct MappedMemory {
+    mappings: Vec<Mapping>,
+    open_bus: u8,
+    rom_write_trap: Option<Box<dyn FnMut(u16, u8)>>,
+}
+
+impl MappedMemory {
+    pub fn new() -> MappedMemory {
+        MappedMemory {
+            mappings: Vec::new(),
+            open_bus: 0xff,
+            rom_write_trap: None,
+        }
+    }
+
+    pub fn map(&mut self, start: u16, end: u16, region: Region) {
+        check_range(start, end);
+        match &region {
+            Region::Rom(data) | Region::Ram(data) if data.is_empty() => {
+                panic!("Region 0x{:04x}..0x{:04x} has no bytes", start, end)
+            }
+            Region::Mirror(target) => {
+                let target_end = u32::from(*target) + u32::from(end - start);
+                if u32::from(*target) <= u32::from(end) && u32::from(start) <= target_end {
+                    panic!("Mirror 0x{:04x}..0x{:04x} overlaps itself", start, end);
+                }
+            }
+            _ => (),
+        }
+        let mirror = matches!(region, Region::Mirror(_));
+        self.mappings.push(Mapping { start, end, region });
+        if mirror {
+            if let Some(addr) = (start..=end).find(|&addr| self.follow(addr).is_err()) {
+                self.mappings.pop();
+                panic!(
+                    "Mirror 0x{:04x}..0x{:04x} loops at 0x{:04x}",
+                    start, end, addr
+                );
+            }
+        }
+    }
+
+    pub fn map_rom(&mut self, start: u16, data: Vec<u8>) {
+        assert!(!data.is_empty(), "ROM at 0x{:04x} has no bytes", start);
+        let end = usize::from(start) + data.len() - 1;
+        assert!(
+            end <= 0xffff,
+            "{} bytes of ROM at 0x{:04x} run past 0xffff",
+            data.len(),
+            start
+        );
+        self.map(start, end as u16, Region::Rom(data));
+    }
+
+    pub fn map_ram(&mut self, start: u16, end: u16) {
+        check_range(start, end);
+        let size = usize::from(end - start) + 1;
+        self.map(start, end, Region::Ram(vec![0; size]));
+    }
+
+    pub fn map_mirror(&mut self, start: u16, end: u16, target: u16) {
+        self.map(start, end, Region::Mirror(target));
+    }
+
+    pub fn map_device(&mut self, start: u16, end: u16, device: impl Device + 'static) {
+        self.map(start, end, Region::Device(Box::new(device)));
+    }
+
+    pub fn open_bus(&self) -> u8 {
+        self.open_bus
+    }
+
+    pub fn set_open_bus(&mut self, val: u8) {
+        self.open_bus = val;
+    }
+
+    // Calls trap with the address and value of every write to ROM
+    pub fn trap_rom_writes(&mut self, trap: impl FnMut(u16, u8) + 'static) {
+        self.rom_write_trap = Some(Box::new(trap));
+    }
+
+    pub fn clear_rom_write_trap(&mut self) {
+        self.rom_write_trap = None;
+    }
+
+    fn find(&self, addr: u16) -> Option<(usize, u16)> {
+        let index = self
+            .mappings
+            .iter()
+            .rposition(|m| m.start <= addr && addr <= m.end)?;
+        Some((index, addr - self.mappings[index].start))
+    }
+
+    // Follows mirrors to the mapping that answers for the address, giving
+    // the address within it, or an error if there are too many to follow
+    fn follow(&self, addr: u16) -> Result<Option<(usize, u16)>, ()> {
+        let mut target = addr;
+        for _ in 0..MAX_MIRROR_DEPTH {
+            let (index, offset) = match self.find(target) {
+                Some(found) => found,
+                None => return Ok(None),
+            };
+            match &self.mappings[index].region {
+                Region::Mirror(next) => target = next.wrapping_add(offset),
+                _ => return Ok(Some((index, target))),
+            }
+        }
+        Err(())
+    }
+
+    // map() refuses mirrors that can't be followed, so this always ends
+    fn resolve(&self, addr: u16) -> Option<(usize, u16)> {
+        self.follow(addr).unwrap_or(None)
+    }
+
+    // Loading programs the ROM, the same as burning the chip
+    fn write(&mut self, addr: u16, val: u8, loading: bool) {
+        let (index, addr) = match self.resolve(addr) {
+            Some(found) => found,
+            None => return,
+        };
+        let mapping = &mut self.mappings[index];
+        let offset = addr - mapping.start;
+        match &mut mapping.region {
+            Region::Rom(data) if loading => {
+                let len = data.len();
+                data[offset as usize % len] = val;
+            }
+            Region::Rom(_) => {
+                if let Some(trap) = &mut self.rom_write_trap {
+                    trap(addr, val);
+                }
+            }
+            Region::Ram(data) => {
+                let len = data.len();
+                data[offset as usize % len] = val;
+            }
+            Region::Mirror(_) => panic!("shouldn't happen"),
+            Region::Device(device) => device.write(offset, val),
+        }
+    }
+}
+
+fn check_range(start: u16, end: u16) {
+    assert!(
+        start <= end,
+        "Region 0x{:04x}..0x{:04x} is backwards",
+        start,
+        end
+    );
+}
+
+impl Default for MappedMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Memory for MappedMemory {
+    type Address = u16;
+
+    fn get_byte(&self, addr: u16) -> u8 {
+        let (index, addr) = match self.resolve(addr) {
+            Some(found) => found,
+            None => return self.open_bus,
+        };
+        let mapping = &self.mappings[index];
+        let offset = addr - mapping.start;
+        match &mapping.region {
+            Region::Rom(data) | Region::Ram(data) => data[offset as usize % data.len()],
+            Region::Mirror(_) => panic!("shouldn't happen"),
+            Region::Device(device) => device.read(offset),
+        }
+    }
+
+    fn set_byte(&mut self, addr: u16, val: u8) {
+        self.write(addr, val, false);
+    }
+
+    fn load(&mut self, base: u16, data: &[u8]) {
+        for (i, &val) in data.iter().enumerate() {
+            self.write(base.wrapping_add(i as u16), val, true);
+        }
+    }
+
+    // Only works within a single ROM or RAM region that nothing covers
+    fn view(&self, start: u16, end: u16) -> &[u8] {
+        let (mut from, mut to) = (start, end);
+        for _ in 0..MAX_MIRROR_DEPTH {
+            let (index, offset) = match self.find(from) {
+                Some(found) => found,
+                None => break,
+            };
+            let covered = self.mappings[index + 1..]
+                .iter()
+                .any(|m| m.start <= to && from <= m.end);
+            let mapping = &self.mappings[index];
+            match &mapping.region {
+                _ if covered || to > mapping.end => break,
+                Region::Rom(data) | Region::Ram(data) => {
+                    let s = offset as usize % data.len();
+                    let e = s + usize::from(end - start);
+                    if e < data.len() {
+                        return &data[s..=e];
+                    }
+                    break;
+                }
+                Region::Mirror(target) => {
+                    from = target.wrapping_add(offset);
+                    to = from.wrapping_add(end - start);
+                }
+                Region::Device(_) => break,
+            }
+        }
+        panic!("Cannot view 0x{:04x}..0x{:04x} as one region", start, end);
+    }
+}
+
+// No ports or interrupts of its own, but devices get ticked
+impl Bus for MappedMemory {
+    fn tick(&mut self, cycles: usize) {
+        for mapping in self.mappings.iter_mut() {
+            if let Region::Device(device) = &mut mapping.region {
+                device.tick(cycles);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for MappedMemory {
+    fn fmt(&self, _f: &mut fmt::Formatter) -> fmt::Result {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Latch {
+        val: u8,
+        cycles: usize,
+    }
+
+    impl Device for Latch {
+        fn read(&self, offset: u16) -> u8 {
+            self.val.wrapping_add(offset as u8)
+        }
+
+        fn write(&mut self, _offset: u16, val: u8) {
+            self.val = val;
+        }
+
+        fn tick(&mut self, cycles: usize) {
+            self.cycles += cycles;
+        }
+    }
+
+    #[test]
+    fn rom_ram_mirror_and_open_bus() {
+        let mut m = MappedMemory::new();
+        // 2K ROM decoded over 4K, 1K RAM mirrored once above it
+        m.map(0x0000, 0x0fff, Region::Rom(vec![0xaa; 0x800]));
+        m.map_ram(0x2000, 0x23ff);
+        m.map_mirror(0x2400, 0x27ff, 0x2000);
+        m.load(0x0801, &[0x12]);
+        assert_eq!(m.get_byte(0x0001), 0x12);
+        assert_eq!(m.get_byte(0x0000), 0xaa);
+
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        let trapped = writes.clone();
+        m.trap_rom_writes(move |addr, val| trapped.borrow_mut().push((addr, val)));
+        m.set_byte(0x0001, 0x34);
+        assert_eq!(m.get_byte(0x0001), 0x12);
+        assert_eq!(*writes.borrow(), vec![(0x0001, 0x34)]);
+
+        m.set_word(0x2402, 0xbeef);
+        assert_eq!(m.get_word(0x2002), 0xbeef);
+        assert_eq!(m.view(0x2402, 0x2403), &[0xef, 0xbe]);
+
+        assert_eq!(m.get_byte(0x8000), 0xff);
+        m.set_open_bus(0x00);
+        m.set_byte(0x8000, 0x55);
+        assert_eq!(m.get_byte(0x8000), 0x00);
+    }
+
+    #[test]
+    fn devices_cover_earlier_regions() {
+        let latch = Rc::new(RefCell::new(Latch::default()));
+        let mut m = MappedMemory::new();
+        m.map_ram(0x0000, 0xffff);
+        m.map_device(0xff00, 0xff0f, latch.clone());
+        m.set_byte(0xff00, 0x40);
+        assert_eq!(m.get_byte(0xff03), 0x43);
+        assert_eq!(m.get_byte(0xff10), 0x00);
+        assert_eq!(latch.borrow().val, 0x40);
+
+        m.tick(12);
+        assert_eq!(latch.borrow().cycles, 12);
+        assert_eq!(m.view(0xfe00, 0xfeff).len(), 0x100);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot view 0xfef0..0xff10 as one region")]
+    fn cannot_view_across_devices() {
+        let mut m = MappedMemory::new();
+        m.map_ram(0x0000, 0xffff);
+        m.map_device(0xff00, 0xff0f, Latch::default());
+        m.view(0xfef0, 0xff10);
+    }
+
+    #[test]
+    #[should_panic(expected = "Mirror 0x3800..0x3fff loops at 0x3800")]
+    fn mirror_loops_are_refused() {
+        let mut m = MappedMemory::new();
+        m.map_mirror(0x1000, 0x1fff, 0x3000);
+        m.map_mirror(0x3000, 0x37ff, 0x5000);
+        m.map_mirror(0x3800, 0x3fff, 0x1800);
+    }
+
+    #[test]
+    #[should_panic(expected = "Region 0x2000..0x1000 is backwards")]
+    fn backwards_ram_is_refused() {
+        MappedMemory::new().map_ram(0x2000, 0x1000);
+    }
+
+    #[test]
+    #[should_panic(expected = "16 bytes of ROM at 0xfff8 run past 0xffff")]
+    fn rom_must_fit_below_the_top() {
+        let mut m = MappedMemory::new();
+        m.map_rom(0xfff0, vec![0; 16]);
+        m.map_rom(0xfff8, vec![0; 16]);
+    }
+}
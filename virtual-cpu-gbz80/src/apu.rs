@@ -0,0 +1,833 @@
+use crate::rtc::CYCLES_PER_SECOND;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+const FRAME_SEQUENCER_PERIOD: usize = 8192;
+
+const DUTY_PATTERNS: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// Bits that always read back as 1, for 0xff10 to 0xff26
+static READ_MASKS: [u8; 0x17] = [
+    0x80, 0x3f, 0x00, 0xff, 0xbf, // NR10-NR14
+    0xff, 0x3f, 0x00, 0xff, 0xbf, // NR20-NR24
+    0x7f, 0xff, 0x9f, 0xff, 0xbf, // NR30-NR34
+    0xff, 0xff, 0x00, 0x00, 0xbf, // NR40-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+];
+
+#[derive(Debug, Default)]
+struct Length {
+    counter: u16,
+    enabled: bool,
+}
+
+impl Length {
+    fn load(&mut self, max: u16, val: u16) {
+        self.counter = max - val;
+    }
+
+    fn trigger(&mut self, max: u16) {
+        if self.counter == 0 {
+            self.counter = max;
+        }
+    }
+
+    // Returns false once the counter runs out and the channel should stop
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter != 0;
+        }
+        true
+    }
+}
+
+#[derive(Debug, Default)]
+struct Envelope {
+    initial: u8,
+    increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, val: u8) {
+        self.initial = val >> 4;
+        self.increase = (val & 0x08) != 0;
+        self.period = val & 0x07;
+    }
+
+    // The DAC is off when the top five bits of NRx2 are all clear
+    fn dac_enabled(&self) -> bool {
+        self.initial != 0 || self.increase
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial;
+        self.timer = self.period;
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    shadow: u16,
+    enabled: bool,
+}
+
+#[derive(Debug, Default)]
+struct Square {
+    enabled: bool,
+    duty: u8,
+    position: u8,
+    frequency: u16,
+    timer: u16,
+    length: Length,
+    envelope: Envelope,
+    sweep: Option<Sweep>,
+}
+
+impl Square {
+    fn with_sweep() -> Square {
+        Square {
+            sweep: Some(Sweep::default()),
+            ..Square::default()
+        }
+    }
+
+    fn period(&self) -> u16 {
+        (2048 - self.frequency) * 4
+    }
+
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                if let Some(sweep) = self.sweep.as_mut() {
+                    sweep.period = (val >> 4) & 0x07;
+                    sweep.negate = (val & 0x08) != 0;
+                    sweep.shift = val & 0x07;
+                }
+            }
+            1 => {
+                self.duty = val >> 6;
+                self.length.load(64, u16::from(val & 0x3f));
+            }
+            2 => {
+                self.envelope.write(val);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x700) | u16::from(val),
+            4 => {
+                self.frequency = (self.frequency & 0xff) | (u16::from(val & 0x07) << 8);
+                self.length.enabled = (val & 0x40) != 0;
+                if (val & 0x80) != 0 {
+                    self.trigger();
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger(64);
+        self.timer = self.period();
+        self.envelope.trigger();
+        let frequency = self.frequency;
+        if let Some(sweep) = self.sweep.as_mut() {
+            sweep.shadow = frequency;
+            sweep.timer = if sweep.period == 0 { 8 } else { sweep.period };
+            sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+            if sweep.shift != 0 && self.sweep_target().is_none() {
+                self.enabled = false;
+            }
+        }
+    }
+
+    // The next sweep frequency, or None if it overflows 11 bits
+    fn sweep_target(&self) -> Option<u16> {
+        let sweep = self.sweep.as_ref()?;
+        let delta = sweep.shadow >> sweep.shift;
+        let target = if sweep.negate {
+            sweep.shadow - delta
+        } else {
+            sweep.shadow + delta
+        };
+        if target > 2047 {
+            None
+        } else {
+            Some(target)
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        let shift = match self.sweep.as_mut() {
+            Some(sweep) => {
+                if sweep.timer > 0 {
+                    sweep.timer -= 1;
+                }
+                if sweep.timer != 0 {
+                    return;
+                }
+                sweep.timer = if sweep.period == 0 { 8 } else { sweep.period };
+                if !sweep.enabled || sweep.period == 0 {
+                    return;
+                }
+                sweep.shift
+            }
+            None => return,
+        };
+        match self.sweep_target() {
+            Some(target) if shift != 0 => {
+                self.frequency = target;
+                if let Some(sweep) = self.sweep.as_mut() {
+                    sweep.shadow = target;
+                }
+                if self.sweep_target().is_none() {
+                    self.enabled = false;
+                }
+            }
+            Some(_) => (),
+            None => self.enabled = false,
+        }
+    }
+
+    fn step(&mut self) {
+        self.timer -= 1;
+        if self.timer == 0 {
+            self.timer = self.period();
+            self.position = (self.position + 1) & 0x07;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        let high = (DUTY_PATTERNS[self.duty as usize] >> (7 - self.position)) & 0x01 != 0;
+        if self.enabled && high {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Wave {
+    enabled: bool,
+    dac_enabled: bool,
+    volume_code: u8,
+    position: u8,
+    frequency: u16,
+    timer: u16,
+    length: Length,
+    ram: [u8; 0x10],
+}
+
+impl Wave {
+    fn new() -> Wave {
+        Wave {
+            enabled: false,
+            dac_enabled: false,
+            volume_code: 0,
+            position: 0,
+            frequency: 0,
+            timer: 0,
+            length: Length::default(),
+            ram: [0; 0x10],
+        }
+    }
+
+    fn period(&self) -> u16 {
+        (2048 - self.frequency) * 2
+    }
+
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.dac_enabled = (val & 0x80) != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(256, u16::from(val)),
+            2 => self.volume_code = (val >> 5) & 0x03,
+            3 => self.frequency = (self.frequency & 0x700) | u16::from(val),
+            4 => {
+                self.frequency = (self.frequency & 0xff) | (u16::from(val & 0x07) << 8);
+                self.length.enabled = (val & 0x40) != 0;
+                if (val & 0x80) != 0 {
+                    self.enabled = self.dac_enabled;
+                    self.length.trigger(256);
+                    self.timer = self.period();
+                    self.position = 0;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn step(&mut self) {
+        self.timer -= 1;
+        if self.timer == 0 {
+            self.timer = self.period();
+            self.position = (self.position + 1) & 0x1f;
+        }
+    }
+
+    // Samples are stored two per byte, high nibble first
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        let byte = self.ram[(self.position / 2) as usize];
+        let sample = if (self.position & 0x01) == 0 {
+            byte >> 4
+        } else {
+            byte & 0x0f
+        };
+        match self.volume_code {
+            0 => 0,
+            code => sample >> (code - 1),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Noise {
+    enabled: bool,
+    shift: u8,
+    narrow: bool,
+    divisor: u8,
+    lfsr: u16,
+    timer: u32,
+    length: Length,
+    envelope: Envelope,
+}
+
+impl Noise {
+    fn new() -> Noise {
+        Noise {
+            enabled: false,
+            shift: 0,
+            narrow: false,
+            divisor: 0,
+            lfsr: 0x7fff,
+            timer: 8,
+            length: Length::default(),
+            envelope: Envelope::default(),
+        }
+    }
+
+    // Shifts up to 15 take the period past 16 bits
+    fn period(&self) -> u32 {
+        NOISE_DIVISORS[self.divisor as usize] << self.shift
+    }
+
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            1 => self.length.load(64, u16::from(val & 0x3f)),
+            2 => {
+                self.envelope.write(val);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => {
+                self.shift = val >> 4;
+                self.narrow = (val & 0x08) != 0;
+                self.divisor = val & 0x07;
+            }
+            4 => {
+                self.length.enabled = (val & 0x40) != 0;
+                if (val & 0x80) != 0 {
+                    self.enabled = self.envelope.dac_enabled();
+                    self.length.trigger(64);
+                    self.timer = self.period();
+                    self.envelope.trigger();
+                    self.lfsr = 0x7fff;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // Shifts 14 and 15 never clock the LFSR
+    fn step(&mut self) {
+        if self.timer > 1 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period();
+        if self.shift < 14 {
+            let bit = (self.lfsr ^ (self.lfsr >> 1)) & 0x01;
+            self.lfsr = (self.lfsr >> 1) | (bit << 14);
+            if self.narrow {
+                self.lfsr = (self.lfsr & !0x40) | (bit << 6);
+            }
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.lfsr & 0x01) == 0 {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+}
+
+// A register write, timed in cycles since the APU was created
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ApuWrite {
+    pub cycle: u64,
+    pub addr: u16,
+    pub val: u8,
+}
+
+// Every register write between two points in time. It opens with writes
+// that recreate the registers as they were at the start.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApuRecording {
+    pub start: u64,
+    pub end: u64,
+    pub writes: Vec<ApuWrite>,
+}
+
+// The four sound channels, frame sequencer and mixer. Output is interleaved
+// stereo (left, right) at the configured sample rate.
+#[derive(Debug)]
+pub struct Apu {
+    registers: [u8; 0x17],
+    powered: bool,
+    square1: Square,
+    square2: Square,
+    wave: Wave,
+    noise: Noise,
+    frame_timer: usize,
+    frame_step: u8,
+    sample_rate: u32,
+    sample_counter: u32,
+    samples: Vec<i16>,
+    cycles: u64,
+    recording: Option<ApuRecording>,
+}
+
+impl Apu {
+    pub fn new() -> Apu {
+        Apu {
+            registers: [0; 0x17],
+            powered: false,
+            square1: Square::with_sweep(),
+            square2: Square::default(),
+            wave: Wave::new(),
+            noise: Noise::new(),
+            frame_timer: 0,
+            frame_step: 0,
+            sample_rate: 0,
+            sample_counter: 0,
+            samples: Vec::new(),
+            cycles: 0,
+            recording: None,
+        }
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn start_recording(&mut self) {
+        let mut writes = vec![(0xff26, (self.powered as u8) << 7)];
+        for addr in 0xff10..=0xff25 {
+            let val = self.registers[(addr - 0xff10) as usize];
+            // Without the trigger bits so no channel restarts
+            let val = match addr {
+                0xff14 | 0xff19 | 0xff1e | 0xff23 => val & 0x7f,
+                _ => val,
+            };
+            writes.push((addr, val));
+        }
+        for (i, &val) in self.wave.ram.iter().enumerate() {
+            writes.push((0xff30 + i as u16, val));
+        }
+        let cycle = self.cycles;
+        self.recording = Some(ApuRecording {
+            start: cycle,
+            end: cycle,
+            writes: writes
+                .into_iter()
+                .map(|(addr, val)| ApuWrite { cycle, addr, val })
+                .collect(),
+        });
+    }
+
+    pub fn stop_recording(&mut self) -> Option<ApuRecording> {
+        let mut recording = self.recording.take()?;
+        recording.end = self.cycles;
+        Some(recording)
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    // No samples are produced until a rate is set, so nothing piles up when
+    // the sound isn't wanted. Zero turns them off again.
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.sample_counter = 0;
+    }
+
+    pub fn samples(&self) -> &[i16] {
+        &self.samples
+    }
+
+    pub fn take_samples(&mut self) -> Vec<i16> {
+        std::mem::take(&mut self.samples)
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xff26 => {
+                let status = (self.powered as u8) << 7
+                    | (self.noise.enabled as u8) << 3
+                    | (self.wave.enabled as u8) << 2
+                    | (self.square2.enabled as u8) << 1
+                    | self.square1.enabled as u8;
+                status | READ_MASKS[0x16]
+            }
+            0xff10..=0xff25 => {
+                let i = (addr - 0xff10) as usize;
+                self.registers[i] | READ_MASKS[i]
+            }
+            0xff30..=0xff3f => self.wave.ram[(addr - 0xff30) as usize],
+            _ => 0xff,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        if let Some(recording) = &mut self.recording {
+            recording.writes.push(ApuWrite {
+                cycle: self.cycles,
+                addr,
+                val,
+            });
+        }
+        match addr {
+            0xff26 => self.set_power((val & 0x80) != 0),
+            0xff30..=0xff3f => self.wave.ram[(addr - 0xff30) as usize] = val,
+            // Everything but NR52 and wave RAM ignores writes while off
+            _ if !self.powered => (),
+            0xff10..=0xff25 => {
+                let i = addr - 0xff10;
+                self.registers[i as usize] = val;
+                match i {
+                    0x00..=0x04 => self.square1.write(i, val),
+                    0x05..=0x09 => self.square2.write(i - 0x05, val),
+                    0x0a..=0x0e => self.wave.write(i - 0x0a, val),
+                    0x0f..=0x13 => self.noise.write(i - 0x0f, val),
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn set_power(&mut self, on: bool) {
+        if self.powered && !on {
+            let ram = self.wave.ram;
+            self.registers = [0; 0x17];
+            self.square1 = Square::with_sweep();
+            self.square2 = Square::default();
+            self.wave = Wave::new();
+            self.wave.ram = ram;
+            self.noise = Noise::new();
+        } else if !self.powered && on {
+            self.frame_step = 0;
+            self.frame_timer = 0;
+        }
+        self.powered = on;
+    }
+
+    pub fn tick(&mut self, cycles: usize) {
+        self.cycles += cycles as u64;
+        for _ in 0..cycles {
+            if self.powered {
+                self.step();
+            }
+            self.sample_counter += self.sample_rate;
+            if self.sample_counter >= CYCLES_PER_SECOND as u32 {
+                self.sample_counter -= CYCLES_PER_SECOND as u32;
+                self.mix();
+            }
+        }
+    }
+
+    fn step(&mut self) {
+        self.frame_timer += 1;
+        if self.frame_timer == FRAME_SEQUENCER_PERIOD {
+            self.frame_timer = 0;
+            self.clock_frame_sequencer();
+        }
+        if self.square1.enabled {
+            self.square1.step();
+        }
+        if self.square2.enabled {
+            self.square2.step();
+        }
+        if self.wave.enabled {
+            self.wave.step();
+        }
+        if self.noise.enabled {
+            self.noise.step();
+        }
+    }
+
+    // Length runs on even steps, sweep on 2 and 6, envelopes on 7
+    fn clock_frame_sequencer(&mut self) {
+        if (self.frame_step & 0x01) == 0 {
+            self.square1.enabled &= self.square1.length.clock();
+            self.square2.enabled &= self.square2.length.clock();
+            self.wave.enabled &= self.wave.length.clock();
+            self.noise.enabled &= self.noise.length.clock();
+        }
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.square1.clock_sweep();
+        }
+        if self.frame_step == 7 {
+            self.square1.envelope.clock();
+            self.square2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+        self.frame_step = (self.frame_step + 1) & 0x07;
+    }
+
+    // Each DAC maps 0-15 to -15..15, or 0 when it is off
+    fn mix(&mut self) {
+        let outputs = [
+            (self.square1.envelope.dac_enabled(), self.square1.output()),
+            (self.square2.envelope.dac_enabled(), self.square2.output()),
+            (self.wave.dac_enabled, self.wave.output()),
+            (self.noise.envelope.dac_enabled(), self.noise.output()),
+        ];
+        let panning = self.registers[0x15];
+        let volume = self.registers[0x14];
+        let (mut left, mut right) = (0i32, 0i32);
+        for (i, &(dac, sample)) in outputs.iter().enumerate() {
+            if !self.powered || !dac {
+                continue;
+            }
+            let analog = i32::from(sample) * 2 - 15;
+            if (panning >> (i + 4)) & 0x01 != 0 {
+                left += analog;
+            }
+            if (panning >> i) & 0x01 != 0 {
+                right += analog;
+            }
+        }
+        left *= i32::from((volume >> 4) & 0x07) + 1;
+        right *= i32::from(volume & 0x07) + 1;
+        self.samples.push((left * 64) as i16);
+        self.samples.push((right * 64) as i16);
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn powered_apu() -> Apu {
+        let mut apu = Apu::new();
+        apu.write(0xff26, 0x80);
+        apu.write(0xff24, 0x77);
+        apu.write(0xff25, 0xff);
+        apu
+    }
+
+    #[test]
+    fn power_off_clears_registers_and_ignores_writes() {
+        let mut apu = powered_apu();
+        apu.write(0xff12, 0xf0);
+        apu.write(0xff30, 0x12);
+        apu.write(0xff26, 0x00);
+        assert_eq!(apu.read(0xff12), 0x00);
+        assert_eq!(apu.read(0xff24), 0x00);
+        assert_eq!(apu.read(0xff26), 0x70);
+        assert_eq!(apu.read(0xff30), 0x12);
+        apu.write(0xff12, 0xf0);
+        assert_eq!(apu.read(0xff12), 0x00);
+    }
+
+    #[test]
+    fn length_counter_stops_channel() {
+        let mut apu = powered_apu();
+        apu.write(0xff12, 0xf0);
+        apu.write(0xff11, 0x3e);
+        apu.write(0xff14, 0xc0);
+        assert_eq!(apu.read(0xff26) & 0x01, 0x01);
+        // Two length clocks happen in the first 8192 * 3 cycles
+        apu.tick(FRAME_SEQUENCER_PERIOD * 3);
+        assert_eq!(apu.read(0xff26) & 0x01, 0x00);
+    }
+
+    #[test]
+    fn sweep_overflow_disables_channel() {
+        let mut apu = powered_apu();
+        apu.write(0xff10, 0x11);
+        apu.write(0xff12, 0xf0);
+        apu.write(0xff13, 0xff);
+        apu.write(0xff14, 0x87);
+        assert_eq!(apu.read(0xff26) & 0x01, 0x00);
+    }
+
+    #[test]
+    fn samples_are_opt_in() {
+        let mut apu = powered_apu();
+        apu.write(0xff12, 0xf0);
+        apu.write(0xff14, 0x87);
+        apu.tick(CYCLES_PER_SECOND / 64);
+        assert!(apu.samples().is_empty());
+        apu.set_sample_rate(DEFAULT_SAMPLE_RATE);
+        apu.tick(CYCLES_PER_SECOND / 64);
+        assert!(!apu.samples().is_empty());
+    }
+
+    #[test]
+    fn produces_stereo_samples() {
+        let mut apu = powered_apu();
+        apu.set_sample_rate(32768);
+        apu.write(0xff25, 0x10);
+        apu.write(0xff12, 0xf0);
+        apu.write(0xff11, 0x80);
+        apu.write(0xff14, 0x87);
+        apu.tick(CYCLES_PER_SECOND / 64);
+        let samples = apu.take_samples();
+        assert_eq!(samples.len(), 2 * 512);
+        assert!(samples.chunks(2).all(|frame| frame[1] == 0));
+        assert!(samples.chunks(2).any(|frame| frame[0] > 0));
+        assert!(samples.chunks(2).any(|frame| frame[0] < 0));
+    }
+
+    fn triggered_noise(nr43: u8) -> Noise {
+        let mut noise = Noise::new();
+        noise.write(2, 0xf0);
+        noise.write(3, nr43);
+        noise.write(4, 0x80);
+        noise
+    }
+
+    // Steps through one period at a time, returning the output after each
+    fn noise_clocks(noise: &mut Noise, clocks: usize) -> Vec<u8> {
+        let period = noise.period();
+        (0..clocks)
+            .map(|_| {
+                for _ in 0..period {
+                    noise.step();
+                }
+                noise.output()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn noise_lfsr_widths() {
+        // All ones shift out one at a time before the first one feeds back
+        let mut noise = triggered_noise(0x00);
+        noise_clocks(&mut noise, 14);
+        assert_eq!(noise.lfsr, 0x0001);
+        noise_clocks(&mut noise, 1);
+        assert_eq!(noise.lfsr, 0x4000);
+
+        let mut wide = triggered_noise(0x00);
+        let out = noise_clocks(&mut wide, 2 * 0x7fff);
+        assert_eq!(out[..0x7fff], out[0x7fff..]);
+        assert_ne!(out[..127], out[127..254]);
+
+        let mut narrow = triggered_noise(0x08);
+        noise_clocks(&mut narrow, 1);
+        assert_eq!(narrow.lfsr, 0x3fbf);
+        let out = noise_clocks(&mut narrow, 2 * 127);
+        assert_eq!(out[..127], out[127..]);
+        assert!(out.contains(&0) && out.contains(&15));
+    }
+
+    #[test]
+    fn noise_high_shifts() {
+        // 8 << 13 doesn't fit in 16 bits but still clocks
+        let mut noise = triggered_noise(0xd0);
+        assert_eq!(noise.period(), 0x10000);
+        noise_clocks(&mut noise, 1);
+        assert_eq!(noise.lfsr, 0x3fff);
+
+        for nr43 in [0xe0, 0xf7].iter() {
+            let mut noise = triggered_noise(*nr43);
+            noise_clocks(&mut noise, 2);
+            assert_eq!(noise.lfsr, 0x7fff);
+        }
+
+        let mut apu = powered_apu();
+        apu.write(0xff21, 0xf0);
+        apu.write(0xff22, 0xf7);
+        apu.write(0xff23, 0x80);
+        apu.tick(0x100000);
+        assert_eq!(apu.read(0xff26) & 0x08, 0x08);
+    }
+
+    #[test]
+    fn wave_ram_playback() {
+        let mut apu = powered_apu();
+        for i in 0..0x10 {
+            apu.write(0xff30 + i, (i as u8) << 4 | (0x0f - i as u8));
+        }
+        apu.write(0xff1a, 0x80);
+        apu.write(0xff1c, 0x20);
+        // The highest frequency moves on a sample every two cycles
+        apu.write(0xff1d, 0xff);
+        apu.write(0xff1e, 0x87);
+        let mut played = vec![apu.wave.output()];
+        for _ in 0..31 {
+            apu.tick(2);
+            played.push(apu.wave.output());
+        }
+        let expected: Vec<u8> = (0..0x10).flat_map(|i| vec![i, 0x0f - i]).collect();
+        assert_eq!(played, expected);
+
+        // Lower volumes shift the samples right
+        apu.write(0xff1c, 0x60);
+        assert_eq!(apu.wave.output(), 0x00);
+        apu.tick(4);
+        assert_eq!(apu.wave.output(), 0x03);
+    }
+}
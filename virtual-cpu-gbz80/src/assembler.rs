@@ -0,0 +1,1285 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::disassembler::SymbolTable;
+use crate::header::{fix_header, HEADER_END};
+use crate::mbc::ROM_BANK_SIZE;
+
+// Unused ROM is filled like erased flash
+const PAD: u8 = 0xff;
+const HEADER_START: u16 = 0x0100;
+const CARTRIDGE_TYPE: usize = 0x0147;
+// The logo through the header checksum, which fix_header writes over
+const FIXED_HEADER: std::ops::Range<usize> = 0x0104..0x014e;
+// Multi-bank images that don't pick a cartridge type get an MBC5
+const DEFAULT_BANKED_TYPE: u8 = 0x19;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssembleError {
+    // 1-based source line, 0 for errors found while linking
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            0 => write!(f, "{}", self.message),
+            line => write!(f, "line {}: {}", line, self.message),
+        }
+    }
+}
+
+impl Error for AssembleError {}
+
+fn error<T>(line: usize, message: impl Into<String>) -> Result<T, AssembleError> {
+    Err(AssembleError {
+        line,
+        message: message.into(),
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Region {
+    Rom0,
+    Romx,
+    Vram,
+    Sram,
+    Wram0,
+    Wramx,
+    Hram,
+}
+
+impl Region {
+    fn parse(name: &str) -> Option<Region> {
+        match name.to_ascii_uppercase().as_str() {
+            "ROM0" => Some(Region::Rom0),
+            "ROMX" => Some(Region::Romx),
+            "VRAM" => Some(Region::Vram),
+            "SRAM" => Some(Region::Sram),
+            "WRAM0" => Some(Region::Wram0),
+            "WRAMX" => Some(Region::Wramx),
+            "HRAM" => Some(Region::Hram),
+            _ => None,
+        }
+    }
+
+    // First and one past the last address
+    fn range(self) -> (u32, u32) {
+        match self {
+            Region::Rom0 => (0x0000, 0x4000),
+            Region::Romx => (0x4000, 0x8000),
+            Region::Vram => (0x8000, 0xa000),
+            Region::Sram => (0xa000, 0xc000),
+            Region::Wram0 => (0xc000, 0xd000),
+            Region::Wramx => (0xd000, 0xe000),
+            Region::Hram => (0xff80, 0xffff),
+        }
+    }
+
+    fn is_rom(self) -> bool {
+        matches!(self, Region::Rom0 | Region::Romx)
+    }
+
+    fn default_bank(self) -> usize {
+        match self {
+            Region::Romx | Region::Wramx => 1,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BinaryOp {
+    Or,
+    Xor,
+    And,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Number(i64),
+    Symbol(String),
+    Here,
+    Bank(String),
+    Negate(Box<Expr>),
+    Complement(Box<Expr>),
+    High(Box<Expr>),
+    Low(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Ident(String),
+    Op(&'static str),
+    Open,
+    Close,
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let rest: String = chars[i..].iter().take(2).collect();
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '(' {
+            tokens.push(Token::Open);
+            i += 1;
+        } else if c == ')' {
+            tokens.push(Token::Close);
+            i += 1;
+        } else if rest == "<<" || rest == ">>" {
+            tokens.push(Token::Op(if rest == "<<" { "<<" } else { ">>" }));
+            i += 2;
+        } else if let Some(op) = ["|", "^", "&", "+", "-", "*", "/", "%", "~"]
+            .iter()
+            .find(|op| op.starts_with(c))
+        {
+            // % also starts binary numbers when an operand is expected
+            let number = (c == '%' && chars.get(i + 1).is_some_and(|&d| d == '0' || d == '1'))
+                && !matches!(
+                    tokens.last(),
+                    Some(Token::Number(_) | Token::Ident(_) | Token::Close)
+                );
+            if number {
+                let end = scan(&chars, i + 1, |d| d == '0' || d == '1' || d == '_');
+                tokens.push(Token::Number(parse_digits(&chars[i + 1..end], 2)?));
+                i = end;
+            } else {
+                tokens.push(Token::Op(op));
+                i += 1;
+            }
+        } else if c == '$' {
+            let end = scan(&chars, i + 1, |d| d.is_ascii_hexdigit() || d == '_');
+            tokens.push(Token::Number(parse_digits(&chars[i + 1..end], 16)?));
+            i = end;
+        } else if c == '\'' {
+            match (chars.get(i + 1), chars.get(i + 2)) {
+                (Some(&ch), Some('\'')) => tokens.push(Token::Number(ch as i64)),
+                _ => return Err("bad character literal".into()),
+            }
+            i += 3;
+        } else if c.is_ascii_digit() {
+            let end = scan(&chars, i, |d| d.is_ascii_alphanumeric() || d == '_');
+            let digits: String = chars[i..end].iter().collect();
+            let digits = digits.to_ascii_lowercase();
+            let value = if let Some(hex) = digits.strip_prefix("0x") {
+                parse_digits(&hex.chars().collect::<Vec<_>>(), 16)?
+            } else if let Some(bin) = digits.strip_prefix("0b") {
+                parse_digits(&bin.chars().collect::<Vec<_>>(), 2)?
+            } else {
+                parse_digits(&chars[i..end], 10)?
+            };
+            tokens.push(Token::Number(value));
+            i = end;
+        } else if c == '@' {
+            tokens.push(Token::Ident("@".into()));
+            i += 1;
+        } else if is_ident_char(c) {
+            let end = scan(&chars, i, is_ident_char);
+            tokens.push(Token::Ident(chars[i..end].iter().collect()));
+            i = end;
+        } else {
+            return Err(format!("unexpected '{}'", c));
+        }
+    }
+    Ok(tokens)
+}
+
+fn scan(chars: &[char], start: usize, accept: impl Fn(char) -> bool) -> usize {
+    let mut end = start;
+    while end < chars.len() && accept(chars[end]) {
+        end += 1;
+    }
+    end
+}
+
+fn parse_digits(digits: &[char], radix: u32) -> Result<i64, String> {
+    let digits: String = digits.iter().filter(|&&c| c != '_').collect();
+    i64::from_str_radix(&digits, radix).map_err(|_| format!("bad number '{}'", digits))
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '#'
+}
+
+// Precedence climbing over the RGBDS operators, loosest first
+const PRECEDENCE: [&[(&str, BinaryOp)]; 6] = [
+    &[("|", BinaryOp::Or)],
+    &[("^", BinaryOp::Xor)],
+    &[("&", BinaryOp::And)],
+    &[("<<", BinaryOp::Shl), (">>", BinaryOp::Shr)],
+    &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+    &[
+        ("*", BinaryOp::Mul),
+        ("/", BinaryOp::Div),
+        ("%", BinaryOp::Mod),
+    ],
+];
+
+struct ExprParser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    scope: Option<&'a str>,
+}
+
+impl<'a> ExprParser<'a> {
+    fn parse(text: &str, scope: Option<&'a str>) -> Result<Expr, String> {
+        let mut parser = ExprParser {
+            tokens: tokenize(text)?,
+            pos: 0,
+            scope,
+        };
+        if parser.tokens.is_empty() {
+            return Err("missing expression".into());
+        }
+        let expr = parser.binary(0)?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(expr),
+            Some(token) => Err(format!("unexpected {:?} in expression", token)),
+        }
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(Token::Op(op)) = self.tokens.get(self.pos) {
+            let op = match PRECEDENCE[level].iter().find(|(name, _)| name == op) {
+                Some(&(_, op)) => op,
+                None => break,
+            };
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Op("-")) => Ok(Expr::Negate(Box::new(self.unary()?))),
+            Some(Token::Op("~")) => Ok(Expr::Complement(Box::new(self.unary()?))),
+            Some(Token::Op("+")) => self.unary(),
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(Token::Open) => {
+                let expr = self.binary(0)?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expr),
+                    _ => Err("missing ')'".into()),
+                }
+            }
+            Some(Token::Ident(name)) if name == "@" => Ok(Expr::Here),
+            Some(Token::Ident(name)) => {
+                if self.tokens.get(self.pos) == Some(&Token::Open) {
+                    return self.function(&name);
+                }
+                Ok(Expr::Symbol(qualify(&name, self.scope)?))
+            }
+            token => Err(format!("unexpected {:?} in expression", token)),
+        }
+    }
+
+    fn function(&mut self, name: &str) -> Result<Expr, String> {
+        self.pos += 1;
+        let expr = match name.to_ascii_uppercase().as_str() {
+            "HIGH" => Expr::High(Box::new(self.binary(0)?)),
+            "LOW" => Expr::Low(Box::new(self.binary(0)?)),
+            "BANK" => match self.next() {
+                Some(Token::Ident(symbol)) => Expr::Bank(qualify(&symbol, self.scope)?),
+                _ => return Err("BANK takes a label".into()),
+            },
+            _ => return Err(format!("unknown function {}", name)),
+        };
+        match self.next() {
+            Some(Token::Close) => Ok(expr),
+            _ => Err("missing ')'".into()),
+        }
+    }
+}
+
+// Local labels belong to the last global label before them
+fn qualify(name: &str, scope: Option<&str>) -> Result<String, String> {
+    if !name.starts_with('.') {
+        return Ok(name.to_string());
+    }
+    match scope {
+        Some(scope) => Ok(format!("{}{}", scope, name)),
+        None => Err(format!("local label {} has no parent label", name)),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Resolved {
+    addr: u16,
+    bank: usize,
+}
+
+struct Env<'a> {
+    constants: &'a HashMap<String, i64>,
+    labels: &'a HashMap<String, Resolved>,
+    here: Option<u16>,
+}
+
+impl<'a> Env<'a> {
+    fn eval(&self, expr: &Expr) -> Result<i64, String> {
+        Ok(match expr {
+            Expr::Number(n) => *n,
+            Expr::Here => match self.here {
+                Some(here) => i64::from(here),
+                None => return Err("@ is not known here".into()),
+            },
+            Expr::Symbol(name) => match (self.constants.get(name), self.labels.get(name)) {
+                (Some(&value), _) => value,
+                (None, Some(label)) => i64::from(label.addr),
+                (None, None) => return Err(format!("unknown symbol {}", name)),
+            },
+            Expr::Bank(name) => match self.labels.get(name) {
+                Some(label) => label.bank as i64,
+                None => return Err(format!("unknown label {}", name)),
+            },
+            Expr::Negate(e) => -self.eval(e)?,
+            Expr::Complement(e) => !self.eval(e)?,
+            Expr::High(e) => (self.eval(e)? >> 8) & 0xff,
+            Expr::Low(e) => self.eval(e)? & 0xff,
+            Expr::Binary(op, lhs, rhs) => {
+                let (lhs, rhs) = (self.eval(lhs)?, self.eval(rhs)?);
+                match op {
+                    BinaryOp::Or => lhs | rhs,
+                    BinaryOp::Xor => lhs ^ rhs,
+                    BinaryOp::And => lhs & rhs,
+                    BinaryOp::Shl => lhs.wrapping_shl(rhs as u32),
+                    BinaryOp::Shr => lhs.wrapping_shr(rhs as u32),
+                    BinaryOp::Add => lhs.wrapping_add(rhs),
+                    BinaryOp::Sub => lhs.wrapping_sub(rhs),
+                    BinaryOp::Mul => lhs.wrapping_mul(rhs),
+                    BinaryOp::Div | BinaryOp::Mod if rhs == 0 => {
+                        return Err("division by zero".into())
+                    }
+                    BinaryOp::Div => lhs / rhs,
+                    BinaryOp::Mod => lhs % rhs,
+                }
+            }
+        })
+    }
+
+    fn byte(&self, expr: &Expr) -> Result<u8, String> {
+        match self.eval(expr)? {
+            value @ -0x80..=0xff => Ok(value as u8),
+            value => Err(format!("{} doesn't fit in a byte", value)),
+        }
+    }
+
+    fn word(&self, expr: &Expr) -> Result<u16, String> {
+        match self.eval(expr)? {
+            value @ -0x8000..=0xffff => Ok(value as u16),
+            value => Err(format!("{} doesn't fit in a word", value)),
+        }
+    }
+}
+
+// What a statement puts into the image, with expressions left until every
+// label has an address
+#[derive(Debug, Clone)]
+enum Emit {
+    Byte(u8),
+    Imm8(Expr),
+    Imm16(Expr),
+    // jr offset, relative to the end of the instruction
+    Relative(Expr),
+    // add sp / ld hl, sp+ offset
+    Signed(Expr),
+    // ldh operand, 0xff00-0xffff or 0x00-0xff
+    HighPage(Expr),
+    Bytes(Vec<u8>),
+    Fill(usize, u8),
+}
+
+impl Emit {
+    fn len(&self) -> usize {
+        match self {
+            Emit::Byte(_)
+            | Emit::Imm8(_)
+            | Emit::Relative(_)
+            | Emit::Signed(_)
+            | Emit::HighPage(_) => 1,
+            Emit::Imm16(_) => 2,
+            Emit::Bytes(bytes) => bytes.len(),
+            Emit::Fill(count, _) => *count,
+        }
+    }
+
+    fn write(&self, env: &Env, addr: u16, out: &mut Vec<u8>) -> Result<(), String> {
+        match self {
+            Emit::Byte(b) => out.push(*b),
+            Emit::Imm8(e) => out.push(env.byte(e)?),
+            Emit::Imm16(e) => out.extend_from_slice(&env.word(e)?.to_le_bytes()),
+            Emit::Relative(e) => {
+                let offset = env.eval(e)? - (i64::from(addr) + 1);
+                if !(-0x80..=0x7f).contains(&offset) {
+                    return Err(format!("jr target is {} bytes away", offset));
+                }
+                out.push(offset as u8);
+            }
+            Emit::Signed(e) => match env.eval(e)? {
+                offset @ -0x80..=0x7f => out.push(offset as u8),
+                offset => return Err(format!("offset {} out of range", offset)),
+            },
+            Emit::HighPage(e) => match env.eval(e)? {
+                addr @ 0xff00..=0xffff | addr @ 0x00..=0xff => out.push(addr as u8),
+                addr => return Err(format!("${:x} is not in $ff00-$ffff", addr)),
+            },
+            Emit::Bytes(bytes) => out.extend_from_slice(bytes),
+            Emit::Fill(count, value) => out.extend(std::iter::repeat_n(*value, *count)),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Statement {
+    line: usize,
+    offset: usize,
+    emits: Vec<Emit>,
+}
+
+#[derive(Debug)]
+struct Section {
+    name: String,
+    region: Region,
+    addr: Option<u16>,
+    bank: Option<usize>,
+    size: usize,
+    statements: Vec<Statement>,
+}
+
+impl Section {
+    fn end(&self) -> u32 {
+        u32::from(self.addr.unwrap_or(0)) + self.size as u32
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    // b, c, d, e, h, l, [hl], a in encoding order
+    R8(u8),
+    // bc, de, hl, sp, then af as 4
+    R16(u8),
+    Condition(u8),
+    HlIncrement,
+    HlDecrement,
+    MemBc,
+    MemDe,
+    MemC,
+    Mem(Expr),
+    Imm(Expr),
+    SpOffset(Expr),
+}
+
+const A: Operand = Operand::R8(7);
+const HL: Operand = Operand::R16(2);
+const SP: Operand = Operand::R16(3);
+
+impl Operand {
+    fn parse(text: &str, scope: Option<&str>) -> Result<Operand, String> {
+        let compact: String = text
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>()
+            .to_ascii_lowercase();
+        let operand = match compact.as_str() {
+            "b" => Operand::R8(0),
+            "c" => Operand::R8(1),
+            "d" => Operand::R8(2),
+            "e" => Operand::R8(3),
+            "h" => Operand::R8(4),
+            "l" => Operand::R8(5),
+            "[hl]" => Operand::R8(6),
+            "a" => Operand::R8(7),
+            "bc" => Operand::R16(0),
+            "de" => Operand::R16(1),
+            "hl" => Operand::R16(2),
+            "sp" => Operand::R16(3),
+            "af" => Operand::R16(4),
+            "nz" => Operand::Condition(0),
+            "z" => Operand::Condition(1),
+            "nc" => Operand::Condition(2),
+            "[hl+]" | "[hli]" => Operand::HlIncrement,
+            "[hl-]" | "[hld]" => Operand::HlDecrement,
+            "[bc]" => Operand::MemBc,
+            "[de]" => Operand::MemDe,
+            "[c]" | "[$ff00+c]" | "[0xff00+c]" => Operand::MemC,
+            _ if compact.starts_with("sp+") || compact.starts_with("sp-") => {
+                let text = text.trim();
+                Operand::SpOffset(ExprParser::parse(&text[2..], scope)?)
+            }
+            _ if compact.starts_with('[') && compact.ends_with(']') => {
+                let text = text.trim();
+                Operand::Mem(ExprParser::parse(&text[1..text.len() - 1], scope)?)
+            }
+            _ => Operand::Imm(ExprParser::parse(text, scope)?),
+        };
+        Ok(operand)
+    }
+
+    // The carry condition is spelled like register C
+    fn condition(&self) -> Option<u8> {
+        match self {
+            Operand::Condition(cc) => Some(*cc),
+            Operand::R8(1) => Some(3),
+            _ => None,
+        }
+    }
+}
+
+const ALU: [&str; 8] = ["add", "adc", "sub", "sbc", "and", "xor", "or", "cp"];
+const ROTATES: [&str; 8] = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+const BIT_OPS: [&str; 3] = ["bit", "res", "set"];
+
+fn encode(
+    mnemonic: &str,
+    operands: &[Operand],
+    constant: impl Fn(&Expr) -> Result<i64, String>,
+) -> Result<Vec<Emit>, String> {
+    use self::Emit::{Byte, Imm16, Imm8};
+    use self::Operand::*;
+
+    let op = |opcode: u8| vec![Byte(opcode)];
+    let alu = ALU.iter().position(|&m| m == mnemonic).map(|i| i as u8);
+    let rotate = ROTATES.iter().position(|&m| m == mnemonic).map(|i| i as u8);
+    let bit_op = BIT_OPS.iter().position(|&m| m == mnemonic).map(|i| i as u8);
+
+    let emits = match (mnemonic, operands) {
+        ("nop", []) => op(0x00),
+        ("stop", []) => vec![Byte(0x10), Byte(0x00)],
+        ("halt", []) => op(0x76),
+        ("di", []) => op(0xf3),
+        ("ei", []) => op(0xfb),
+        ("rlca", []) => op(0x07),
+        ("rrca", []) => op(0x0f),
+        ("rla", []) => op(0x17),
+        ("rra", []) => op(0x1f),
+        ("daa", []) => op(0x27),
+        ("cpl", []) => op(0x2f),
+        ("scf", []) => op(0x37),
+        ("ccf", []) => op(0x3f),
+        ("reti", []) => op(0xd9),
+        ("ret", []) => op(0xc9),
+        ("ret", [cc]) if cc.condition().is_some() => op(0xc0 | cc.condition().unwrap() << 3),
+        ("jp", [Imm(e)]) => vec![Byte(0xc3), Imm16(e.clone())],
+        ("jp", [HL]) | ("jp", [R8(6)]) => op(0xe9),
+        ("jp", [cc, Imm(e)]) if cc.condition().is_some() => {
+            vec![Byte(0xc2 | cc.condition().unwrap() << 3), Imm16(e.clone())]
+        }
+        ("jr", [Imm(e)]) => vec![Byte(0x18), Emit::Relative(e.clone())],
+        ("jr", [cc, Imm(e)]) if cc.condition().is_some() => vec![
+            Byte(0x20 | cc.condition().unwrap() << 3),
+            Emit::Relative(e.clone()),
+        ],
+        ("call", [Imm(e)]) => vec![Byte(0xcd), Imm16(e.clone())],
+        ("call", [cc, Imm(e)]) if cc.condition().is_some() => {
+            vec![Byte(0xc4 | cc.condition().unwrap() << 3), Imm16(e.clone())]
+        }
+        ("rst", [Imm(e)]) => match constant(e)? {
+            vector @ 0x00..=0x38 if (vector & 0x07) == 0 => op(0xc7 | vector as u8),
+            vector => return Err(format!("${:x} is not a rst vector", vector)),
+        },
+        // af takes the place of sp
+        ("push", [R16(p)]) if *p != 3 => op(0xc5 | p.min(&3) << 4),
+        ("pop", [R16(p)]) if *p != 3 => op(0xc1 | p.min(&3) << 4),
+        ("inc", [R8(r)]) => op(0x04 | r << 3),
+        ("dec", [R8(r)]) => op(0x05 | r << 3),
+        ("inc", [R16(p)]) if *p < 4 => op(0x03 | p << 4),
+        ("dec", [R16(p)]) if *p < 4 => op(0x0b | p << 4),
+        ("add", [HL, R16(p)]) if *p < 4 => op(0x09 | p << 4),
+        ("add", [SP, Imm(e)]) => vec![Byte(0xe8), Emit::Signed(e.clone())],
+        (_, [A, R8(r)]) | (_, [R8(r)]) if alu.is_some() => op(0x80 | alu.unwrap() << 3 | r),
+        (_, [A, Imm(e)]) | (_, [Imm(e)]) if alu.is_some() => {
+            vec![Byte(0xc6 | alu.unwrap() << 3), Imm8(e.clone())]
+        }
+        (_, [R8(r)]) if rotate.is_some() => vec![Byte(0xcb), Byte(rotate.unwrap() << 3 | r)],
+        (_, [Imm(e), R8(r)]) if bit_op.is_some() => match constant(e)? {
+            bit @ 0..=7 => vec![
+                Byte(0xcb),
+                Byte((bit_op.unwrap() + 1) << 6 | (bit as u8) << 3 | r),
+            ],
+            bit => return Err(format!("bit {} out of range", bit)),
+        },
+        ("ld", [R8(6), R8(6)]) => return Err("ld [hl], [hl] is halt".into()),
+        ("ld", [R8(d), R8(s)]) => op(0x40 | d << 3 | s),
+        ("ld", [R8(d), Imm(e)]) => vec![Byte(0x06 | d << 3), Imm8(e.clone())],
+        ("ld", [R16(p), Imm(e)]) if *p < 4 => vec![Byte(0x01 | p << 4), Imm16(e.clone())],
+        ("ld", [MemBc, A]) => op(0x02),
+        ("ld", [MemDe, A]) => op(0x12),
+        ("ld", [HlIncrement, A]) => op(0x22),
+        ("ld", [HlDecrement, A]) => op(0x32),
+        ("ld", [A, MemBc]) => op(0x0a),
+        ("ld", [A, MemDe]) => op(0x1a),
+        ("ld", [A, HlIncrement]) => op(0x2a),
+        ("ld", [A, HlDecrement]) => op(0x3a),
+        ("ld", [Mem(e), SP]) => vec![Byte(0x08), Imm16(e.clone())],
+        ("ld", [Mem(e), A]) => vec![Byte(0xea), Imm16(e.clone())],
+        ("ld", [A, Mem(e)]) => vec![Byte(0xfa), Imm16(e.clone())],
+        ("ld", [MemC, A]) | ("ldh", [MemC, A]) => op(0xe2),
+        ("ld", [A, MemC]) | ("ldh", [A, MemC]) => op(0xf2),
+        ("ld", [SP, HL]) => op(0xf9),
+        ("ld", [HL, SpOffset(e)]) => vec![Byte(0xf8), Emit::Signed(e.clone())],
+        ("ldh", [Mem(e), A]) => vec![Byte(0xe0), Emit::HighPage(e.clone())],
+        ("ldh", [A, Mem(e)]) => vec![Byte(0xf0), Emit::HighPage(e.clone())],
+        _ => return Err(format!("invalid instruction {}", mnemonic)),
+    };
+    Ok(emits)
+}
+
+// Splits on commas outside brackets, parentheses and strings
+fn split_operands(text: &str) -> Vec<String> {
+    let mut operands = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0;
+    let mut quoted = false;
+    for c in text.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            '[' | '(' if !quoted => depth += 1,
+            ']' | ')' if !quoted => depth -= 1,
+            ',' if !quoted && depth == 0 => {
+                operands.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            _ => (),
+        }
+        current.push(c);
+    }
+    if !current.trim().is_empty() || !operands.is_empty() {
+        operands.push(current.trim().to_string());
+    }
+    operands
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &line[..i],
+            _ => (),
+        }
+    }
+    line
+}
+
+fn string_literal(text: &str) -> Option<&str> {
+    let text = text.trim();
+    if text.len() >= 2 && text.starts_with('"') && text.ends_with('"') {
+        Some(&text[1..text.len() - 1])
+    } else {
+        None
+    }
+}
+
+fn is_label_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.chars().all(is_ident_char)
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+}
+
+// Assembles a subset of RGBDS syntax: SECTION placement in ROM0/ROMX banks
+// (and label-only RAM sections), global and local labels, EQU, DB/DW/DS,
+// INCBIN and every gbz80 instruction. The result is a padded ROM image with
+// the header fixed up.
+pub struct Assembler {
+    include_dir: PathBuf,
+    constants: HashMap<String, i64>,
+    labels: HashMap<String, Resolved>,
+    sections: Vec<Section>,
+    label_sections: HashMap<String, (usize, usize, usize)>,
+    scope: Option<String>,
+}
+
+impl Assembler {
+    pub fn new() -> Assembler {
+        Assembler::with_include_dir(".")
+    }
+
+    // INCBIN paths are relative to the directory
+    pub fn with_include_dir<P: Into<PathBuf>>(dir: P) -> Assembler {
+        Assembler {
+            include_dir: dir.into(),
+            constants: HashMap::new(),
+            labels: HashMap::new(),
+            sections: Vec::new(),
+            label_sections: HashMap::new(),
+            scope: None,
+        }
+    }
+
+    // Every label's address, for the disassembler or a debugger
+    pub fn symbols(&self) -> SymbolTable {
+        let mut symbols = SymbolTable::new();
+        for (name, label) in self.labels.iter() {
+            symbols.insert(label.bank as u16, label.addr, name.clone());
+        }
+        symbols
+    }
+
+    pub fn assemble(&mut self, source: &str) -> Result<Vec<u8>, AssembleError> {
+        self.constants.clear();
+        self.labels.clear();
+        self.sections.clear();
+        self.label_sections.clear();
+        self.scope = None;
+
+        for (i, line) in source.lines().enumerate() {
+            self.parse_line(i + 1, line)?;
+        }
+        self.place_sections()?;
+        for (name, &(section, offset, _)) in self.label_sections.iter() {
+            let section = &self.sections[section];
+            let addr = section.addr.unwrap_or(0) as usize + offset;
+            self.labels.insert(
+                name.clone(),
+                Resolved {
+                    addr: addr as u16,
+                    bank: section.bank.unwrap_or(0),
+                },
+            );
+        }
+        self.link()
+    }
+
+    fn current_section(&mut self, line: usize) -> Result<&mut Section, AssembleError> {
+        match self.sections.last_mut() {
+            Some(section) => Ok(section),
+            None => error(line, "code outside of a SECTION"),
+        }
+    }
+
+    // Evaluates with what is known while parsing: constants, @ and labels
+    // in sections with a fixed address
+    fn constant(&self, expr: &Expr) -> Result<i64, String> {
+        let here = self
+            .sections
+            .last()
+            .and_then(|s| s.addr.map(|addr| addr.wrapping_add(s.size as u16)));
+        let env = Env {
+            constants: &self.constants,
+            labels: &self.labels,
+            here,
+        };
+        env.eval(expr)
+    }
+
+    fn parse_line(&mut self, line: usize, text: &str) -> Result<(), AssembleError> {
+        let err = |message: String| AssembleError { line, message };
+        let mut text = strip_comment(text).trim();
+
+        // Labels end in ':' or '::', local labels may leave it off
+        let first = text.split_whitespace().next().unwrap_or("");
+        let name = first.trim_end_matches(':');
+        if !name.is_empty() && is_label_name(name) {
+            let next = text.split_whitespace().nth(1).unwrap_or("");
+            if next.eq_ignore_ascii_case("equ") {
+                let value_text = text
+                    .split_whitespace()
+                    .skip(2)
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                let expr = ExprParser::parse(&value_text, self.scope.as_deref()).map_err(err)?;
+                let value = self.constant(&expr).map_err(err)?;
+                self.constants.insert(name.to_string(), value);
+                return Ok(());
+            }
+            let is_local = name.starts_with('.') && name.len() > 1;
+            if first.ends_with(':') || (is_local && first.len() == text.len()) {
+                self.define_label(line, name)?;
+                text = text[text.find(name).unwrap() + first.len()..].trim();
+                text = text.trim_start_matches(':').trim();
+            } else if first.contains(':') {
+                let colon = text.find(':').unwrap();
+                if is_label_name(&text[..colon]) {
+                    self.define_label(line, &text[..colon])?;
+                    text = text[colon..].trim_start_matches(':').trim();
+                }
+            }
+        }
+        if text.is_empty() {
+            return Ok(());
+        }
+
+        let (mnemonic, rest) = match text.find(char::is_whitespace) {
+            Some(i) => (&text[..i], text[i..].trim()),
+            None => (text, ""),
+        };
+        let mnemonic = mnemonic.to_ascii_lowercase();
+        let operands = split_operands(rest);
+
+        if mnemonic == "section" {
+            return self.parse_section(line, &operands);
+        }
+
+        let scope = self.scope.clone();
+        let parse = |text: &str| ExprParser::parse(text, scope.as_deref()).map_err(err);
+        let emits = match mnemonic.as_str() {
+            "db" => {
+                let mut emits = Vec::new();
+                for operand in operands.iter() {
+                    match string_literal(operand) {
+                        Some(s) => emits.push(Emit::Bytes(s.bytes().collect())),
+                        None => emits.push(Emit::Imm8(parse(operand)?)),
+                    }
+                }
+                emits
+            }
+            "dw" => operands
+                .iter()
+                .map(|o| parse(o).map(Emit::Imm16))
+                .collect::<Result<Vec<_>, _>>()?,
+            "ds" => {
+                let count = match operands.first() {
+                    Some(count) => self.constant(&parse(count)?).map_err(err)?,
+                    None => return error(line, "DS needs a size"),
+                };
+                let fill = match operands.get(1) {
+                    Some(fill) => self.constant(&parse(fill)?).map_err(err)? as u8,
+                    None => 0,
+                };
+                if count < 0 {
+                    return error(line, format!("negative DS size {}", count));
+                }
+                vec![Emit::Fill(count as usize, fill)]
+            }
+            "incbin" => vec![Emit::Bytes(self.incbin(line, &operands)?)],
+            _ => {
+                let operands = operands
+                    .iter()
+                    .map(|o| Operand::parse(o, scope.as_deref()))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(err)?;
+                encode(&mnemonic, &operands, |e| self.constant(e)).map_err(err)?
+            }
+        };
+
+        let section = self.current_section(line)?;
+        let size: usize = emits.iter().map(Emit::len).sum();
+        let data = emits.iter().any(|e| !matches!(e, Emit::Fill(..)));
+        if data && !section.region.is_rom() {
+            return error(
+                line,
+                format!("section \"{}\" can only hold DS", section.name),
+            );
+        }
+        section.statements.push(Statement {
+            line,
+            offset: section.size,
+            emits,
+        });
+        section.size += size;
+        Ok(())
+    }
+
+    fn define_label(&mut self, line: usize, name: &str) -> Result<(), AssembleError> {
+        let name = if name.contains('.') {
+            qualify(name, self.scope.as_deref())
+                .map_err(|message| AssembleError { line, message })?
+        } else {
+            self.scope = Some(name.to_string());
+            name.to_string()
+        };
+        if let Some(&(_, _, previous)) = self.label_sections.get(&name) {
+            return error(
+                line,
+                format!("{} already defined on line {}", name, previous),
+            );
+        }
+        let index = self.sections.len().wrapping_sub(1);
+        let section = self.current_section(line)?;
+        let offset = section.size;
+        if let Some(addr) = section.addr {
+            let resolved = Resolved {
+                addr: addr.wrapping_add(offset as u16),
+                bank: section.bank.unwrap_or(0),
+            };
+            self.labels.insert(name.clone(), resolved);
+        }
+        self.label_sections.insert(name, (index, offset, line));
+        Ok(())
+    }
+
+    // SECTION "name", TYPE[addr], BANK[n]
+    fn parse_section(&mut self, line: usize, operands: &[String]) -> Result<(), AssembleError> {
+        let err = |message: String| AssembleError { line, message };
+        let name = match operands.first().and_then(|o| string_literal(o)) {
+            Some(name) => name.to_string(),
+            None => return error(line, "SECTION needs a quoted name"),
+        };
+        let (region, addr) = match operands.get(1) {
+            Some(kind) => self.bracketed(kind).map_err(err)?,
+            None => return error(line, "SECTION needs a type"),
+        };
+        let region = match Region::parse(&region) {
+            Some(region) => region,
+            None => return error(line, format!("unknown section type {}", region)),
+        };
+        let bank = match operands.get(2).map(|o| self.bracketed(o)) {
+            Some(Ok((keyword, Some(bank)))) if keyword.eq_ignore_ascii_case("bank") => {
+                Some(bank as usize)
+            }
+            Some(_) => return error(line, "expected BANK[n]"),
+            None => None,
+        };
+
+        let (start, end) = region.range();
+        if let Some(addr) = addr {
+            if !(start..end).contains(&(addr as u32)) {
+                return error(line, format!("${:04x} is outside {:?}", addr, region));
+            }
+        }
+        match (region, bank) {
+            (Region::Romx, Some(0)) => return error(line, "ROMX can't be bank 0"),
+            (Region::Romx, _) | (Region::Wramx, _) | (Region::Vram, _) | (Region::Sram, _) => (),
+            (_, Some(_)) => return error(line, format!("{:?} has no banks", region)),
+            _ => (),
+        }
+
+        self.sections.push(Section {
+            name,
+            region,
+            addr,
+            bank: bank.or_else(|| addr.map(|_| region.default_bank())),
+            size: 0,
+            statements: Vec::new(),
+        });
+        self.scope = None;
+        Ok(())
+    }
+
+    // Parses "NAME" or "NAME[expr]"
+    fn bracketed(&self, text: &str) -> Result<(String, Option<u16>), String> {
+        match text.find('[') {
+            Some(open) if text.ends_with(']') => {
+                let expr = ExprParser::parse(&text[open + 1..text.len() - 1], None)?;
+                let value = self.constant(&expr)?;
+                Ok((text[..open].trim().to_string(), Some(value as u16)))
+            }
+            _ => Ok((text.trim().to_string(), None)),
+        }
+    }
+
+    // INCBIN "file"[, start[, length]]
+    fn incbin(&self, line: usize, operands: &[String]) -> Result<Vec<u8>, AssembleError> {
+        let err = |message: String| AssembleError { line, message };
+        let path = match operands.first().and_then(|o| string_literal(o)) {
+            Some(path) => self.include_dir.join(path),
+            None => return error(line, "INCBIN needs a quoted file name"),
+        };
+        let data = fs::read(&path)
+            .map_err(|e| err(format!("could not read {}: {}", path.display(), e)))?;
+        let value = |i: usize| -> Result<Option<usize>, AssembleError> {
+            match operands.get(i) {
+                Some(o) => {
+                    let expr = ExprParser::parse(o, None).map_err(err)?;
+                    Ok(Some(self.constant(&expr).map_err(err)? as usize))
+                }
+                None => Ok(None),
+            }
+        };
+        let start = value(1)?.unwrap_or(0);
+        let length = value(2)?.unwrap_or(data.len().saturating_sub(start));
+        match data.get(start..start + length) {
+            Some(slice) => Ok(slice.to_vec()),
+            None => error(
+                line,
+                format!("{} is only {} bytes", path.display(), data.len()),
+            ),
+        }
+    }
+
+    // Floating sections go in the lowest free spot of their region, in the
+    // first bank with room. The cartridge header is kept clear.
+    fn place_sections(&mut self) -> Result<(), AssembleError> {
+        let mut used: Vec<(Region, usize, u32, u32)> =
+            vec![(Region::Rom0, 0, u32::from(HEADER_START), HEADER_END as u32)];
+        let mut fixed: Vec<(Region, usize, u32, u32, &str)> = Vec::new();
+        for section in self.sections.iter() {
+            if let (Some(addr), Some(bank)) = (section.addr, section.bank) {
+                let (_, region_end) = section.region.range();
+                if section.end() > region_end {
+                    return error(0, format!("section \"{}\" doesn't fit", section.name));
+                }
+                for &(region, other_bank, start, end, name) in fixed.iter() {
+                    let overlaps = u32::from(addr) < end && start < section.end();
+                    if region == section.region && other_bank == bank && overlaps {
+                        return error(
+                            0,
+                            format!("sections \"{}\" and \"{}\" overlap", name, section.name),
+                        );
+                    }
+                }
+                fixed.push((
+                    section.region,
+                    bank,
+                    u32::from(addr),
+                    section.end(),
+                    &section.name,
+                ));
+            }
+        }
+        used.extend(fixed.iter().map(|&(r, b, s, e, _)| (r, b, s, e)));
+
+        for i in 0..self.sections.len() {
+            let section = &self.sections[i];
+            if section.addr.is_some() && section.bank.is_some() {
+                continue;
+            }
+            let (start, end) = section.region.range();
+            let banks: Vec<usize> = match (section.bank, section.region) {
+                (Some(bank), _) => vec![bank],
+                (None, Region::Romx) => (1..0x200).collect(),
+                (None, region) => vec![region.default_bank()],
+            };
+            let size = section.size as u32;
+            let fixed_addr = section.addr.map(u32::from);
+            let mut placement = None;
+            'banks: for bank in banks {
+                let mut candidates: Vec<u32> = match fixed_addr {
+                    Some(addr) => vec![addr],
+                    None => std::iter::once(start)
+                        .chain(
+                            used.iter()
+                                .filter(|u| u.0 == section.region && u.1 == bank)
+                                .map(|u| u.3),
+                        )
+                        .collect(),
+                };
+                candidates.sort_unstable();
+                for addr in candidates {
+                    let free = used.iter().all(|&(region, b, s, e)| {
+                        region != section.region || b != bank || addr + size <= s || e <= addr
+                    });
+                    if free && addr + size <= end {
+                        placement = Some((addr, bank));
+                        break 'banks;
+                    }
+                }
+            }
+            let (addr, bank) = match placement {
+                Some(placement) => placement,
+                None => return error(0, format!("no room for section \"{}\"", section.name)),
+            };
+            used.push((section.region, bank, addr, addr + size));
+            let section = &mut self.sections[i];
+            section.addr = Some(addr as u16);
+            section.bank = Some(bank);
+        }
+        Ok(())
+    }
+
+    fn link(&self) -> Result<Vec<u8>, AssembleError> {
+        let rom_sections = self.sections.iter().filter(|s| s.region.is_rom());
+        let last_bank = rom_sections.map(|s| s.bank.unwrap_or(0)).max().unwrap_or(0);
+        let banks = (last_bank + 1).max(2).next_power_of_two();
+        let mut rom = vec![PAD; banks * ROM_BANK_SIZE];
+        // Header fields nobody sets read as zero rather than padding
+        for byte in rom[HEADER_START as usize..HEADER_END].iter_mut() {
+            *byte = 0;
+        }
+
+        for section in self.sections.iter().filter(|s| s.region.is_rom()) {
+            let base = section.addr.unwrap_or(0);
+            let mut bytes = Vec::with_capacity(section.size);
+            for statement in section.statements.iter() {
+                let addr = base.wrapping_add(statement.offset as u16);
+                let env = Env {
+                    constants: &self.constants,
+                    labels: &self.labels,
+                    here: Some(addr),
+                };
+                for emit in statement.emits.iter() {
+                    let at = base.wrapping_add(bytes.len() as u16);
+                    // DS only reserves the space, so it's fine there
+                    let start = at as usize;
+                    let overlaps =
+                        start < FIXED_HEADER.end && FIXED_HEADER.start < start + emit.len();
+                    let reserved = matches!(emit, Emit::Fill(..));
+                    if section.region == Region::Rom0 && overlaps && !reserved {
+                        return error(
+                            statement.line,
+                            format!("data at ${:04x} overlaps the cartridge header", at),
+                        );
+                    }
+                    emit.write(&env, at, &mut bytes)
+                        .map_err(|message| AssembleError {
+                            line: statement.line,
+                            message,
+                        })?;
+                }
+            }
+            let bank = section.bank.unwrap_or(0);
+            let offset = bank * ROM_BANK_SIZE + (base as usize & (ROM_BANK_SIZE - 1));
+            rom[offset..offset + bytes.len()].copy_from_slice(&bytes);
+        }
+
+        if banks > 2 && rom[CARTRIDGE_TYPE] == 0x00 {
+            rom[CARTRIDGE_TYPE] = DEFAULT_BANKED_TYPE;
+        }
+        fix_header(&mut rom);
+        Ok(rom)
+    }
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn assemble(source: &str) -> Result<Vec<u8>, AssembleError> {
+    Assembler::new().assemble(source)
+}
+
+// Assembles a file, with INCBIN relative to its directory
+pub fn assemble_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, AssembleError> {
+    let path = path.as_ref();
+    let source = fs::read_to_string(path).map_err(|e| AssembleError {
+        line: 0,
+        message: format!("could not read {}: {}", path.display(), e),
+    })?;
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    Assembler::with_include_dir(dir).assemble(&source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassembler::{decode, RomBank};
+    use crate::header::Header;
+
+    #[test]
+    fn round_trips_through_disassembler() {
+        let lines = [
+            "ld [hl+], a",
+            "ldh [c], a",
+            "ldh a, [$ff44]",
+            "ld hl, sp-2",
+            "add sp, 5",
+            "sbc a, [hl]",
+            "swap a",
+            "set 7, [hl]",
+            "ld [$c000], sp",
+            "jp hl",
+            "rst $38",
+            "pop af",
+        ];
+        let source = format!("SECTION \"Code\", ROM0[$150]\n{}\n", lines.join("\n"));
+        let rom = assemble(&source).unwrap();
+        let memory = RomBank::new(&rom, 1);
+        let mut addr = 0x150;
+        for line in lines.iter() {
+            let instruction = decode(&memory, addr);
+            assert_eq!(&instruction.to_string(), line);
+            addr += instruction.len() as u16;
+        }
+    }
+
+    #[test]
+    fn builds_valid_banked_rom() {
+        let source = r#"
+; entry point
+SECTION "Header", ROM0[$100]
+    nop
+    jp Start
+    ds $150 - @, 0
+
+SECTION "Main", ROM0[$150]
+Start:
+    ld a, BANK(Far)
+    ld [$2000], a
+    call Far
+.loop:
+    dec a
+    jr nz, .loop
+    jr Start.loop
+
+SECTION "Far", ROMX, BANK[2]
+Far::
+    ld hl, Table
+    ret
+Table:
+    db "Hi", 0, LOW(Far), HIGH(Far)
+    dw Table + 2
+"#;
+        let mut assembler = Assembler::new();
+        let rom = assembler.assemble(source).unwrap();
+        assert_eq!(rom.len(), 4 * ROM_BANK_SIZE);
+        let header = Header::parse(&rom).unwrap();
+        assert_eq!(header.validate(&rom), Ok(()));
+        assert!(crate::Cartridge::new(rom.clone()).is_ok());
+        assert_eq!(&rom[0x100..0x104], &[0x00, 0xc3, 0x50, 0x01]);
+        assert_eq!(&rom[0x150..0x155], &[0x3e, 0x02, 0xea, 0x00, 0x20]);
+        // dec a; jr nz, .loop; jr Start.loop
+        assert_eq!(&rom[0x158..0x15d], &[0x3d, 0x20, 0xfd, 0x18, 0xfb]);
+        let far = 2 * ROM_BANK_SIZE;
+        assert_eq!(&rom[far..far + 4], &[0x21, 0x04, 0x40, 0xc9]);
+        assert_eq!(
+            &rom[far + 4..far + 11],
+            &[b'H', b'i', 0, 0x00, 0x40, 0x06, 0x40]
+        );
+        assert_eq!(assembler.symbols().lookup(2, 0x4004), Some("Table"));
+        assert_eq!(assembler.symbols().lookup(0, 0x0158), Some("Start.loop"));
+    }
+
+    #[test]
+    fn reports_errors_with_lines() {
+        let source = "SECTION \"A\", ROM0[$150]\n    nop\n    jr Missing\n";
+        assert_eq!(
+            assemble(source),
+            Err(AssembleError {
+                line: 3,
+                message: "unknown symbol Missing".into()
+            })
+        );
+        let source = "SECTION \"A\", ROM0[$150]\n    ld b, $1234\n";
+        assert_eq!(assemble(source).unwrap_err().line, 2);
+        let source = "SECTION \"A\", ROM0[$150]\nds 4\nSECTION \"B\", ROM0[$152]\nnop\n";
+        assert!(assemble(source).unwrap_err().message.contains("overlap"));
+    }
+
+    #[test]
+    fn rejects_data_in_the_header() {
+        let source = "SECTION \"X\", ROM0[$130]\n    ds 4\n    db 1, 2\n";
+        assert_eq!(
+            assemble(source),
+            Err(AssembleError {
+                line: 3,
+                message: "data at $0134 overlaps the cartridge header".into()
+            })
+        );
+        let source = "SECTION \"X\", ROM0[$100]\n    nop\n    jp $150\n    ds $49, 0\n    db 0\n";
+        assert_eq!(assemble(source).unwrap_err().line, 5);
+        let source = "SECTION \"X\", ROM0[$14e]\n    dw 0\n    nop\n";
+        assert!(assemble(source).is_ok());
+    }
+}
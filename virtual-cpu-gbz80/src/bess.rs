@@ -0,0 +1,426 @@
+use std::error::Error;
+use std::fmt;
+
+use virtual_cpu_core::{Memory, Program, Registers16, Stack};
+
+use crate::cgb::Model;
+use crate::dma::Dma;
+use crate::registers::Name16;
+use crate::state::StateGbz80;
+use crate::timer::Timer;
+
+// Best Effort Save State: a chain of blocks found through a footer at the
+// very end of the file, with the bulk memory stored outside the blocks.
+// https://github.com/LIJI32/SameBoy/blob/master/BESS.md
+
+const MAGIC: &[u8; 4] = b"BESS";
+const CORE_SIZE: usize = 0xd0;
+const CORE_MAJOR: u16 = 1;
+const CORE_MINOR: u16 = 1;
+const INFO_SIZE: usize = 0x12;
+const RTC_SIZE: usize = 0x30;
+const EMULATOR_NAME: &str = concat!("virtual-cpu-gbz80 ", env!("CARGO_PKG_VERSION"));
+
+const EXECUTION_RUNNING: u8 = 0;
+const EXECUTION_HALTED: u8 = 1;
+const EXECUTION_STOPPED: u8 = 2;
+
+// The four NRx4 registers restart their channel when bit 7 is written
+const TRIGGER_REGISTERS: [u16; 4] = [0xff14, 0xff19, 0xff1e, 0xff23];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BessError {
+    NotBess,
+    Truncated,
+    MissingCore,
+    BadBlock(String),
+    UnsupportedVersion(u16),
+    UnsupportedModel(String),
+}
+
+impl fmt::Display for BessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BessError::NotBess => write!(f, "no BESS footer"),
+            BessError::Truncated => write!(f, "save state is truncated"),
+            BessError::MissingCore => write!(f, "save state has no CORE block"),
+            BessError::BadBlock(id) => write!(f, "malformed {} block", id.trim()),
+            BessError::UnsupportedVersion(major) => {
+                write!(f, "unsupported BESS version {}", major)
+            }
+            BessError::UnsupportedModel(model) => {
+                write!(
+                    f,
+                    "unsupported model \"{}\", only DMG and CGB are emulated",
+                    model
+                )
+            }
+        }
+    }
+}
+
+impl Error for BessError {}
+
+// What the file says about where it came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct BessInfo {
+    pub model: Model,
+    pub emulator: Option<String>,
+    pub title: Option<String>,
+    pub global_checksum: Option<u16>,
+}
+
+fn u16_at(data: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes([data[i], data[i + 1]])
+}
+
+fn u32_at(data: &[u8], i: usize) -> usize {
+    u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]) as usize
+}
+
+fn write_block(out: &mut Vec<u8>, id: &[u8; 4], body: &[u8]) {
+    out.extend_from_slice(id);
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(body);
+}
+
+fn model_id(model: Model) -> &'static [u8; 4] {
+    match model {
+        Model::Dmg => b"GD  ",
+        Model::Cgb => b"CC  ",
+    }
+}
+
+// The first letter is the family and the second the model. SGBs and the
+// GBA's CGB mode aren't emulated.
+fn model_for(id: &[u8]) -> Result<Model, BessError> {
+    match (id[0], id[1]) {
+        (b'G', _) => Ok(Model::Dmg),
+        (b'C', b'A') => Err(BessError::UnsupportedModel(
+            String::from_utf8_lossy(id).into(),
+        )),
+        (b'C', _) => Ok(Model::Cgb),
+        _ => Err(BessError::UnsupportedModel(
+            String::from_utf8_lossy(id).into(),
+        )),
+    }
+}
+
+// The memory areas CORE points to, in the order the format lists them
+fn memory_blocks(s: &StateGbz80) -> [&[u8]; 7] {
+    let cgb = s.m.cgb();
+    let vram = if cgb {
+        &s.m.ppu.vram[..]
+    } else {
+        &s.m.ppu.vram[..0x2000]
+    };
+    let (bg_palettes, obj_palettes) = if cgb {
+        (s.m.ppu.bg_palettes.data(), s.m.ppu.obj_palettes.data())
+    } else {
+        (&[][..], &[][..])
+    };
+    [
+        s.m.wram(),
+        vram,
+        s.m.cartridge.ram(),
+        &s.m.ppu.oam[..],
+        s.m.hram(),
+        bg_palettes,
+        obj_palettes,
+    ]
+}
+
+pub fn save_state(s: &StateGbz80) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut locations = Vec::new();
+    for block in memory_blocks(s).iter() {
+        locations.push((block.len(), out.len()));
+        out.extend_from_slice(block);
+    }
+    let first_block = out.len();
+
+    write_block(&mut out, b"NAME", EMULATOR_NAME.as_bytes());
+
+    let mut core = Vec::with_capacity(CORE_SIZE);
+    core.extend_from_slice(&CORE_MAJOR.to_le_bytes());
+    core.extend_from_slice(&CORE_MINOR.to_le_bytes());
+    core.extend_from_slice(model_id(s.m.model()));
+    let registers = [
+        s.p.get_pc(),
+        s.r.get16(Name16::AF),
+        s.r.get16(Name16::BC),
+        s.r.get16(Name16::DE),
+        s.r.get16(Name16::HL),
+        s.s.get_sp(),
+    ];
+    for register in registers.iter() {
+        core.extend_from_slice(&register.to_le_bytes());
+    }
+    let execution = if s.stopped {
+        EXECUTION_STOPPED
+    } else if s.halted {
+        EXECUTION_HALTED
+    } else {
+        EXECUTION_RUNNING
+    };
+    core.extend_from_slice(&[s.int_enable as u8, s.m.interrupts.enable, execution, 0]);
+    core.extend((0xff00..0xff80).map(|addr| s.m.read_bus(addr)));
+    for &(size, offset) in locations.iter() {
+        core.extend_from_slice(&(size as u32).to_le_bytes());
+        core.extend_from_slice(&(offset as u32).to_le_bytes());
+    }
+    write_block(&mut out, b"CORE", &core);
+
+    let rom = s.m.cartridge.rom();
+    if rom.len() >= 0x150 {
+        let mut info = rom[0x134..0x144].to_vec();
+        info.extend_from_slice(&rom[0x14e..0x150]);
+        write_block(&mut out, b"INFO", &info);
+    }
+
+    let writes = s.m.cartridge.mbc_register_writes();
+    if !writes.is_empty() {
+        let mut mbc = Vec::with_capacity(writes.len() * 3);
+        for &(addr, val) in writes.iter() {
+            mbc.extend_from_slice(&addr.to_le_bytes());
+            mbc.push(val);
+        }
+        write_block(&mut out, b"MBC ", &mbc);
+    }
+
+    if let Some(rtc) = s.m.cartridge.rtc_save_data() {
+        write_block(&mut out, b"RTC ", &rtc);
+    }
+
+    write_block(&mut out, b"END ", &[]);
+    out.extend_from_slice(&(first_block as u32).to_le_bytes());
+    out.extend_from_slice(MAGIC);
+    out
+}
+
+struct Blocks<'a> {
+    core: &'a [u8],
+    name: Option<&'a [u8]>,
+    info: Option<&'a [u8]>,
+    mbc: Option<&'a [u8]>,
+    rtc: Option<&'a [u8]>,
+}
+
+// Unknown blocks are skipped, as the format asks
+fn read_blocks(data: &[u8]) -> Result<Blocks<'_>, BessError> {
+    if data.len() < 8 || &data[data.len() - 4..] != MAGIC {
+        return Err(BessError::NotBess);
+    }
+    let end = data.len() - 8;
+    let mut offset = u32_at(data, end);
+    let (mut core, mut name, mut info, mut mbc, mut rtc) = (None, None, None, None, None);
+    loop {
+        if offset + 8 > end {
+            return Err(BessError::Truncated);
+        }
+        let id = &data[offset..offset + 4];
+        let len = u32_at(data, offset + 4);
+        let body = data
+            .get(offset + 8..offset + 8 + len)
+            .filter(|_| offset + 8 + len <= end)
+            .ok_or(BessError::Truncated)?;
+        let bad_block = || BessError::BadBlock(String::from_utf8_lossy(id).into());
+        match id {
+            b"CORE" if len < CORE_SIZE => return Err(bad_block()),
+            b"CORE" => core = Some(body),
+            b"NAME" => name = Some(body),
+            b"INFO" if len != INFO_SIZE => return Err(bad_block()),
+            b"INFO" => info = Some(body),
+            b"MBC " if !len.is_multiple_of(3) => return Err(bad_block()),
+            b"MBC " => mbc = Some(body),
+            b"RTC " if len < RTC_SIZE => return Err(bad_block()),
+            b"RTC " => rtc = Some(body),
+            b"END " => break,
+            _ => (),
+        }
+        offset += 8 + len;
+    }
+    Ok(Blocks {
+        core: core.ok_or(BessError::MissingCore)?,
+        name,
+        info,
+        mbc,
+        rtc,
+    })
+}
+
+// Replaces the machine state with the one in the file, keeping the
+// cartridge. Nothing changes unless the whole file checks out.
+pub fn load_state(s: &mut StateGbz80, data: &[u8]) -> Result<BessInfo, BessError> {
+    let blocks = read_blocks(data)?;
+    let core = blocks.core;
+    let major = u16_at(core, 0);
+    if major != CORE_MAJOR {
+        return Err(BessError::UnsupportedVersion(major));
+    }
+    let model = model_for(&core[4..8])?;
+    let mut memory = Vec::new();
+    for i in 0..7 {
+        let size = u32_at(core, 0x98 + i * 8);
+        let offset = u32_at(core, 0x9c + i * 8);
+        memory.push(
+            data.get(offset..offset + size)
+                .ok_or(BessError::Truncated)?,
+        );
+    }
+
+    let cartridge = std::mem::take(&mut s.m.cartridge);
+    *s = StateGbz80::with_model(cartridge, model);
+
+    let copy = |dest: &mut [u8], src: &[u8]| {
+        let len = dest.len().min(src.len());
+        dest[..len].copy_from_slice(&src[..len]);
+    };
+    copy(s.m.wram_mut(), memory[0]);
+    copy(&mut s.m.ppu.vram, memory[1]);
+    copy(s.m.cartridge.ram_mut(), memory[2]);
+    copy(&mut s.m.ppu.oam, memory[3]);
+    copy(s.m.hram_mut(), memory[4]);
+    if model == Model::Cgb {
+        s.m.ppu.bg_palettes.load_data(memory[5]);
+        s.m.ppu.obj_palettes.load_data(memory[6]);
+    }
+
+    if let Some(mbc) = blocks.mbc {
+        for write in mbc.chunks(3) {
+            s.m.cartridge.write_rom(u16_at(write, 0), write[2]);
+        }
+    }
+    if let Some(rtc) = blocks.rtc {
+        s.m.cartridge.load_rtc_data(&rtc[..RTC_SIZE]);
+    }
+
+    restore_io(s, &core[0x18..0x98]);
+    s.m.set_byte(0xffff, core[0x15]);
+    s.int_enable = core[0x14] != 0;
+    s.halted = core[0x16] == EXECUTION_HALTED;
+    s.stopped = core[0x16] == EXECUTION_STOPPED;
+    s.r.set16(Name16::AF, u16_at(core, 0x0a));
+    s.r.set16(Name16::BC, u16_at(core, 0x0c));
+    s.r.set16(Name16::DE, u16_at(core, 0x0e));
+    s.r.set16(Name16::HL, u16_at(core, 0x10));
+    s.s.set_sp(u16_at(core, 0x12));
+    s.p.jump(u16_at(core, 0x08));
+
+    let info = blocks.info;
+    Ok(BessInfo {
+        model,
+        emulator: blocks
+            .name
+            .map(|name| String::from_utf8_lossy(name).into_owned()),
+        title: info.map(|info| {
+            let title = info[..0x10].iter().take_while(|&&b| b != 0);
+            title.map(|&b| b as char).collect()
+        }),
+        global_checksum: info.map(|info| u16::from_be_bytes([info[0x10], info[0x11]])),
+    })
+}
+
+// Writes the registers back in an order that avoids their side effects:
+// any OAM DMA is dropped and no DMA or HDMA is started, DIV sets the counter rather than clearing it,
+// and the APU is powered before its registers are written.
+fn restore_io(s: &mut StateGbz80, io: &[u8]) {
+    let m = &mut s.m;
+    let reg = |addr: u16| io[(addr - 0xff00) as usize];
+
+    m.dma = Dma::new();
+    if m.cgb() && (reg(0xff4d) & 0x80) != 0 {
+        m.switch_speed();
+    }
+    m.timer = Timer::with_counter(u16::from(reg(0xff04)) << 8);
+    m.set_byte(0xff26, reg(0xff26));
+
+    for addr in 0xff00..0xff80 {
+        let val = match addr {
+            0xff04 | 0xff26 | 0xff44 | 0xff46 | 0xff50..=0xff55 | 0xff69 | 0xff6b => continue,
+            _ if TRIGGER_REGISTERS.contains(&addr) => reg(addr) & 0x7f,
+            _ => reg(addr),
+        };
+        m.set_byte(addr, val);
+    }
+    m.ppu.restore_line(reg(0xff44));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::mbc::ROM_BANK_SIZE;
+
+    fn mbc3_state(model: Model) -> StateGbz80 {
+        let mut rom = vec![0; 8 * ROM_BANK_SIZE];
+        // MBC3+TIMER+RAM+BATTERY, 32KB RAM
+        rom[0x147] = 0x10;
+        rom[0x149] = 0x03;
+        rom[0x134..0x138].copy_from_slice(b"BESS");
+        let cartridge = Cartridge::new(rom).unwrap().with_clock(|| 1000);
+        StateGbz80::with_model(cartridge, model)
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut s = mbc3_state(Model::Cgb);
+        s.r.set16(Name16::BC, 0x1234);
+        s.s.set_sp(0xdff0);
+        s.p.jump(0x4321);
+        s.halted = true;
+        s.m.set_byte(0xc010, 0x11);
+        s.m.set_byte(0xff70, 0x03);
+        s.m.set_byte(0xd020, 0x22);
+        s.m.set_byte(0x0000, 0x0a);
+        s.m.set_byte(0x2000, 0x05);
+        s.m.set_byte(0x4000, 0x02);
+        s.m.set_byte(0xa000, 0x33);
+        s.m.set_byte(0xff80, 0x44);
+        s.m.set_byte(0xff42, 0x55);
+        s.m.set_byte(0xff68, 0x82);
+        s.m.set_byte(0xff69, 0x66);
+        s.m.set_byte(0xffff, 0x1f);
+        s.m.switch_speed();
+        let saved = save_state(&s);
+
+        let mut restored = mbc3_state(Model::Dmg);
+        let info = load_state(&mut restored, &saved).unwrap();
+        assert_eq!(info.model, Model::Cgb);
+        assert_eq!(info.title.as_deref(), Some("BESS"));
+        assert_eq!(info.emulator.as_deref(), Some(EMULATOR_NAME));
+
+        let r = &mut restored;
+        assert_eq!(r.r.get16(Name16::BC), 0x1234);
+        assert_eq!(r.s.get_sp(), 0xdff0);
+        assert_eq!(r.p.get_pc(), 0x4321);
+        assert!(r.halted);
+        assert!(r.m.double_speed());
+        assert_eq!(r.m.get_byte(0xc010), 0x11);
+        assert_eq!(r.m.get_byte(0xd020), 0x22);
+        assert_eq!(r.m.get_byte(0xa000), 0x33);
+        assert_eq!(r.m.get_byte(0xff80), 0x44);
+        assert_eq!(r.m.get_byte(0xff42), 0x55);
+        assert_eq!(r.m.ppu.bg_palettes.color(0, 1), 0x7f66);
+        assert_eq!(r.m.get_byte(0xffff), 0x1f);
+        assert_eq!(r.m.get_byte(0x4000), r.m.cartridge.rom()[5 * ROM_BANK_SIZE]);
+        assert_eq!(r.m.get_byte(0xff04), s.m.get_byte(0xff04));
+        assert_eq!(save_state(r), saved);
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let mut s = mbc3_state(Model::Dmg);
+        assert_eq!(load_state(&mut s, b"nothing"), Err(BessError::NotBess));
+
+        let mut saved = save_state(&s);
+        let core = saved.windows(4).position(|w| w == b"CORE").unwrap();
+        saved[core + 12..core + 16].copy_from_slice(b"SN  ");
+        assert_eq!(
+            load_state(&mut s, &saved),
+            Err(BessError::UnsupportedModel("SN  ".into()))
+        );
+        saved[core..core + 4].copy_from_slice(b"XXXX");
+        assert_eq!(load_state(&mut s, &saved), Err(BessError::MissingCore));
+    }
+}
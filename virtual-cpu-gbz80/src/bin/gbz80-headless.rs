@@ -0,0 +1,156 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+use virtual_cpu_gbz80::runner::{parse_script, Runner};
+use virtual_cpu_gbz80::vgm::write_vgm;
+use virtual_cpu_gbz80::vram_view::write_views;
+
+const USAGE: &str = "usage: gbz80-headless <rom.gb> [--frames N] [--input SCRIPT] \
+[--screenshot FRAME]... [--output DIR] [--ppm] [--vram DIR] \
+[--vgm FILE [--vgm-loop FRAME]] [--no-save]
+
+Runs the ROM for N frames (default 60) and prints \"<frame> <hash>\" after
+each one. Screenshots are written to DIR as frame-NNNNNN.png (or .ppm).
+SCRIPT has one \"<frame> press|release <button>\" per line. With --vram the
+tiles, tile maps, sprites and palettes are dumped to DIR after the last frame.
+With --vgm every sound register write is saved to FILE, looping back to the
+start of FRAME if --vgm-loop is given. Battery RAM is read from and written
+back to the .sav next to the ROM, unless --no-save is given, which also stops
+the cartridge clock so runs are reproducible.";
+
+struct Options {
+    rom: PathBuf,
+    frames: u64,
+    input: Option<PathBuf>,
+    screenshots: Vec<u64>,
+    output: PathBuf,
+    ppm: bool,
+    vram: Option<PathBuf>,
+    vgm: Option<PathBuf>,
+    vgm_loop: Option<u64>,
+    no_save: bool,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut args = env::args().skip(1);
+    let mut rom = None;
+    let mut options = Options {
+        rom: PathBuf::new(),
+        frames: 60,
+        input: None,
+        screenshots: Vec::new(),
+        output: PathBuf::from("."),
+        ppm: false,
+        vram: None,
+        vgm: None,
+        vgm_loop: None,
+        no_save: false,
+    };
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{} needs a value", name));
+        match arg.as_str() {
+            "--frames" => {
+                let frames = value("--frames")?;
+                options.frames = frames
+                    .parse()
+                    .map_err(|_| format!("bad frame count {}", frames))?;
+            }
+            "--input" => options.input = Some(PathBuf::from(value("--input")?)),
+            "--screenshot" => {
+                let frame = value("--screenshot")?;
+                options
+                    .screenshots
+                    .push(frame.parse().map_err(|_| format!("bad frame {}", frame))?);
+            }
+            "--output" => options.output = PathBuf::from(value("--output")?),
+            "--ppm" => options.ppm = true,
+            "--vram" => options.vram = Some(PathBuf::from(value("--vram")?)),
+            "--vgm" => options.vgm = Some(PathBuf::from(value("--vgm")?)),
+            "--vgm-loop" => {
+                let frame = value("--vgm-loop")?;
+                options.vgm_loop = Some(frame.parse().map_err(|_| format!("bad frame {}", frame))?);
+            }
+            "--no-save" => options.no_save = true,
+            "-h" | "--help" => return Err(String::new()),
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ if rom.is_none() => rom = Some(PathBuf::from(arg)),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+    options.rom = rom.ok_or_else(|| String::from("no ROM given"))?;
+    Ok(options)
+}
+
+fn run(options: Options) -> Result<(), String> {
+    let mut runner = if options.no_save {
+        let rom = fs::read(&options.rom)
+            .map_err(|e| format!("could not read {}: {}", options.rom.display(), e))?;
+        Runner::from_rom(rom).map_err(|e| e.to_string())?
+    } else {
+        Runner::from_file(&options.rom)
+            .map_err(|e| format!("could not load {}: {}", options.rom.display(), e))?
+    };
+    if let Some(input) = &options.input {
+        let text = fs::read_to_string(input)
+            .map_err(|e| format!("could not read {}: {}", input.display(), e))?;
+        let script = parse_script(&text).map_err(|e| format!("{}: {}", input.display(), e))?;
+        runner = runner.with_script(script);
+    }
+
+    if options.vgm.is_some() {
+        runner.state.m.apu.start_recording();
+    }
+    let mut loop_cycle = None;
+    for _ in 0..options.frames {
+        if options.vgm_loop == Some(runner.frame() + 1) {
+            loop_cycle = Some(runner.state.m.apu.cycles());
+        }
+        runner.run_frame();
+        let frame = runner.frame();
+        println!("{} {:016x}", frame, runner.frame_hash());
+        if options.screenshots.contains(&frame) {
+            let extension = if options.ppm { "ppm" } else { "png" };
+            let path = options
+                .output
+                .join(format!("frame-{:06}.{}", frame, extension));
+            let image = runner.screenshot();
+            let result = if options.ppm {
+                image.write_ppm(&path)
+            } else {
+                image.write_png(&path)
+            };
+            result.map_err(|e| format!("could not write {}: {}", path.display(), e))?;
+        }
+    }
+    if let (Some(path), Some(recording)) = (&options.vgm, runner.state.m.apu.stop_recording()) {
+        write_vgm(path, &recording, loop_cycle)
+            .map_err(|e| format!("could not write {}: {}", path.display(), e))?;
+    }
+    if let Some(dir) = &options.vram {
+        write_views(&runner.state.m.ppu, dir)
+            .map_err(|e| format!("could not write {}: {}", dir.display(), e))?;
+    }
+    runner
+        .save()
+        .map_err(|e| format!("could not save battery RAM: {}", e))?;
+    Ok(())
+}
+
+fn main() {
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(message) => {
+            if !message.is_empty() {
+                eprintln!("Error: {}", message);
+            }
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(message) = run(options) {
+        eprintln!("Error: {}", message);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,251 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::header::{Header, HeaderError, Mapper};
+use crate::mbc::*;
+use crate::rtc::{Clock, Rtc, SystemClock, RTC_SAVE_SIZE};
+
+pub struct Cartridge {
+    header: Header,
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    mbc: Box<dyn Mbc>,
+    save_path: Option<PathBuf>,
+    clock: Box<dyn Clock>,
+}
+
+fn mbc_for(header: &Header) -> Result<Box<dyn Mbc>, HeaderError> {
+    Ok(match header.mapper()? {
+        Mapper::RomOnly => Box::new(RomOnly),
+        Mapper::Mbc1 => Box::new(Mbc1::new()),
+        Mapper::Mbc2 => Box::new(Mbc2::new()),
+        Mapper::Mbc3 => Box::new(Mbc3::new(header.has_timer())),
+        Mapper::Mbc5 => Box::new(Mbc5::new(header.has_rumble())),
+    })
+}
+
+impl Cartridge {
+    pub fn new(rom: Vec<u8>) -> Result<Cartridge, HeaderError> {
+        let header = Header::parse(&rom)?;
+        let mbc = mbc_for(&header)?;
+        let ram = vec![0; header.ram_size()?];
+
+        Ok(Cartridge {
+            header,
+            rom,
+            ram,
+            mbc,
+            save_path: None,
+            clock: Box::new(SystemClock),
+        })
+    }
+
+    // Battery-backed carts keep their RAM in a .sav file next to the ROM,
+    // which is read here if it exists and written back by save()
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Cartridge> {
+        let path = path.as_ref();
+        let mut cartridge = Cartridge::new(fs::read(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        if cartridge.header.has_battery() {
+            let save_path = path.with_extension("sav");
+            if save_path.exists() {
+                cartridge.load_save_data(&fs::read(&save_path)?);
+            }
+            cartridge.save_path = Some(save_path);
+        }
+        Ok(cartridge)
+    }
+
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Cartridge {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    pub fn save_path(&self) -> Option<&Path> {
+        self.save_path.as_deref()
+    }
+
+    pub fn set_save_path(&mut self, path: impl Into<PathBuf>) {
+        self.save_path = Some(path.into());
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        match &self.save_path {
+            Some(path) => fs::write(path, self.save_data()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn save_data(&self) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        if let Some(rtc) = self.mbc.rtc() {
+            data.extend_from_slice(&rtc.save_data(self.clock.now()));
+        }
+        data
+    }
+
+    // Short files fill the start of RAM, anything past the RAM is taken to
+    // be the RTC trailer
+    pub fn load_save_data(&mut self, data: &[u8]) {
+        let len = data.len().min(self.ram.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+
+        let now = self.clock.now();
+        let trailer = &data[len..];
+        if let Some(rtc) = self.mbc.rtc_mut() {
+            if trailer.len() >= RTC_SAVE_SIZE - 4 {
+                rtc.load_save_data(trailer, now);
+            }
+        }
+    }
+
+    pub fn rtc(&self) -> Option<&Rtc> {
+        self.mbc.rtc()
+    }
+
+    // The RTC trailer as it would be written to the .sav file
+    pub fn rtc_save_data(&self) -> Option<[u8; RTC_SAVE_SIZE]> {
+        self.mbc.rtc().map(|rtc| rtc.save_data(self.clock.now()))
+    }
+
+    pub fn load_rtc_data(&mut self, data: &[u8]) {
+        let now = self.clock.now();
+        if let Some(rtc) = self.mbc.rtc_mut() {
+            rtc.load_save_data(data, now);
+        }
+    }
+
+    pub fn mbc_register_writes(&self) -> Vec<(u16, u8)> {
+        self.mbc.register_writes()
+    }
+
+    pub fn tick(&mut self, cycles: usize) {
+        if let Some(rtc) = self.mbc.rtc_mut() {
+            rtc.tick(cycles);
+        }
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    pub fn rom_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.rom
+    }
+
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    pub fn read_rom(&self, addr: u16) -> u8 {
+        self.mbc.read_rom(&self.rom, addr)
+    }
+
+    pub fn write_rom(&mut self, addr: u16, val: u8) {
+        self.mbc.write_register(addr, val);
+    }
+
+    pub fn read_ram(&self, addr: u16) -> u8 {
+        self.mbc.read_ram(&self.ram, addr)
+    }
+
+    pub fn write_ram(&mut self, addr: u16, val: u8) {
+        self.mbc.write_ram(&mut self.ram, addr, val);
+    }
+}
+
+impl Default for Cartridge {
+    fn default() -> Self {
+        Cartridge::new(vec![0; 2 * ROM_BANK_SIZE]).expect("blank ROM has a valid header")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    // MBC3+TIMER+RAM+BATTERY and MBC1+RAM+BATTERY, each with 8K of RAM
+    const MBC3_RTC: u8 = 0x10;
+    const MBC1_BATTERY: u8 = 0x03;
+
+    fn battery_rom(cartridge_type: u8) -> Vec<u8> {
+        let mut rom = vec![0; 2 * ROM_BANK_SIZE];
+        rom[0x0147] = cartridge_type;
+        rom[0x0149] = 0x02;
+        rom
+    }
+
+    fn set_rtc_minutes(cartridge: &mut Cartridge, minutes: u8) {
+        cartridge.write_rom(0x0000, 0x0a);
+        cartridge.write_rom(0x4000, 0x09);
+        cartridge.write_ram(0xa000, minutes);
+    }
+
+    #[test]
+    fn save_data_round_trip() {
+        let mut cartridge = Cartridge::new(battery_rom(MBC3_RTC))
+            .unwrap()
+            .with_clock(|| 1000);
+        cartridge.ram_mut()[0] = 0x12;
+        cartridge.ram_mut()[0x1fff] = 0x34;
+        set_rtc_minutes(&mut cartridge, 10);
+        let data = cartridge.save_data();
+        assert_eq!(data.len(), 0x2000 + RTC_SAVE_SIZE);
+
+        // An hour and five seconds pass while switched off
+        let mut restored = Cartridge::new(battery_rom(MBC3_RTC))
+            .unwrap()
+            .with_clock(|| 1000 + 3605);
+        restored.load_save_data(&data);
+        assert_eq!(restored.ram(), cartridge.ram());
+        let rtc = restored.rtc().unwrap().registers();
+        assert_eq!((rtc.hours, rtc.minutes, rtc.seconds), (1, 10, 5));
+
+        // Older saves have a 32-bit timestamp
+        restored.load_save_data(&data[..0x2000 + 44]);
+        assert_eq!(restored.rtc().unwrap().registers().seconds, 5);
+    }
+
+    #[test]
+    fn short_save_files() {
+        let mut cartridge = Cartridge::new(battery_rom(MBC3_RTC))
+            .unwrap()
+            .with_clock(|| 0);
+        cartridge.load_save_data(&[1, 2, 3]);
+        assert_eq!(&cartridge.ram()[..4], &[1, 2, 3, 0]);
+
+        // A cut-off RTC trailer is ignored, the RAM before it is not
+        set_rtc_minutes(&mut cartridge, 10);
+        cartridge.load_save_data(&vec![0xff; 0x2000 + 10]);
+        assert_eq!(cartridge.ram()[0x1fff], 0xff);
+        assert_eq!(cartridge.rtc().unwrap().registers().minutes, 10);
+    }
+
+    #[test]
+    fn save_file_next_to_rom() {
+        let dir = env::temp_dir().join(format!("gbz80-cartridge-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rom_path = dir.join("game.gb");
+        fs::write(&rom_path, battery_rom(MBC1_BATTERY)).unwrap();
+
+        let mut cartridge = Cartridge::from_file(&rom_path).unwrap();
+        assert_eq!(cartridge.save_path(), Some(dir.join("game.sav").as_path()));
+        cartridge.ram_mut()[5] = 0x77;
+        cartridge.save().unwrap();
+
+        let reloaded = Cartridge::from_file(&rom_path).unwrap();
+        assert_eq!(reloaded.ram()[5], 0x77);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
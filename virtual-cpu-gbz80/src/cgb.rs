@@ -0,0 +1,184 @@
+use crate::header::{CgbSupport, Header};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Model {
+    Dmg,
+    Cgb,
+}
+
+impl Model {
+    // Cartridges that advertise colour support get a CGB
+    pub fn for_header(header: &Header) -> Model {
+        match header.cgb {
+            CgbSupport::None => Model::Dmg,
+            CgbSupport::Compatible | CgbSupport::Only => Model::Cgb,
+        }
+    }
+}
+
+// 8 palettes of 4 RGB555 colours, reached through an index register
+// (BCPS/OCPS) and a data register (BCPD/OCPD). Bit 7 of the index makes
+// data writes advance it.
+#[derive(Debug)]
+pub struct ColorPalettes {
+    index: u8,
+    auto_increment: bool,
+    data: [u8; 0x40],
+}
+
+impl ColorPalettes {
+    pub fn new() -> ColorPalettes {
+        ColorPalettes {
+            index: 0,
+            auto_increment: false,
+            data: [0xff; 0x40],
+        }
+    }
+
+    pub fn read_index(&self) -> u8 {
+        0x40 | (self.auto_increment as u8) << 7 | self.index
+    }
+
+    pub fn write_index(&mut self, val: u8) {
+        self.index = val & 0x3f;
+        self.auto_increment = (val & 0x80) != 0;
+    }
+
+    pub fn read_data(&self) -> u8 {
+        self.data[self.index as usize]
+    }
+
+    pub fn write_data(&mut self, val: u8) {
+        self.data[self.index as usize] = val;
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0x3f;
+        }
+    }
+
+    // All 64 bytes of palette memory
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn load_data(&mut self, data: &[u8]) {
+        let len = data.len().min(self.data.len());
+        self.data[..len].copy_from_slice(&data[..len]);
+    }
+
+    pub fn color(&self, palette: u8, color: u8) -> u16 {
+        let i = (palette as usize & 0x07) * 8 + color as usize * 2;
+        u16::from(self.data[i]) | (u16::from(self.data[i + 1]) & 0x7f) << 8
+    }
+}
+
+impl Default for ColorPalettes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// VRAM DMA (0xff51-0xff55). General-purpose transfers copy every block at
+// once, HBlank transfers copy one 16-byte block at the start of each HBlank.
+#[derive(Debug)]
+pub struct Hdma {
+    source: u16,
+    dest: u16,
+    remaining: u8,
+    hblank: bool,
+}
+
+impl Hdma {
+    pub fn new() -> Hdma {
+        Hdma {
+            source: 0,
+            dest: 0,
+            remaining: 0x7f,
+            hblank: false,
+        }
+    }
+
+    pub fn hblank_active(&self) -> bool {
+        self.hblank
+    }
+
+    // Bit 7 reads 0 while an HBlank transfer runs, so a finished transfer
+    // reads 0xff
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xff55 => (!self.hblank as u8) << 7 | self.remaining,
+            _ => 0xff,
+        }
+    }
+
+    // Returns how many blocks must be copied straight away
+    pub fn write(&mut self, addr: u16, val: u8) -> usize {
+        match addr {
+            0xff51 => self.source = (self.source & 0x00ff) | u16::from(val) << 8,
+            0xff52 => self.source = (self.source & 0xff00) | u16::from(val & 0xf0),
+            0xff53 => self.dest = (self.dest & 0x00ff) | u16::from(val & 0x1f) << 8,
+            0xff54 => self.dest = (self.dest & 0xff00) | u16::from(val & 0xf0),
+            0xff55 => {
+                if self.hblank && (val & 0x80) == 0 {
+                    self.hblank = false;
+                    return 0;
+                }
+                self.remaining = val & 0x7f;
+                if (val & 0x80) != 0 {
+                    self.hblank = true;
+                } else {
+                    return self.remaining as usize + 1;
+                }
+            }
+            _ => (),
+        }
+        0
+    }
+
+    // Source and VRAM destination of the next block
+    pub fn next_block(&mut self) -> (u16, u16) {
+        let block = (self.source, 0x8000 | self.dest);
+        self.source = self.source.wrapping_add(0x10);
+        self.dest = (self.dest + 0x10) & 0x1ff0;
+        self.remaining = self.remaining.wrapping_sub(1) & 0x7f;
+        if self.remaining == 0x7f {
+            self.hblank = false;
+        }
+        block
+    }
+}
+
+impl Default for Hdma {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn palette_auto_increment() {
+        let mut palettes = ColorPalettes::new();
+        palettes.write_index(0x86);
+        palettes.write_data(0x1f);
+        palettes.write_data(0x7c);
+        assert_eq!(palettes.read_index(), 0xc8);
+        assert_eq!(palettes.color(0, 3), 0x7c1f);
+    }
+
+    #[test]
+    fn hblank_transfer_counts_down() {
+        let mut hdma = Hdma::new();
+        hdma.write(0xff51, 0xc1);
+        hdma.write(0xff52, 0x23);
+        hdma.write(0xff53, 0xff);
+        hdma.write(0xff54, 0x00);
+        assert_eq!(hdma.write(0xff55, 0x81), 0);
+        assert_eq!(hdma.read(0xff55), 0x01);
+        assert_eq!(hdma.next_block(), (0xc120, 0x9f00));
+        assert_eq!(hdma.read(0xff55), 0x00);
+        assert_eq!(hdma.next_block(), (0xc130, 0x9f10));
+        assert_eq!(hdma.read(0xff55), 0xff);
+    }
+}
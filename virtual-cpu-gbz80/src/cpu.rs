@@ -1,27 +1,54 @@
-use virtual_cpu_8080::flags::Flags8080;
-use virtual_cpu_8080::instructions::*;
-use virtual_cpu_8080::registers::{Name16, Name8};
-use virtual_cpu_8080::state::State8080 as State;
-use virtual_cpu_core::{Memory, Program, Registers16, Registers8, Stack};
+use virtual_cpu_core::{Cpu, Program, Registers16, Registers8, Stack};
 
+use crate::flags::FlagsGbz80;
+use crate::instructions::*;
+use crate::memory::MemoryGbz80;
+use crate::registers::{Name16, Name8};
+use crate::state::StateGbz80 as State;
+
+// STOP (0x10) is fetched along with the padding byte after it, so like the
+// other two-byte instructions it can't take less than two M-cycles
 static OPCODE_TIMING: [usize; 256] = [
-    4, 10, 7, 5, 5, 5, 7, 4, 4, 10, 7, 5, 5, 5, 7, 4, //0x00..0x0f
-    4, 10, 7, 5, 5, 5, 7, 4, 4, 10, 7, 5, 5, 5, 7, 4, //0x10..0x1f
-    4, 10, 16, 5, 5, 5, 7, 4, 4, 10, 16, 5, 5, 5, 7, 4, //etc
-    4, 10, 13, 5, 10, 10, 10, 4, 4, 10, 13, 5, 5, 5, 7, 4, 5, 5, 5, 5, 5, 5, 7, 5, 5, 5, 5, 5, 5,
-    5, 7, 5, //0x40..0x4f
-    5, 5, 5, 5, 5, 5, 7, 5, 5, 5, 5, 5, 5, 5, 7, 5, 5, 5, 5, 5, 5, 5, 7, 5, 5, 5, 5, 5, 5, 5, 7, 5,
-    7, 7, 7, 7, 7, 7, 7, 7, 5, 5, 5, 5, 5, 5, 7, 5, 4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7,
-    4, //0x80..8x4f
-    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
-    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, 11, 10, 10, 10, 17, 11, 7, 11, 11, 10, 10, 10,
-    10, 17, 7, 11, //0xc0..0xcf
-    11, 10, 10, 10, 17, 11, 7, 11, 11, 10, 10, 10, 10, 17, 7, 11, 11, 10, 10, 18, 17, 11, 7, 11,
-    11, 5, 10, 5, 17, 17, 7, 11, 11, 10, 10, 4, 17, 11, 7, 11, 11, 5, 10, 4, 17, 17, 7, 11,
+    4, 12, 8, 8, 4, 4, 8, 4, 20, 8, 8, 8, 4, 4, 8, 4, // 0x00..0x0f
+    8, 12, 8, 8, 4, 4, 8, 4, 12, 8, 8, 8, 4, 4, 8, 4, // 0x10..0x1f
+    8, 12, 8, 8, 4, 4, 8, 4, 8, 8, 8, 8, 4, 4, 8, 4, // 0x20..0x2f
+    8, 12, 8, 8, 12, 12, 12, 4, 8, 8, 8, 8, 4, 4, 8, 4, // 0x30..0x3f
+    4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4, // 0x40..0x4f
+    4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4, // 0x50..0x5f
+    4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4, // 0x60..0x6f
+    8, 8, 8, 8, 8, 8, 4, 8, 4, 4, 4, 4, 4, 4, 8, 4, // 0x70..0x7f
+    4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4, // 0x80..0x8f
+    4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4, // 0x90..0x9f
+    4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4, // 0xa0..0xaf
+    4, 4, 4, 4, 4, 4, 8, 4, 4, 4, 4, 4, 4, 4, 8, 4, // 0xb0..0xbf
+    8, 12, 12, 16, 12, 16, 8, 16, 8, 16, 12, 0, 12, 24, 8, 16, // 0xc0..0xcf
+    8, 12, 12, 4, 12, 16, 8, 16, 8, 16, 12, 4, 12, 4, 8, 16, // 0xd0..0xdf
+    12, 12, 8, 4, 4, 16, 8, 16, 16, 4, 16, 4, 4, 4, 8, 16, // 0xe0..0xef
+    12, 12, 8, 4, 4, 16, 8, 16, 12, 8, 16, 4, 4, 4, 8, 16, // 0xf0..0xff
 ];
 
-fn get_operand(state: &State, opcode: u8) -> u8 {
-    let operand_code = opcode & 0x07;
+// Extra cycles taken by conditional control flow when the condition holds
+fn branch_timing(opcode: u8) -> usize {
+    match opcode {
+        0x20 | 0x28 | 0x30 | 0x38 => 4,  // JR cc
+        0xc2 | 0xca | 0xd2 | 0xda => 4,  // JP cc
+        0xc0 | 0xc8 | 0xd0 | 0xd8 => 12, // RET cc
+        0xc4 | 0xcc | 0xd4 | 0xdc => 12, // CALL cc
+        _ => 0,
+    }
+}
+
+fn cb_timing(opcode: u8) -> usize {
+    match (opcode & 0x07, opcode >> 6) {
+        (0x06, 0x1) => 12, // BIT n,(HL)
+        (0x06, _) => 16,
+        _ => 8,
+    }
+}
+
+// Register codes follow the 8080 order, with 0x6 meaning (HL)
+fn get_operand(state: &mut State, code: u8) -> u8 {
+    let operand_code = code & 0x07;
     if operand_code == 0x06 {
         state.get_indirect8(Name16::HL)
     } else {
@@ -29,12 +56,21 @@ fn get_operand(state: &State, opcode: u8) -> u8 {
     }
 }
 
+fn set_operand(state: &mut State, code: u8, val: u8) {
+    let operand_code = code & 0x07;
+    if operand_code == 0x06 {
+        state.mov_pi8(Name16::HL, val);
+    } else {
+        state.r.set8(register_for_code(operand_code), val);
+    }
+}
+
 fn mov_for(state: &mut State, opcode: u8) {
     let input_code = opcode & 0x07;
     let output_code = (opcode >> 3) & 0x07;
 
     match (input_code, output_code) {
-        (0x06, 0x06) => unimplemented_instruction(state, opcode), // this is HLT
+        (0x06, 0x06) => state.halt(), // HALT
         (0x06, reg) => state.mov_rp8(register_for_code(reg), Name16::HL),
         (reg, 0x06) => state.mov_pr8(Name16::HL, register_for_code(reg)),
         (input, output) => state.mov_rr8(register_for_code(output), register_for_code(input)),
@@ -46,150 +82,112 @@ fn operate8(state: &mut State, opcode: u8, operand: u8) {
         0x0 => state.add_ri8(operand),
         0x1 => state.adc_ri8(operand),
         0x2 => state.sub_ri8(operand),
-        0x3 => state.sbb_ri8(operand),
-        0x4 => state.logical_operation_ri(operand, and8),
-        0x5 => state.logical_operation_ri(operand, xor8),
-        0x6 => state.logical_operation_ri(operand, or8),
+        0x3 => state.sbc_ri8(operand),
+        0x4 => state.and_ri8(operand),
+        0x5 => state.xor_ri8(operand),
+        0x6 => state.or_ri8(operand),
         0x7 => state.cmp_ri8(operand),
         _ => panic!("Shouldn't happen"),
     }
 }
 
-fn unimplemented_instruction(s: &mut State, opcode: u8) {
-    println!(
-        "Error: unimplemented instruction 0x{:02x} at 0x{:04x}",
-        opcode,
-        s.p.get_pc()
-    );
-    println!("{:?}", s);
-    panic!("unimplemented");
-}
-
 pub fn emulate_group0(instruction: &[u8], s: &mut State) {
     let opcode = instruction[0];
 
     match opcode & 0x3f {
-        0x00 => (),                                                 // NOP
-        0x01 => s.mov_ri16(Name16::BC, word_arg_from(instruction)), // LD BC,nn
-        0x02 => s.mov_pr8(Name16::BC, Name8::B),                    // LD (BC), A
-        0x03 => s.r.update16(Name16::BC, inc16),                    // INC BC
-        0x04 => s.unary_math_r8(Name8::B, inc8),                    // INC B
-        0x05 => s.unary_math_r8(Name8::B, dec8),                    // DEC B
-        0x06 => s.mov_ri8(Name8::B, byte_arg_from(instruction)),    // LD B,n
-        0x07 => {
-            // RLC A
-            let x = s.r.get8(Name8::A);
-            s.r.set8(Name8::A, x.rotate_left(1));
-            s.r.cc.cy = (x & 0x80) != 0;
-        }
-        0x08 => s.m.set_word(word_arg_from(instruction), s.s.get_sp()), // LD (nn),SP
-        0x09 => s.add_rr16(Name16::BC),                                 // ADD HL,BC
-        0x0a => s.mov_rp8(Name8::A, Name16::BC),                        // LD A,(BC)
-        0x0b => s.r.update16(Name16::BC, dec16),                        // DEC BC
-        0x0c => s.unary_math_r8(Name8::C, inc8),                        // INC C
-        0x0d => s.unary_math_r8(Name8::C, dec8),                        // DEC C
-        0x0e => s.mov_ri8(Name8::C, byte_arg_from(instruction)),        // LD C,n
-        0x0f => {
-            // RRC
-            let x = s.r.get8(Name8::A);
-            s.r.set8(Name8::A, x.rotate_right(1));
-            s.r.cc.cy = (x & 0x01) == 1;
-        }
+        0x00 => (),                                                   // NOP
+        0x01 => s.mov_ri16(Name16::BC, word_arg_from(instruction)),   // LD BC,nn
+        0x02 => s.mov_pr8(Name16::BC, Name8::A),                      // LD (BC),A
+        0x03 => s.r.update16(Name16::BC, inc16),                      // INC BC
+        0x04 => s.inc_r8(Name8::B),                                   // INC B
+        0x05 => s.dec_r8(Name8::B),                                   // DEC B
+        0x06 => s.mov_ri8(Name8::B, byte_arg_from(instruction)),      // LD B,n
+        0x07 => s.rotate_a(0x00),                                     // RLCA
+        0x08 => s.mov_ai16(word_arg_from(instruction), s.s.get_sp()), // LD (nn),SP
+        0x09 => s.add_rr16(Name16::BC),                               // ADD HL,BC
+        0x0a => s.mov_rp8(Name8::A, Name16::BC),                      // LD A,(BC)
+        0x0b => s.r.update16(Name16::BC, dec16),                      // DEC BC
+        0x0c => s.inc_r8(Name8::C),                                   // INC C
+        0x0d => s.dec_r8(Name8::C),                                   // DEC C
+        0x0e => s.mov_ri8(Name8::C, byte_arg_from(instruction)),      // LD C,n
+        0x0f => s.rotate_a(0x08),                                     // RRCA
 
-        0x10 => unimplemented_instruction(s, opcode), // STOP
-        0x11 => s.mov_ri16(Name16::DE, word_arg_from(instruction)), // LD D,nn
-        0x12 => s.mov_pr8(Name16::DE, Name8::A),      // LD (DE),A
-        0x13 => s.r.update16(Name16::DE, inc16),      // INC DE
-        0x14 => s.unary_math_r8(Name8::D, inc8),      // INC D
-        0x15 => s.unary_math_r8(Name8::D, dec8),      // DEC D
-        0x16 => s.mov_ri8(Name8::D, byte_arg_from(instruction)), // LD D,n
-        0x17 => {
-            // RL A
-            let x = s.r.get8(Name8::A);
-            s.r.a = (s.r.cc.cy as u8) | (x << 1);
-            s.r.cc.cy = (x & 0x80) != 0;
-        }
-        0x18 => s.jr_o(byte_arg_from(instruction)), // JR n
-        0x19 => s.add_rr16(Name16::DE),             // ADD HL,DE
-        0x1a => s.mov_rp8(Name8::A, Name16::DE),    // LD A,(DE)
-        0x1b => s.r.update16(Name16::DE, dec16),    // DEC DE
-        0x1c => s.unary_math_r8(Name8::E, inc8),    // INC E
-        0x1d => s.unary_math_r8(Name8::E, dec8),    // DEC E
-        0x1e => s.mov_ri8(Name8::E, byte_arg_from(instruction)), // LD E,n
-        0x1f => {
-            // RR A
-            let x = s.r.get8(Name8::A);
-            s.r.a = ((s.r.cc.cy as u8) << 7) | (x >> 1);
-            s.r.cc.cy = (x & 0x01) == 1;
-        }
+        0x10 => s.stop(),                                           // STOP
+        0x11 => s.mov_ri16(Name16::DE, word_arg_from(instruction)), // LD DE,nn
+        0x12 => s.mov_pr8(Name16::DE, Name8::A),                    // LD (DE),A
+        0x13 => s.r.update16(Name16::DE, inc16),                    // INC DE
+        0x14 => s.inc_r8(Name8::D),                                 // INC D
+        0x15 => s.dec_r8(Name8::D),                                 // DEC D
+        0x16 => s.mov_ri8(Name8::D, byte_arg_from(instruction)),    // LD D,n
+        0x17 => s.rotate_a(0x10),                                   // RLA
+        0x18 => s.jr_o(byte_arg_from(instruction)),                 // JR n
+        0x19 => s.add_rr16(Name16::DE),                             // ADD HL,DE
+        0x1a => s.mov_rp8(Name8::A, Name16::DE),                    // LD A,(DE)
+        0x1b => s.r.update16(Name16::DE, dec16),                    // DEC DE
+        0x1c => s.inc_r8(Name8::E),                                 // INC E
+        0x1d => s.dec_r8(Name8::E),                                 // DEC E
+        0x1e => s.mov_ri8(Name8::E, byte_arg_from(instruction)),    // LD E,n
+        0x1f => s.rotate_a(0x18),                                   // RRA
 
-        0x20 => s.jr_if(byte_arg_from(instruction), Flags8080::is_nz), // JR NZ,n
-        0x21 => s.mov_ri16(Name16::HL, word_arg_from(instruction)),    // LD HL,nn
+        0x20 => s.jr_if(byte_arg_from(instruction), FlagsGbz80::is_nz), // JR NZ,n
+        0x21 => s.mov_ri16(Name16::HL, word_arg_from(instruction)),     // LD HL,nn
         0x22 => {
             // LDI (HL),A
             s.mov_pr8(Name16::HL, Name8::A);
             s.r.update16(Name16::HL, inc16);
         }
         0x23 => s.r.update16(Name16::HL, inc16), // INC HL
-        0x24 => s.unary_math_r8(Name8::H, inc8), // INC H
-        0x25 => s.unary_math_r8(Name8::H, dec8), // DEC H
+        0x24 => s.inc_r8(Name8::H),              // INC H
+        0x25 => s.dec_r8(Name8::H),              // DEC H
         0x26 => s.mov_ri8(Name8::H, byte_arg_from(instruction)), // LD H,n
-        0x27 => {
-            // DAA
-            if (s.r.get8(Name8::A) & 0x0f) > 9 {
-                s.r.a += 6;
-            }
-            if (s.r.get8(Name8::A) & 0xf0) > 0x90 {
-                s.add_ri8(0x60);
-            }
-        }
-        0x28 => s.jr_if(byte_arg_from(instruction), Flags8080::is_z), // JR Z,n
-        0x29 => s.add_rr16(Name16::HL),                               // ADD HL,HL
+        0x27 => s.daa(),                         // DAA
+        0x28 => s.jr_if(byte_arg_from(instruction), FlagsGbz80::is_z), // JR Z,n
+        0x29 => s.add_rr16(Name16::HL),          // ADD HL,HL
         0x2a => {
             // LDI A,(HL)
             s.mov_rp8(Name8::A, Name16::HL);
             s.r.update16(Name16::HL, inc16);
         }
         0x2b => s.r.update16(Name16::HL, dec16), // DEC HL
-        0x2c => s.unary_math_r8(Name8::L, inc8), // INC L
-        0x2d => s.unary_math_r8(Name8::L, dec8), // DEC L
+        0x2c => s.inc_r8(Name8::L),              // INC L
+        0x2d => s.dec_r8(Name8::L),              // DEC L
         0x2e => s.mov_ri8(Name8::L, byte_arg_from(instruction)), // LD L,n
-        0x2f => s.r.update8(Name8::A, |a| !a),   // CPL
+        0x2f => s.cpl(),                         // CPL
 
-        0x30 => s.jr_if(byte_arg_from(instruction), Flags8080::is_nc), // JR NC,n
-        0x31 => s.s.set_sp(word_arg_from(instruction)),                // LD SP,nn
+        0x30 => s.jr_if(byte_arg_from(instruction), FlagsGbz80::is_nc), // JR NC,n
+        0x31 => s.s.set_sp(word_arg_from(instruction)),                 // LD SP,nn
         0x32 => {
             // LDD (HL),A
             s.mov_pr8(Name16::HL, Name8::A);
             s.r.update16(Name16::HL, dec16);
         }
-        0x33 => s.s.set_sp(s.s.get_sp() + 1), // INC SP
+        0x33 => s.s.set_sp(s.s.get_sp().wrapping_add(1)), // INC SP
         0x34 => {
             // INC (HL)
-            let new_value = s.get_indirect8(Name16::HL).wrapping_add(1);
-            s.r.cc.set_flags_no_carry(new_value);
+            let value = s.get_indirect8(Name16::HL);
+            let new_value = s.increment8(value);
             s.mov_pi8(Name16::HL, new_value);
         }
         0x35 => {
             // DEC (HL)
-            let new_value = s.get_indirect8(Name16::HL).wrapping_sub(1);
-            s.r.cc.set_flags_no_carry(new_value);
+            let value = s.get_indirect8(Name16::HL);
+            let new_value = s.decrement8(value);
             s.mov_pi8(Name16::HL, new_value);
         }
         0x36 => s.mov_pi8(Name16::HL, byte_arg_from(instruction)), // LD (HL),n
-        0x37 => s.r.cc.cy = true,                                  // SCF
-        0x38 => s.jr_if(byte_arg_from(instruction), Flags8080::is_c), // JR C,n
+        0x37 => s.scf(),                                           // SCF
+        0x38 => s.jr_if(byte_arg_from(instruction), FlagsGbz80::is_c), // JR C,n
         0x39 => s.add_ri16(s.s.get_sp()),                          // ADD HL,SP
         0x3a => {
             // LDD A,(HL)
             s.mov_rp8(Name8::A, Name16::HL);
             s.r.update16(Name16::HL, dec16);
         }
-        0x3b => s.s.set_sp(s.s.get_sp() - 1),    // DEC SP
-        0x3c => s.unary_math_r8(Name8::A, inc8), // INC A
-        0x3d => s.unary_math_r8(Name8::A, dec8), // DEC A
+        0x3b => s.s.set_sp(s.s.get_sp().wrapping_sub(1)), // DEC SP
+        0x3c => s.inc_r8(Name8::A),                       // INC A
+        0x3d => s.dec_r8(Name8::A),                       // DEC A
         0x3e => s.mov_ri8(Name8::A, byte_arg_from(instruction)), // LD A,n
-        0x3f => s.r.cc.cy = false,               // CCF
+        0x3f => s.ccf(),                                  // CCF
         _ => panic!("Unknown opcode"),
     }
 }
@@ -208,7 +206,7 @@ fn emulate_group3(instruction: &[u8], s: &mut State) {
         0xc8 => s.ret_if(instruction),                 // RET Z (implicitly)
         0xc9 => s.ret(),                               // RET
         0xca => s.jump_if(instruction),                // JP Z,nn (implicitly)
-        0xcb => unimplemented_instruction(s, opcode),  // Prefix
+        0xcb => emulate_cb(s, byte_arg_from(instruction)), // Prefix
         0xcc => s.call_if(instruction),                // CALL Z,nn (implicitly)
         0xcd => s.call_a(word_arg_from(instruction)),  // CALL nn
         0xce => s.adc_ri8(byte_arg_from(instruction)), // ADC A,n
@@ -217,74 +215,495 @@ fn emulate_group3(instruction: &[u8], s: &mut State) {
         0xd0 => s.ret_if(instruction),  // RET NC (implicitly)
         0xd1 => s.pop_r16(Name16::DE),  // POP DE
         0xd2 => s.jump_if(instruction), // JP NC,nn (implicitly)
-        0xd3 => panic!("0xd3 is not valid instruction"), // No instruction
         0xd4 => s.call_if(instruction), // CALL NC,nn (implicitly)
         0xd5 => s.push_r16(Name16::DE), // PUSH DE
         0xd6 => s.sub_ri8(byte_arg_from(instruction)), // SUB A,n
         0xd7 => s.call_a(0x0010),       // RST 10
         0xd8 => s.ret_if(instruction),  // RET C (implicitly)
         0xd9 => {
+            // RETI
             s.ret();
-            s.set_interrupt_flag(true)
-        } // RETI
+            s.set_interrupt_flag(true);
+        }
         0xda => s.jump_if(instruction), // JP C,nn (implicitly)
-        0xdb => panic!("0xdb is not valid instruction"), // No instruction
         0xdc => s.call_if(instruction), // CALL C,nn (implicitly)
-        0xdd => panic!("0xdd is not valid instruction"), // No instruction
-        0xde => s.sbb_ri8(byte_arg_from(instruction)), // SBC A,n
+        0xde => s.sbc_ri8(byte_arg_from(instruction)), // SBC A,n
         0xdf => s.call_a(0x0018),       // RST 18
 
         0xe0 => s.mov_ar8(0xff00 + byte_arg_from(instruction) as u16, Name8::A), // LDH (n),A
         0xe1 => s.pop_r16(Name16::HL),                                           // POP HL
-        0xe2 => unimplemented_instruction(s, opcode),                            // LDH (C),A
-        0xe3 => panic!("0xe3 is not valid instruction"),                         // No instruction
-        0xe4 => panic!("0xe4 is not valid instruction"),                         // No instruction
+        0xe2 => s.mov_ar8(0xff00 + s.r.c as u16, Name8::A),                      // LDH (C),A
         0xe5 => s.push_r16(Name16::HL),                                          // PUSH HL
-        0xe6 => s.logical_operation_ri(byte_arg_from(instruction), and8),        // AND n
+        0xe6 => s.and_ri8(byte_arg_from(instruction)),                           // AND n
         0xe7 => s.call_a(0x0020),                                                // RST 20
-        0xe8 => unimplemented_instruction(s, opcode),                            // ADD SP,d
-        0xe9 => unimplemented_instruction(s, opcode),                            // JP (HL)
-        0xea => s.mov_ar8(word_arg_from(instruction), Name8::A),                 // LD (nn),A
-        0xeb => panic!("0xeb is not valid instruction"),                         // No instruction
-        0xec => panic!("0xec is not valid instruction"),                         // No instruction
-        0xed => panic!("0xed is not valid instruction"),                         // No instruction
-        0xee => s.logical_operation_ri(byte_arg_from(instruction), xor8),        // XOR n
-        0xef => s.call_a(0x0028),                                                // RST 28
-
-        0xf0 => unimplemented_instruction(s, opcode), // LDH A,(n)
-        0xf1 => s.pop_r16(Name16::AF),                // POP AF
-        0xf2 => panic!("0xf2 is not valid instruction"), // No instruction
-        0xf3 => s.set_interrupt_flag(false),          // DI
-        0xf4 => panic!("0xf4 is not valid instruction"), // No instruction
-        0xf5 => s.push_r16(Name16::AF),               // PUSH AF
-        0xf6 => s.logical_operation_ri(byte_arg_from(instruction), or8), // OR n
-        0xf7 => s.call_a(0x0030),                     // RST 30
-        0xf8 => unimplemented_instruction(s, opcode), // LDHL SP,d
-        0xf9 => s.s.set_sp(s.r.get16(Name16::HL)),    // LD SP,HL
+        0xe8 => {
+            // ADD SP,d
+            let sp = s.sp_offset(byte_arg_from(instruction));
+            s.s.set_sp(sp);
+        }
+        0xe9 => s.jump_a(s.r.get16(Name16::HL)), // JP (HL)
+        0xea => s.mov_ar8(word_arg_from(instruction), Name8::A), // LD (nn),A
+        0xee => s.xor_ri8(byte_arg_from(instruction)), // XOR n
+        0xef => s.call_a(0x0028),                // RST 28
+
+        0xf0 => s.mov_ra8(Name8::A, 0xff00 + byte_arg_from(instruction) as u16), // LDH A,(n)
+        0xf1 => s.pop_r16(Name16::AF),                                           // POP AF
+        0xf2 => s.mov_ra8(Name8::A, 0xff00 + s.r.c as u16),                      // LDH A,(C)
+        0xf3 => s.set_interrupt_flag(false),                                     // DI
+        0xf5 => s.push_r16(Name16::AF),                                          // PUSH AF
+        0xf6 => s.or_ri8(byte_arg_from(instruction)),                            // OR n
+        0xf7 => s.call_a(0x0030),                                                // RST 30
+        0xf8 => {
+            // LDHL SP,d
+            let hl = s.sp_offset(byte_arg_from(instruction));
+            s.r.set16(Name16::HL, hl);
+        }
+        0xf9 => s.s.set_sp(s.r.get16(Name16::HL)), // LD SP,HL
         0xfa => s.mov_ra8(Name8::A, word_arg_from(instruction)), // LD A,(nn)
-        0xfb => s.set_interrupt_flag(true),           // EI
-        0xfc => panic!("0xfc is not valid instruction"), // No instruction
-        0xfd => panic!("0xfd is not valid instruction"), // No instruction
+        0xfb => s.enable_interrupts_delayed(),     // EI
         0xfe => s.cmp_ri8(byte_arg_from(instruction)), // CP n
-        0xff => s.call_a(0x0030),                     // RST 30
+        0xff => s.call_a(0x0038),                  // RST 38
+
+        0xd3 | 0xdb | 0xdd | 0xe3 | 0xe4 | 0xeb | 0xec | 0xed | 0xf4 | 0xfc | 0xfd => s.lock(),
+        _ => panic!("Shouldn't happen"),
+    }
+}
+
+fn emulate_cb(s: &mut State, opcode: u8) {
+    let bit = (opcode >> 3) & 0x07;
+    let value = get_operand(s, opcode);
 
+    match opcode >> 6 {
+        0x0 => {
+            let result = s.rotate_shift(opcode, value);
+            set_operand(s, opcode, result);
+        }
+        0x1 => s.bit(bit, value),                           // BIT n,r
+        0x2 => set_operand(s, opcode, value & !(1 << bit)), // RES n,r
+        0x3 => set_operand(s, opcode, value | (1 << bit)),  // SET n,r
         _ => panic!("Shouldn't happen"),
     }
 }
 
+// Memory accesses happen on their own M-cycles as the instruction runs, with
+// the rest of the system ticked in between. The timing tables give the
+// total, and the internal cycles left after the last access make up the
+// difference.
 pub fn emulate_instruction(s: &mut State) -> usize {
+    // A locked CPU does nothing, but the rest of the system keeps running
+    if s.locked {
+        s.start_instruction();
+        return s.finish_instruction(4);
+    }
+    if let Some(cycles) = s.service_interrupts() {
+        return cycles;
+    }
+    s.start_instruction();
+    if s.halted {
+        return s.finish_instruction(4);
+    }
+    // The clock stops altogether until a selected button line goes low
+    if s.stopped {
+        if (s.m.joypad.read() & 0x0f) == 0x0f {
+            return 4;
+        }
+        s.stopped = false;
+    }
+    if s.int_enable_pending {
+        s.set_interrupt_flag(true);
+    }
+
     let instruction = s.get_instruction();
     let opcode = instruction[0];
 
     match opcode {
         0x00..=0x3f => emulate_group0(&instruction, s),
         0x40..=0x7f => mov_for(s, opcode),
-        0x80..=0xbf => operate8(s, opcode, get_operand(s, opcode)),
+        0x80..=0xbf => {
+            let operand = get_operand(s, opcode);
+            operate8(s, opcode, operand)
+        }
         0xc0..=0xff => emulate_group3(&instruction, s),
+    }
 
-        _ => unimplemented_instruction(s, opcode),
+    let mut cycles = match opcode {
+        0xcb => cb_timing(instruction[1]),
+        _ => OPCODE_TIMING[opcode as usize],
+    };
+    if s.p.jumped() {
+        cycles += branch_timing(opcode);
     }
 
     s.p.advance();
-    OPCODE_TIMING[opcode as usize]
+    s.finish_instruction(cycles)
+}
+
+// A gbz80 with its memory bus and everything attached to it
+#[derive(Debug)]
+pub struct CpuGbz80 {
+    pub state: State,
+    cycles: u64,
+}
+
+impl CpuGbz80 {
+    pub fn new(state: State) -> CpuGbz80 {
+        CpuGbz80 { state, cycles: 0 }
+    }
+}
+
+impl Cpu for CpuGbz80 {
+    type Address = u16;
+    type State = State;
+    type Bus = MemoryGbz80;
+    type Register = Name16;
+
+    fn state(&self) -> &State {
+        &self.state
+    }
+
+    fn state_mut(&mut self) -> &mut State {
+        &mut self.state
+    }
+
+    fn bus(&self) -> &MemoryGbz80 {
+        &self.state.m
+    }
+
+    fn bus_mut(&mut self) -> &mut MemoryGbz80 {
+        &mut self.state.m
+    }
+
+    fn step(&mut self) -> usize {
+        let cycles = emulate_instruction(&mut self.state);
+        self.cycles += cycles as u64;
+        cycles
+    }
+
+    // The registers go back to where the boot ROM leaves them
+    fn reset(&mut self) {
+        self.state.reset_post_boot();
+        self.cycles = 0;
+    }
+
+    fn pc(&self) -> u16 {
+        self.state.p.get_pc()
+    }
+
+    fn set_pc(&mut self, addr: u16) {
+        self.state.p.jump(addr);
+    }
+
+    fn register(&self, reg: Name16) -> u16 {
+        self.state.r.get16(reg)
+    }
+
+    fn set_register(&mut self, reg: Name16, val: u16) {
+        self.state.r.set16(reg, val);
+    }
+
+    fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    fn interrupts_enabled(&self) -> bool {
+        self.state.int_enable
+    }
+
+    // Requests interrupt n in IF, to be dispatched by a later step
+    fn interrupt(&mut self, n: u8) {
+        self.state.m.interrupts.request(1 << (n & 0x07));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::cgb::Model;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use virtual_cpu_core::{MappedMemory, Memory, Region};
+
+    fn run(program: &[u8], steps: usize) -> State {
+        let mut s = State::new();
+        s.m.load(0x0100, program);
+        s.p.jump(0x0100);
+        for _ in 0..steps {
+            emulate_instruction(&mut s);
+        }
+        s
+    }
+
+    #[test]
+    fn post_boot_state() {
+        let s = State::new();
+        assert_eq!(s.r.get16(Name16::AF), 0x01b0);
+        assert_eq!(s.r.get16(Name16::HL), 0x014d);
+        assert_eq!(s.s.get_sp(), 0xfffe);
+        assert_eq!(s.p.get_pc(), 0x0100);
+        assert_eq!(s.m.get_byte(0xff40), 0x91);
+    }
+
+    #[test]
+    fn boot_rom_unmaps() {
+        let mut cartridge = Cartridge::default();
+        cartridge.rom_mut()[0] = 0xaa;
+        let boot_rom = vec![0x3e, 0x01, 0xe0, 0x50];
+        let mut s = State::with_boot_rom(cartridge, boot_rom);
+
+        assert_eq!(s.p.get_pc(), 0x0000);
+        assert_eq!(s.m.get_byte(0x0000), 0x3e);
+        emulate_instruction(&mut s);
+        emulate_instruction(&mut s);
+        assert_eq!(s.p.get_pc(), 0x0004);
+        assert_eq!(s.m.get_byte(0x0000), 0xaa);
+    }
+
+    #[test]
+    fn daa_after_add() {
+        // LD A,0x15; ADD A,0x27; DAA
+        let s = run(&[0x3e, 0x15, 0xc6, 0x27, 0x27], 3);
+        assert_eq!(s.r.a, 0x42);
+        assert!(!s.r.cc.cy);
+    }
+
+    #[test]
+    fn relative_jump_and_bit() {
+        // LD H,0x80; BIT 7,H; JR NZ,+1 skips the NOP at 0x0106
+        let s = run(&[0x26, 0x80, 0xcb, 0x7c, 0x20, 0x01, 0x00, 0x00], 3);
+        assert!(!s.r.cc.z);
+        assert!(s.r.cc.h);
+        assert_eq!(s.p.get_pc(), 0x0107);
+    }
+
+    #[test]
+    fn branch_timing_depends_on_condition() {
+        let mut s = State::new();
+        // XOR A; JR Z,0; JR NZ,0
+        s.m.load(0x0100, &[0xaf, 0x28, 0x00, 0x20, 0x00]);
+        s.p.jump(0x0100);
+        emulate_instruction(&mut s);
+        assert_eq!(emulate_instruction(&mut s), 12);
+        assert_eq!(emulate_instruction(&mut s), 8);
+    }
+
+    #[test]
+    fn interrupt_dispatch_after_ei_delay() {
+        // EI; NOP; NOP
+        let mut s = run(&[0xfb, 0x00, 0x00], 0);
+        s.m.set_byte(0xffff, 0x05);
+        s.m.set_byte(0xff0f, 0x04 | 0x01);
+
+        emulate_instruction(&mut s);
+        emulate_instruction(&mut s);
+        assert_eq!(s.p.get_pc(), 0x0102);
+        assert_eq!(emulate_instruction(&mut s), 20);
+        assert_eq!(s.p.get_pc(), 0x0040);
+        assert_eq!(s.m.get_byte(0xff0f), 0xe0 | 0x04);
+        assert_eq!(s.pop_word(), 0x0102);
+        assert!(!s.int_enable);
+    }
+
+    #[test]
+    fn accesses_land_on_their_own_m_cycle() {
+        // LD HL,0xff24; LD (HL),A; LDH (0x12),A
+        let mut s = run(&[0x21, 0x24, 0xff, 0x77, 0xe0, 0x12], 0);
+        s.m.apu.start_recording();
+        for _ in 0..3 {
+            emulate_instruction(&mut s);
+        }
+        let recording = s.m.apu.stop_recording().unwrap();
+        let writes: Vec<(u64, u16)> = recording
+            .writes
+            .iter()
+            .skip_while(|w| w.cycle == recording.start)
+            .map(|w| (w.cycle - recording.start, w.addr))
+            .collect();
+        assert_eq!(writes, vec![(20, 0xff24), (32, 0xff12)]);
+    }
+
+    #[test]
+    fn interrupt_cancelled_by_push_to_ie() {
+        let mut s = State::new();
+        s.p.jump(0x0050);
+        s.s.set_sp(0x0000);
+        s.int_enable = true;
+        s.m.set_byte(0xffff, 0x01);
+        s.m.set_byte(0xff0f, 0x01);
+
+        // Pushing PC's high byte clears IE before the vector is chosen
+        assert_eq!(emulate_instruction(&mut s), 20);
+        assert_eq!(s.p.get_pc(), 0x0000);
+        assert_eq!(s.m.get_byte(0xff0f), 0xe1);
+        assert_eq!(s.m.get_byte(0xfffe), 0x50);
+    }
+
+    #[test]
+    fn halt_wakes_without_ime() {
+        // HALT; INC A
+        let mut s = run(&[0x76, 0x3c], 1);
+        s.m.set_byte(0xff0f, 0x00);
+        assert!(s.halted);
+        assert_eq!(emulate_instruction(&mut s), 4);
+
+        s.m.set_byte(0xffff, 0x10);
+        s.m.set_byte(0xff0f, 0x10);
+        emulate_instruction(&mut s);
+        assert!(!s.halted);
+        assert_eq!(s.r.a, 0x02);
+    }
+
+    #[test]
+    fn invalid_opcode_locks_up() {
+        // EI; NOP; 0xd3
+        let mut s = run(&[0xfb, 0x00, 0xd3, 0x3c], 3);
+        assert!(s.locked);
+        let pc = s.p.get_pc();
+        let div = s.m.get_byte(0xff04);
+
+        // Interrupts don't wake it, but the timer keeps counting
+        s.m.set_byte(0xffff, 0x1f);
+        s.m.set_byte(0xff0f, 0x1f);
+        for _ in 0..100 {
+            assert_eq!(emulate_instruction(&mut s), 4);
+        }
+        assert_eq!(s.p.get_pc(), pc);
+        assert_eq!(s.r.a, 0x01);
+        assert_ne!(s.m.get_byte(0xff04), div);
+
+        s.reset_post_boot();
+        assert!(!s.locked);
+    }
+
+    #[test]
+    fn halt_bug_repeats_byte() {
+        let mut s = State::new();
+        // HALT; INC A with an interrupt pending and IME off
+        s.m.load(0x0100, &[0x76, 0x3c]);
+        s.m.set_byte(0xffff, 0x01);
+        emulate_instruction(&mut s);
+        emulate_instruction(&mut s);
+        emulate_instruction(&mut s);
+        assert_eq!(s.r.a, 0x03);
+        assert_eq!(s.p.get_pc(), 0x0102);
+    }
+
+    // Written once for any Cpu, the way frontends use it
+    fn run_until<C: Cpu<Address = u16>>(cpu: &mut C, addr: u16) -> u64 {
+        while cpu.pc() != addr {
+            cpu.step();
+        }
+        cpu.cycles()
+    }
+
+    #[test]
+    fn generic_cpu_interface() {
+        // EI; NOP; NOP
+        let mut cpu = CpuGbz80::new(run(&[0xfb, 0x00, 0x00], 0));
+        assert_eq!(run_until(&mut cpu, 0x0102), 8);
+        cpu.state.m.set_byte(0xffff, 0x04);
+        cpu.interrupt(2);
+        assert!(cpu.interrupts_enabled());
+        cpu.step();
+        assert_eq!(cpu.pc(), 0x0050);
+        assert_eq!(cpu.cycles(), 28);
+
+        cpu.reset();
+        assert_eq!(cpu.pc(), 0x0100);
+        assert_eq!(cpu.register(Name16::AF), 0x01b0);
+        assert_eq!(cpu.cycles(), 0);
+    }
+
+    #[test]
+    fn stop_reads_its_padding_byte() {
+        let mut s = State::new();
+        s.m.load(0x0100, &[0x10, 0x00, 0x3c]);
+        s.p.jump(0x0100);
+        assert_eq!(emulate_instruction(&mut s), 8);
+        assert!(s.stopped);
+        assert_eq!(s.p.get_pc(), 0x0102);
+    }
+
+    #[test]
+    fn stop_switches_speed_on_cgb() {
+        let mut s = State::with_model(Cartridge::default(), Model::Cgb);
+        assert_eq!(s.r.a, 0x11);
+        s.m.load(0x0100, &[0x10, 0x00, 0x3c]);
+        s.m.set_byte(0xff4d, 0x01);
+        s.m.set_byte(0xff70, 0x03);
+        s.m.set_byte(0xd000, 0x33);
+        s.m.set_byte(0xff70, 0x00);
+        assert_eq!(s.m.get_byte(0xd000), 0x00);
+
+        emulate_instruction(&mut s);
+        assert!(s.m.double_speed());
+        assert!(!s.stopped);
+        assert_eq!(s.m.get_byte(0xff4d), 0xfe);
+        s.m.set_byte(0xff70, 0x03);
+        assert_eq!(s.m.get_byte(0xd000), 0x33);
+    }
+
+    #[test]
+    fn alu_flags() {
+        // LD A,0x0f; ADD A,0x01; SUB 0x10
+        let program = [0x3e, 0x0f, 0xc6, 0x01, 0xd6, 0x10];
+        let s = run(&program, 2);
+        assert_eq!(s.r.a, 0x10);
+        assert!(s.r.cc.h && !s.r.cc.n && !s.r.cc.cy && !s.r.cc.z);
+        let s = run(&program, 3);
+        assert_eq!(s.r.a, 0x00);
+        assert!(s.r.cc.z && s.r.cc.n && !s.r.cc.h && !s.r.cc.cy);
+    }
+
+    #[test]
+    fn cb_shifts_and_swap() {
+        // LD A,0xf1; SWAP A; SRA A
+        let s = run(&[0x3e, 0xf1, 0xcb, 0x37, 0xcb, 0x2f], 2);
+        assert_eq!(s.r.a, 0x1f);
+        let s = run(&[0x3e, 0xf1, 0xcb, 0x37, 0xcb, 0x2f], 3);
+        assert_eq!(s.r.a, 0x0f);
+        assert!(s.r.cc.cy);
+    }
+
+    #[test]
+    fn pop_af_drops_low_flag_bits() {
+        // LD SP,0xd000; LD BC,0x12ff; PUSH BC; POP AF
+        let s = run(&[0x31, 0x00, 0xd0, 0x01, 0xff, 0x12, 0xc5, 0xf1], 4);
+        assert_eq!(s.r.get16(Name16::AF), 0x12f0);
+        assert_eq!(s.s.get_sp(), 0xd000);
+    }
+
+    #[test]
+    fn stack_pointer_arithmetic() {
+        // LD SP,0xfff8; LD (0xc000),SP; LD HL,SP+8
+        let s = run(&[0x31, 0xf8, 0xff, 0x08, 0x00, 0xc0, 0xf8, 0x08], 3);
+        assert_eq!(s.m.get_word(0xc000), 0xfff8);
+        assert_eq!(s.r.get16(Name16::HL), 0x0000);
+        assert!(s.r.cc.h && s.r.cc.cy && !s.r.cc.z);
+    }
+
+    #[test]
+    fn runs_from_mapped_slot() {
+        let mut slot = MappedMemory::new();
+        // 16K of ROM repeated over both banks, with 2K of RAM mirrored at
+        // 0xb000
+        slot.map(0x0000, 0x7fff, Region::Rom(vec![0; 0x4000]));
+        slot.map_ram(0xa000, 0xa7ff);
+        slot.map_mirror(0xb000, 0xb7ff, 0xa000);
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        let trapped = writes.clone();
+        slot.trap_rom_writes(move |addr, val| trapped.borrow_mut().push((addr, val)));
+
+        let mut s = State::with_slot(slot, Model::Dmg);
+        // LD A,0x42; LD (0x2000),A; LD (0xb001),A; LD A,(0x4100)
+        s.m.load(0x0100, &[0x3e, 0x42, 0xea, 0x00, 0x20, 0xea, 0x01, 0xb0]);
+        s.m.load(0x0108, &[0xfa, 0x00, 0x41]);
+        for _ in 0..4 {
+            emulate_instruction(&mut s);
+        }
+        assert_eq!(s.r.a, 0x3e);
+        assert_eq!(*writes.borrow(), vec![(0x2000, 0x42)]);
+        assert_eq!(s.m.get_byte(0xa001), 0x42);
+        assert_eq!(s.m.view(0x4100, 0x4101), &[0x3e, 0x42]);
+    }
 }
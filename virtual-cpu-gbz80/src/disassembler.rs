@@ -0,0 +1,533 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use virtual_cpu_core::Memory;
+
+use crate::mbc::ROM_BANK_SIZE;
+
+const R8: [&str; 8] = ["b", "c", "d", "e", "h", "l", "[hl]", "a"];
+const R16: [&str; 4] = ["bc", "de", "hl", "sp"];
+const R16_STACK: [&str; 4] = ["bc", "de", "hl", "af"];
+const R16_MEMORY: [&str; 4] = ["[bc]", "[de]", "[hl+]", "[hl-]"];
+const CONDITIONS: [&str; 4] = ["nz", "z", "nc", "c"];
+const ALU: [&str; 8] = ["add", "adc", "sub", "sbc", "and", "xor", "or", "cp"];
+const ACCUMULATOR_OPS: [&str; 8] = ["rlca", "rrca", "rla", "rra", "daa", "cpl", "scf", "ccf"];
+const ROTATES: [&str; 8] = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+const BIT_OPS: [&str; 3] = ["bit", "res", "set"];
+
+fn hex8(val: u8) -> String {
+    format!("${:02x}", val)
+}
+
+fn hex16(val: u16) -> String {
+    format!("${:04x}", val)
+}
+
+fn signed(val: u8) -> String {
+    let offset = val as i8;
+    if offset < 0 {
+        format!("-{}", -i16::from(offset))
+    } else {
+        format!("+{}", offset)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    Relative(u16),
+    Jump(u16),
+    Call(u16),
+}
+
+impl Target {
+    pub fn addr(self) -> u16 {
+        match self {
+            Target::Relative(addr) | Target::Jump(addr) | Target::Call(addr) => addr,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    // Everything but the branch target, e.g. "jr nz, "
+    pub mnemonic: String,
+    pub target: Option<Target>,
+}
+
+impl Instruction {
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    // Formats the instruction with the branch target named by `name`, or as
+    // a plain address when it returns None
+    pub fn render(&self, name: impl Fn(u16) -> Option<String>) -> String {
+        match self.target {
+            Some(target) => {
+                let addr = target.addr();
+                let target = name(addr).unwrap_or_else(|| hex16(addr));
+                format!("{}{}", self.mnemonic, target)
+            }
+            None => self.mnemonic.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.render(|_| None))
+    }
+}
+
+// Decodes the instruction at addr in RGBDS syntax
+pub fn decode<M: Memory<Address = u16>>(m: &M, addr: u16) -> Instruction {
+    let byte = |i: u16| m.get_byte(addr.wrapping_add(i));
+    let opcode = byte(0);
+    let n8 = byte(1);
+    let n16 = u16::from(byte(1)) | u16::from(byte(2)) << 8;
+    let relative = addr.wrapping_add(2).wrapping_add(n8 as i8 as u16);
+
+    let y = ((opcode >> 3) & 0x07) as usize;
+    let z = (opcode & 0x07) as usize;
+    let (p, q) = (y >> 1, y & 0x01);
+
+    let (length, mnemonic, target): (u16, String, Option<Target>) = match opcode {
+        0x00 => (1, "nop".into(), None),
+        0x08 => (3, format!("ld [{}], sp", hex16(n16)), None),
+        0x10 => (2, "stop".into(), None),
+        0x18 => (2, "jr ".into(), Some(Target::Relative(relative))),
+        0x20 | 0x28 | 0x30 | 0x38 => (
+            2,
+            format!("jr {}, ", CONDITIONS[y - 4]),
+            Some(Target::Relative(relative)),
+        ),
+        0x01..=0x3f => match z {
+            1 if q == 0 => (3, format!("ld {}, {}", R16[p], hex16(n16)), None),
+            1 => (1, format!("add hl, {}", R16[p]), None),
+            2 if q == 0 => (1, format!("ld {}, a", R16_MEMORY[p]), None),
+            2 => (1, format!("ld a, {}", R16_MEMORY[p]), None),
+            3 if q == 0 => (1, format!("inc {}", R16[p]), None),
+            3 => (1, format!("dec {}", R16[p]), None),
+            4 => (1, format!("inc {}", R8[y]), None),
+            5 => (1, format!("dec {}", R8[y]), None),
+            6 => (2, format!("ld {}, {}", R8[y], hex8(n8)), None),
+            7 => (1, ACCUMULATOR_OPS[y].into(), None),
+            _ => panic!("shouldn't happen"),
+        },
+        0x76 => (1, "halt".into(), None),
+        0x40..=0x7f => (1, format!("ld {}, {}", R8[y], R8[z]), None),
+        0x80..=0xbf => (1, format!("{} a, {}", ALU[y], R8[z]), None),
+        0xc0 | 0xc8 | 0xd0 | 0xd8 => (1, format!("ret {}", CONDITIONS[y]), None),
+        0xc9 => (1, "ret".into(), None),
+        0xd9 => (1, "reti".into(), None),
+        0xc2 | 0xca | 0xd2 | 0xda => (
+            3,
+            format!("jp {}, ", CONDITIONS[y]),
+            Some(Target::Jump(n16)),
+        ),
+        0xc3 => (3, "jp ".into(), Some(Target::Jump(n16))),
+        0xe9 => (1, "jp hl".into(), None),
+        0xc4 | 0xcc | 0xd4 | 0xdc => (
+            3,
+            format!("call {}, ", CONDITIONS[y]),
+            Some(Target::Call(n16)),
+        ),
+        0xcd => (3, "call ".into(), Some(Target::Call(n16))),
+        0xc1 | 0xd1 | 0xe1 | 0xf1 => (1, format!("pop {}", R16_STACK[p]), None),
+        0xc5 | 0xd5 | 0xe5 | 0xf5 => (1, format!("push {}", R16_STACK[p]), None),
+        0xc6 | 0xce | 0xd6 | 0xde | 0xe6 | 0xee | 0xf6 | 0xfe => {
+            (2, format!("{} a, {}", ALU[y], hex8(n8)), None)
+        }
+        0xc7 | 0xcf | 0xd7 | 0xdf | 0xe7 | 0xef | 0xf7 | 0xff => {
+            (1, format!("rst {}", hex8(opcode & 0x38)), None)
+        }
+        0xcb => (2, decode_cb(n8), None),
+        0xe0 => (
+            2,
+            format!("ldh [{}], a", hex16(0xff00 | u16::from(n8))),
+            None,
+        ),
+        0xf0 => (
+            2,
+            format!("ldh a, [{}]", hex16(0xff00 | u16::from(n8))),
+            None,
+        ),
+        0xe2 => (1, "ldh [c], a".into(), None),
+        0xf2 => (1, "ldh a, [c]".into(), None),
+        0xea => (3, format!("ld [{}], a", hex16(n16)), None),
+        0xfa => (3, format!("ld a, [{}]", hex16(n16)), None),
+        0xe8 => (2, format!("add sp, {}", n8 as i8), None),
+        0xf8 => (2, format!("ld hl, sp{}", signed(n8)), None),
+        0xf9 => (1, "ld sp, hl".into(), None),
+        0xf3 => (1, "di".into(), None),
+        0xfb => (1, "ei".into(), None),
+        _ => (1, format!("db {}", hex8(opcode)), None),
+    };
+
+    Instruction {
+        addr,
+        bytes: (0..length).map(byte).collect(),
+        mnemonic,
+        target,
+    }
+}
+
+fn decode_cb(opcode: u8) -> String {
+    let operation = (opcode >> 6) as usize;
+    let y = ((opcode >> 3) & 0x07) as usize;
+    let register = R8[(opcode & 0x07) as usize];
+    match operation {
+        0 => format!("{} {}", ROTATES[y], register),
+        _ => format!("{} {}, {}", BIT_OPS[operation - 1], y, register),
+    }
+}
+
+// Which bank an address belongs to when `bank` is mapped at 0x4000-0x7fff.
+// Everything outside the switchable ROM area is looked up as bank 0.
+fn bank_of(addr: u16, bank: u16) -> u16 {
+    if (0x4000..0x8000).contains(&addr) {
+        bank
+    } else {
+        0
+    }
+}
+
+// Names keyed by bank and address, as in RGBDS .sym files
+#[derive(Debug, Default, Clone)]
+pub struct SymbolTable {
+    symbols: BTreeMap<(u16, u16), String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable::default()
+    }
+
+    // Reads "BB:AAAA Name" lines, ignoring comments after ';'
+    pub fn parse_sym(text: &str) -> SymbolTable {
+        let mut table = SymbolTable::new();
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            let mut parts = line.split_whitespace();
+            let (location, name) = match (parts.next(), parts.next()) {
+                (Some(location), Some(name)) => (location, name),
+                _ => continue,
+            };
+            let mut location = location.split(':');
+            let bank = location
+                .next()
+                .and_then(|b| u16::from_str_radix(b, 16).ok());
+            let addr = location
+                .next()
+                .and_then(|a| u16::from_str_radix(a, 16).ok());
+            if let (Some(bank), Some(addr)) = (bank, addr) {
+                table.insert(bank, addr, name);
+            }
+        }
+        table
+    }
+
+    pub fn insert(&mut self, bank: u16, addr: u16, name: impl Into<String>) {
+        self.symbols.insert((bank, addr), name.into());
+    }
+
+    pub fn lookup(&self, bank: u16, addr: u16) -> Option<&str> {
+        self.symbols.get(&(bank, addr)).map(String::as_str)
+    }
+
+    // Looks addr up as the CPU sees it with `bank` mapped in
+    pub fn resolve(&self, addr: u16, bank: u16) -> Option<&str> {
+        self.lookup(bank_of(addr, bank), addr)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Label {
+    name: String,
+    parent: Option<String>,
+}
+
+impl Label {
+    fn local(&self) -> Option<&str> {
+        let parent = self.parent.as_ref()?;
+        self.name
+            .strip_prefix(parent.as_str())
+            .filter(|rest| rest.starts_with('.'))
+    }
+
+    // How code in the `scope` global label refers to this one
+    fn reference(&self, scope: Option<&str>) -> String {
+        match self.local() {
+            Some(local) if self.parent.as_deref() == scope => local.to_string(),
+            _ => self.name.clone(),
+        }
+    }
+}
+
+// Lists start..=end with `bank` mapped at 0x4000-0x7fff. Branch targets
+// inside the range get labels: symbols where known, otherwise Call_/Jump_
+// globals and local labels for relative jumps.
+pub fn disassemble<M: Memory<Address = u16>>(
+    m: &M,
+    start: u16,
+    end: u16,
+    bank: u16,
+    symbols: &SymbolTable,
+) -> String {
+    let mut instructions = Vec::new();
+    let mut addr = u32::from(start);
+    while addr <= u32::from(end) {
+        let mut instruction = decode(m, addr as u16);
+        if addr + instruction.len() as u32 > u32::from(end) + 1 {
+            let opcode = instruction.bytes[0];
+            instruction = Instruction {
+                addr: addr as u16,
+                bytes: vec![opcode],
+                mnemonic: format!("db {}", hex8(opcode)),
+                target: None,
+            };
+        }
+        addr += instruction.len() as u32;
+        instructions.push(instruction);
+    }
+
+    let labels = collect_labels(&instructions, bank, symbols);
+
+    let mut out = String::new();
+    let mut scope: Option<String> = None;
+    for instruction in instructions.iter() {
+        if let Some(label) = labels.get(&instruction.addr) {
+            match label.local() {
+                Some(local) => out.push_str(&format!("{}:\n", local)),
+                None => {
+                    out.push_str(&format!("\n{}:\n", label.name));
+                    scope = Some(label.name.clone());
+                }
+            }
+        }
+        let text = instruction.render(|target| match labels.get(&target) {
+            Some(label) => Some(label.reference(scope.as_deref())),
+            None => symbols.resolve(target, bank).map(String::from),
+        });
+        let bytes: Vec<String> = instruction
+            .bytes
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        out.push_str(&format!(
+            "    {:<24}; ${:04x}: {}\n",
+            text,
+            instruction.addr,
+            bytes.join(" ")
+        ));
+    }
+    out
+}
+
+fn collect_labels(
+    instructions: &[Instruction],
+    bank: u16,
+    symbols: &SymbolTable,
+) -> BTreeMap<u16, Label> {
+    let starts: BTreeSet<u16> = instructions.iter().map(|i| i.addr).collect();
+
+    // Global names first, then relative targets that don't have one
+    let mut names: BTreeMap<u16, (String, bool)> = BTreeMap::new();
+    for &addr in starts.iter() {
+        if let Some(name) = symbols.resolve(addr, bank) {
+            names.insert(addr, (name.to_string(), !name.contains('.')));
+        }
+    }
+    for target in instructions.iter().filter_map(|i| i.target) {
+        let addr = target.addr();
+        if !starts.contains(&addr) || names.contains_key(&addr) {
+            continue;
+        }
+        let name = match target {
+            Target::Call(_) => format!("Call_{:02x}_{:04x}", bank_of(addr, bank), addr),
+            Target::Jump(_) => format!("Jump_{:02x}_{:04x}", bank_of(addr, bank), addr),
+            Target::Relative(_) => continue,
+        };
+        names.insert(addr, (name, true));
+    }
+    for target in instructions.iter().filter_map(|i| i.target) {
+        let addr = target.addr();
+        if starts.contains(&addr) && !names.contains_key(&addr) {
+            names.insert(addr, (format!(".jr_{:04x}", addr), false));
+        }
+    }
+
+    // Locals hang off the closest global before them, or become globals
+    // themselves when there is none
+    let mut labels = BTreeMap::new();
+    let mut parent: Option<String> = None;
+    for (addr, (name, global)) in names {
+        let label = if global {
+            parent = Some(name.clone());
+            Label { name, parent: None }
+        } else if let Some(parent) = parent.as_ref() {
+            let local = name.rsplit('.').next().unwrap_or(&name);
+            Label {
+                name: format!("{}.{}", parent, local),
+                parent: Some(parent.clone()),
+            }
+        } else {
+            let global = format!("Jump_{:02x}_{:04x}", bank_of(addr, bank), addr);
+            parent = Some(global.clone());
+            Label {
+                name: global,
+                parent: None,
+            }
+        };
+        labels.insert(addr, label);
+    }
+    labels
+}
+
+// A single ROM bank as the CPU sees it, with bank 0 at 0x0000 and `bank` at
+// 0x4000. Writes are ignored.
+pub struct RomBank<'a> {
+    rom: &'a [u8],
+    bank: usize,
+}
+
+impl<'a> RomBank<'a> {
+    pub fn new(rom: &'a [u8], bank: usize) -> RomBank<'a> {
+        RomBank { rom, bank }
+    }
+
+    fn index(&self, addr: u16) -> usize {
+        match addr {
+            0x0000..=0x3fff => addr as usize,
+            _ => self.bank * ROM_BANK_SIZE + (addr as usize & (ROM_BANK_SIZE - 1)),
+        }
+    }
+}
+
+impl<'a> Memory for RomBank<'a> {
+    type Address = u16;
+
+    fn get_byte(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x7fff => self.rom.get(self.index(addr)).copied().unwrap_or(0xff),
+            _ => 0xff,
+        }
+    }
+
+    fn set_byte(&mut self, _addr: u16, _value: u8) {}
+
+    fn load(&mut self, _addr: u16, _data: &[u8]) {}
+
+    fn view(&self, start: u16, end: u16) -> &[u8] {
+        &self.rom[self.index(start)..=self.index(end)]
+    }
+}
+
+// One listing per ROM bank, each starting with its RGBDS SECTION
+pub fn disassemble_rom(rom: &[u8], symbols: &SymbolTable) -> Vec<String> {
+    // An empty ROM has no banks and so no listing
+    let banks = rom.len().div_ceil(ROM_BANK_SIZE);
+    (0..banks)
+        .map(|bank| {
+            let memory = RomBank::new(rom, bank);
+            let end = ((rom.len() - bank * ROM_BANK_SIZE).min(ROM_BANK_SIZE) - 1) as u16;
+            let (section, start) = match bank {
+                0 => (
+                    String::from("SECTION \"ROM Bank $000\", ROM0[$0000]"),
+                    0x0000,
+                ),
+                _ => (
+                    format!(
+                        "SECTION \"ROM Bank ${:03x}\", ROMX[$4000], BANK[${:x}]",
+                        bank, bank
+                    ),
+                    0x4000,
+                ),
+            };
+            let listing = disassemble(&memory, start, start + end, bank as u16, symbols);
+            format!("{}\n{}", section, listing)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use virtual_cpu_core::MappedMemory;
+
+    fn decode_bytes(bytes: &[u8]) -> String {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0150..0x0150 + bytes.len()].copy_from_slice(bytes);
+        decode(&RomBank::new(&rom, 1), 0x0150).to_string()
+    }
+
+    #[test]
+    fn rgbds_syntax() {
+        assert_eq!(decode_bytes(&[0x22]), "ld [hl+], a");
+        assert_eq!(decode_bytes(&[0xe2]), "ldh [c], a");
+        assert_eq!(decode_bytes(&[0xf0, 0x44]), "ldh a, [$ff44]");
+        assert_eq!(decode_bytes(&[0xf8, 0xfe]), "ld hl, sp-2");
+        assert_eq!(decode_bytes(&[0xe8, 0x05]), "add sp, 5");
+        assert_eq!(decode_bytes(&[0x9e]), "sbc a, [hl]");
+        assert_eq!(decode_bytes(&[0xcb, 0x37]), "swap a");
+        assert_eq!(decode_bytes(&[0xcb, 0xfe]), "set 7, [hl]");
+        assert_eq!(decode_bytes(&[0x20, 0xfe]), "jr nz, $0150");
+        assert_eq!(decode_bytes(&[0xff]), "rst $38");
+        assert_eq!(decode_bytes(&[0xd3]), "db $d3");
+    }
+
+    #[test]
+    fn labels_jump_targets() {
+        let mut rom = vec![0; 0x8000];
+        // Main: call Sub; .loop: jr .loop; Sub: dec a; jr nz, Sub; ret
+        rom[0x0150..0x015a]
+            .copy_from_slice(&[0xcd, 0x55, 0x01, 0x18, 0xfe, 0x3d, 0x20, 0xfd, 0xc9, 0x00]);
+        let mut symbols = SymbolTable::parse_sym("; comment\n00:0150 Main\n");
+        symbols.insert(1, 0x4000, "Banked");
+        let listing = disassemble(&RomBank::new(&rom, 1), 0x0150, 0x0158, 1, &symbols);
+        assert!(listing.contains("\nMain:\n"));
+        assert!(listing.contains("    call Call_00_0155 "));
+        assert!(listing.contains(".jr_0153:\n    jr .jr_0153 "));
+        assert!(listing.contains("\nCall_00_0155:\n"));
+        assert!(listing.contains("    jr nz, Call_00_0155 "));
+        assert_eq!(symbols.resolve(0x4000, 1), Some("Banked"));
+        assert_eq!(symbols.resolve(0x4000, 2), None);
+    }
+
+    #[test]
+    fn per_bank_sections() {
+        let rom = vec![0; 3 * ROM_BANK_SIZE];
+        let listings = disassemble_rom(&rom, &SymbolTable::new());
+        assert_eq!(listings.len(), 3);
+        assert!(listings[2].starts_with("SECTION \"ROM Bank $002\", ROMX[$4000], BANK[$2]"));
+        assert!(listings[2].contains("; $7fff: 00"));
+    }
+
+    #[test]
+    fn empty_rom_has_no_sections() {
+        assert!(disassemble_rom(&[], &SymbolTable::new()).is_empty());
+        let listings = disassemble_rom(&[0x00], &SymbolTable::new());
+        assert_eq!(listings.len(), 1);
+        assert!(listings[0].contains("; $0000: 00"));
+    }
+
+    #[test]
+    fn decodes_mapped_memory() {
+        let mut m = MappedMemory::new();
+        // ld a, [$c000] in ROM, then nothing until WRAM and its echo
+        m.map_rom(0x0000, vec![0xfa, 0x00, 0xc0]);
+        m.map_ram(0xc000, 0xdfff);
+        m.map_mirror(0xe000, 0xfdff, 0xc000);
+        m.set_byte(0xe000, 0x76);
+        assert_eq!(decode(&m, 0x0000).to_string(), "ld a, [$c000]");
+        assert_eq!(decode(&m, 0xc000).to_string(), "halt");
+        // The open bus reads 0xff
+        assert_eq!(decode(&m, 0x8000).to_string(), "rst $38");
+    }
+}
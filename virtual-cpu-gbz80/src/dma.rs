@@ -0,0 +1,133 @@
+pub const OAM_SIZE: usize = 0xa0;
+
+// A write to 0xff46 takes one M-cycle to start the transfer, which then copies
+// one byte per M-cycle. Writing again while a transfer runs restarts it from
+// the new source once the start-up cycle has passed.
+#[derive(Debug)]
+pub struct Dma {
+    register: u8,
+    source: u16,
+    index: usize,
+    active: bool,
+    pending: Option<(u16, usize)>,
+}
+
+impl Dma {
+    pub fn new() -> Dma {
+        Dma {
+            register: 0xff,
+            source: 0,
+            index: 0,
+            active: false,
+            pending: None,
+        }
+    }
+
+    pub fn read(&self) -> u8 {
+        self.register
+    }
+
+    pub fn write(&mut self, val: u8) {
+        self.register = val;
+        // Sources from 0xe000 up see work RAM through the echo region
+        let source = match u16::from(val) << 8 {
+            addr @ 0xe000..=0xffff => addr - 0x2000,
+            addr => addr,
+        };
+        self.pending = Some((source, 1));
+    }
+
+    pub fn active(&self) -> bool {
+        self.active
+    }
+
+    // Advances one M-cycle, returning the source address and OAM offset of
+    // the byte to copy during it
+    pub fn step(&mut self) -> Option<(u16, usize)> {
+        if let Some((source, delay)) = self.pending {
+            if delay == 0 {
+                self.pending = None;
+                self.source = source;
+                self.index = 0;
+                self.active = true;
+            } else {
+                self.pending = Some((source, delay - 1));
+            }
+        }
+        if !self.active {
+            return None;
+        }
+        let transfer = (self.source + self.index as u16, self.index);
+        self.index += 1;
+        if self.index == OAM_SIZE {
+            self.active = false;
+        }
+        Some(transfer)
+    }
+}
+
+impl Default for Dma {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::MemoryGbz80;
+    use virtual_cpu_core::Memory;
+
+    #[test]
+    fn copies_from_echo_ram_and_blocks_cpu() {
+        let mut m = MemoryGbz80::new();
+        for i in 0..0xa0 {
+            m.set_byte(0xc100 + i, i as u8 + 1);
+        }
+        m.set_byte(0xff80, 0x42);
+        m.set_byte(0xff46, 0xe1);
+        assert_eq!(m.get_byte(0xff46), 0xe1);
+
+        m.tick(8);
+        assert!(m.dma.active());
+        assert_eq!(m.get_byte(0xc100), 0xff);
+        assert_eq!(m.get_byte(0xff80), 0x42);
+        m.set_byte(0xc000, 0x99);
+        m.set_byte(0xff80, 0x43);
+        assert_eq!(m.get_byte(0xff80), 0x43);
+
+        m.tick(159 * 4);
+        assert!(!m.dma.active());
+        assert_eq!(m.get_byte(0xc000), 0x00);
+        assert_eq!(m.get_byte(0xfe00), 0x01);
+        assert_eq!(m.get_byte(0xfe9f), 0xa0);
+    }
+
+    #[test]
+    fn io_registers_blocked_during_transfer() {
+        let mut m = MemoryGbz80::new();
+        m.set_byte(0xff42, 0x12);
+        m.set_byte(0xff46, 0xc0);
+        m.tick(8);
+        assert_eq!(m.get_byte(0xff42), 0xff);
+        assert_eq!(m.get_byte(0xff46), 0xff);
+        m.set_byte(0xff42, 0x34);
+        m.set_byte(0xff0f, 0x1f);
+
+        m.tick(160 * 4);
+        assert_eq!(m.get_byte(0xff42), 0x12);
+        assert_eq!(m.get_byte(0xff0f) & 0x1f, 0x00);
+    }
+
+    #[test]
+    fn restart_replaces_running_transfer() {
+        let mut m = MemoryGbz80::new();
+        m.set_byte(0xc000, 0x11);
+        m.set_byte(0xd000, 0x22);
+        m.set_byte(0xff46, 0xc0);
+        m.tick(40);
+        m.set_byte(0xff46, 0xd0);
+        m.tick(161 * 4);
+        assert!(!m.dma.active());
+        assert_eq!(m.get_byte(0xfe00), 0x22);
+    }
+}
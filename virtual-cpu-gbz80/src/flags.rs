@@ -0,0 +1,81 @@
+use virtual_cpu_core::Flags;
+
+#[derive(PartialEq, Debug, Default, Clone, Copy)]
+pub struct FlagsGbz80 {
+    pub z: bool,
+    pub n: bool,
+    pub h: bool,
+    pub cy: bool,
+}
+
+impl FlagsGbz80 {
+    pub fn new() -> FlagsGbz80 {
+        Default::default()
+    }
+
+    // Associated predicates
+
+    pub fn is_nz(f: &FlagsGbz80) -> bool {
+        !f.z
+    }
+
+    pub fn is_z(f: &FlagsGbz80) -> bool {
+        f.z
+    }
+
+    pub fn is_nc(f: &FlagsGbz80) -> bool {
+        !f.cy
+    }
+
+    pub fn is_c(f: &FlagsGbz80) -> bool {
+        f.cy
+    }
+
+    // Modifications
+
+    pub fn set_z(&mut self, n: u8) {
+        self.z = n == 0;
+    }
+
+    pub fn set_all(&mut self, z: bool, n: bool, h: bool, cy: bool) {
+        self.z = z;
+        self.n = n;
+        self.h = h;
+        self.cy = cy;
+    }
+}
+
+// The low nibble of F always reads back as zero
+impl Flags for FlagsGbz80 {
+    type Representation = u8;
+
+    fn serialize(&self) -> u8 {
+        (self.z as u8) << 7 | (self.n as u8) << 6 | (self.h as u8) << 5 | (self.cy as u8) << 4
+    }
+
+    fn deserialize(&mut self, flags: u8) {
+        self.z = (flags & 0x80) != 0;
+        self.n = (flags & 0x40) != 0;
+        self.h = (flags & 0x20) != 0;
+        self.cy = (flags & 0x10) != 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut flags = FlagsGbz80::new();
+
+        flags.deserialize(0xb0);
+        assert!(flags.z);
+        assert!(!flags.n);
+        assert!(flags.h);
+        assert!(flags.cy);
+
+        flags.deserialize(0xff);
+        assert_eq!(flags.serialize(), 0xf0);
+    }
+}
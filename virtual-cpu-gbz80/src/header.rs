@@ -0,0 +1,355 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::mbc::{RAM_BANK_SIZE, ROM_BANK_SIZE};
+
+pub const HEADER_END: usize = 0x0150;
+
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xce, 0xed, 0x66, 0x66, 0xcc, 0x0d, 0x00, 0x0b, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0c, 0x00, 0x0d,
+    0x00, 0x08, 0x11, 0x1f, 0x88, 0x89, 0x00, 0x0e, 0xdc, 0xcc, 0x6e, 0xe6, 0xdd, 0xdd, 0xd9, 0x99,
+    0xbb, 0xbb, 0x67, 0x63, 0x6e, 0x0e, 0xec, 0xcc, 0xdd, 0xdc, 0x99, 0x9f, 0xbb, 0xb9, 0x33, 0x3e,
+];
+
+const LOGO: usize = 0x0104;
+const TITLE: usize = 0x0134;
+const MANUFACTURER_CODE: usize = 0x013f;
+const CGB_FLAG: usize = 0x0143;
+const NEW_LICENSEE: usize = 0x0144;
+const SGB_FLAG: usize = 0x0146;
+const CARTRIDGE_TYPE: usize = 0x0147;
+const ROM_SIZE: usize = 0x0148;
+const RAM_SIZE: usize = 0x0149;
+const DESTINATION: usize = 0x014a;
+const OLD_LICENSEE: usize = 0x014b;
+const VERSION: usize = 0x014c;
+const HEADER_CHECKSUM: usize = 0x014d;
+const GLOBAL_CHECKSUM: usize = 0x014e;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CgbSupport {
+    None,
+    Compatible,
+    Only,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Mapper {
+    RomOnly,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HeaderError {
+    TooShort(usize),
+    UnsupportedCartridgeType(u8),
+    InvalidRomSize(u8),
+    InvalidRamSize(u8),
+    RomSizeMismatch { expected: usize, actual: usize },
+    LogoMismatch,
+    HeaderChecksum { expected: u8, actual: u8 },
+    GlobalChecksum { expected: u16, actual: u16 },
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeaderError::TooShort(len) => write!(f, "image of {} bytes has no header", len),
+            HeaderError::UnsupportedCartridgeType(code) => {
+                write!(f, "unsupported cartridge type 0x{:02x}", code)
+            }
+            HeaderError::InvalidRomSize(code) => write!(f, "invalid ROM size code 0x{:02x}", code),
+            HeaderError::InvalidRamSize(code) => write!(f, "invalid RAM size code 0x{:02x}", code),
+            HeaderError::RomSizeMismatch { expected, actual } => write!(
+                f,
+                "header declares {} bytes of ROM, image has {}",
+                expected, actual
+            ),
+            HeaderError::LogoMismatch => write!(f, "Nintendo logo does not match"),
+            HeaderError::HeaderChecksum { expected, actual } => write!(
+                f,
+                "header checksum is 0x{:02x}, computed 0x{:02x}",
+                expected, actual
+            ),
+            HeaderError::GlobalChecksum { expected, actual } => write!(
+                f,
+                "global checksum is 0x{:04x}, computed 0x{:04x}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl Error for HeaderError {}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Header {
+    pub title: String,
+    pub manufacturer_code: Option<String>,
+    pub cgb: CgbSupport,
+    pub sgb: bool,
+    pub cartridge_type: u8,
+    pub rom_size_code: u8,
+    pub ram_size_code: u8,
+    pub japanese: bool,
+    pub old_licensee: u8,
+    pub new_licensee: String,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+}
+
+fn ascii(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .take_while(|&&b| b != 0)
+        .map(|&b| b as char)
+        .collect()
+}
+
+pub fn compute_header_checksum(rom: &[u8]) -> u8 {
+    rom[TITLE..HEADER_CHECKSUM]
+        .iter()
+        .fold(0u8, |acc, &b| acc.wrapping_sub(b).wrapping_sub(1))
+}
+
+// Sum of every byte in the image except the checksum itself
+pub fn compute_global_checksum(rom: &[u8]) -> u16 {
+    rom.iter()
+        .enumerate()
+        .filter(|&(i, _)| i != GLOBAL_CHECKSUM && i != GLOBAL_CHECKSUM + 1)
+        .fold(0u16, |acc, (_, &b)| acc.wrapping_add(u16::from(b)))
+}
+
+pub fn has_valid_logo(rom: &[u8]) -> bool {
+    rom.get(LOGO..LOGO + NINTENDO_LOGO.len()) == Some(&NINTENDO_LOGO[..])
+}
+
+// Inserts the logo and fixes the ROM size code and both checksums, like
+// rgbfix. The image must be a power of two of at least two banks.
+pub fn fix_header(rom: &mut [u8]) {
+    rom[LOGO..LOGO + NINTENDO_LOGO.len()].copy_from_slice(&NINTENDO_LOGO);
+    rom[ROM_SIZE] = (rom.len() / (2 * ROM_BANK_SIZE)).trailing_zeros() as u8;
+    rom[HEADER_CHECKSUM] = compute_header_checksum(rom);
+    let global = compute_global_checksum(rom);
+    rom[GLOBAL_CHECKSUM] = (global >> 8) as u8;
+    rom[GLOBAL_CHECKSUM + 1] = global as u8;
+}
+
+impl Header {
+    pub fn parse(rom: &[u8]) -> Result<Header, HeaderError> {
+        if rom.len() < HEADER_END {
+            return Err(HeaderError::TooShort(rom.len()));
+        }
+
+        let cgb = match rom[CGB_FLAG] {
+            0x80 => CgbSupport::Compatible,
+            0xc0 => CgbSupport::Only,
+            _ => CgbSupport::None,
+        };
+        let code = &rom[MANUFACTURER_CODE..CGB_FLAG];
+        let manufacturer_code = if cgb != CgbSupport::None
+            && code
+                .iter()
+                .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit())
+        {
+            Some(ascii(code))
+        } else {
+            None
+        };
+        let title_end = match (cgb, &manufacturer_code) {
+            (_, Some(_)) => MANUFACTURER_CODE,
+            (CgbSupport::None, None) => NEW_LICENSEE,
+            _ => CGB_FLAG,
+        };
+
+        Ok(Header {
+            title: ascii(&rom[TITLE..title_end]),
+            manufacturer_code,
+            cgb,
+            sgb: rom[SGB_FLAG] == 0x03,
+            cartridge_type: rom[CARTRIDGE_TYPE],
+            rom_size_code: rom[ROM_SIZE],
+            ram_size_code: rom[RAM_SIZE],
+            japanese: rom[DESTINATION] == 0x00,
+            old_licensee: rom[OLD_LICENSEE],
+            new_licensee: ascii(&rom[NEW_LICENSEE..SGB_FLAG]),
+            version: rom[VERSION],
+            header_checksum: rom[HEADER_CHECKSUM],
+            global_checksum: u16::from(rom[GLOBAL_CHECKSUM]) << 8
+                | u16::from(rom[GLOBAL_CHECKSUM + 1]),
+        })
+    }
+
+    // Checks everything the header declares against the image itself
+    pub fn validate(&self, rom: &[u8]) -> Result<(), HeaderError> {
+        self.mapper()?;
+        self.ram_size()?;
+        let expected = self.rom_size()?;
+        if rom.len() != expected {
+            return Err(HeaderError::RomSizeMismatch {
+                expected,
+                actual: rom.len(),
+            });
+        }
+        if !has_valid_logo(rom) {
+            return Err(HeaderError::LogoMismatch);
+        }
+        let actual = compute_header_checksum(rom);
+        if actual != self.header_checksum {
+            return Err(HeaderError::HeaderChecksum {
+                expected: self.header_checksum,
+                actual,
+            });
+        }
+        let actual = compute_global_checksum(rom);
+        if actual != self.global_checksum {
+            return Err(HeaderError::GlobalChecksum {
+                expected: self.global_checksum,
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn licensee(&self) -> String {
+        match self.old_licensee {
+            0x33 => self.new_licensee.clone(),
+            code => format!("{:02X}", code),
+        }
+    }
+
+    pub fn mapper(&self) -> Result<Mapper, HeaderError> {
+        match self.cartridge_type {
+            0x00 | 0x08 | 0x09 => Ok(Mapper::RomOnly),
+            0x01..=0x03 => Ok(Mapper::Mbc1),
+            0x05 | 0x06 => Ok(Mapper::Mbc2),
+            0x0f..=0x13 => Ok(Mapper::Mbc3),
+            0x19..=0x1e => Ok(Mapper::Mbc5),
+            code => Err(HeaderError::UnsupportedCartridgeType(code)),
+        }
+    }
+
+    pub fn has_ram(&self) -> bool {
+        matches!(
+            self.cartridge_type,
+            0x02 | 0x03
+                | 0x05
+                | 0x06
+                | 0x08
+                | 0x09
+                | 0x10
+                | 0x12
+                | 0x13
+                | 0x1a
+                | 0x1b
+                | 0x1d
+                | 0x1e
+        )
+    }
+
+    pub fn has_battery(&self) -> bool {
+        matches!(
+            self.cartridge_type,
+            0x03 | 0x06 | 0x09 | 0x0f | 0x10 | 0x13 | 0x1b | 0x1e
+        )
+    }
+
+    pub fn has_timer(&self) -> bool {
+        matches!(self.cartridge_type, 0x0f | 0x10)
+    }
+
+    pub fn has_rumble(&self) -> bool {
+        matches!(self.cartridge_type, 0x1c..=0x1e)
+    }
+
+    pub fn rom_size(&self) -> Result<usize, HeaderError> {
+        match self.rom_size_code {
+            code @ 0x00..=0x08 => Ok((2 * ROM_BANK_SIZE) << code),
+            code => Err(HeaderError::InvalidRomSize(code)),
+        }
+    }
+
+    // MBC2 reports no RAM in the header but always carries 512 nibbles
+    pub fn ram_size(&self) -> Result<usize, HeaderError> {
+        if self.mapper() == Ok(Mapper::Mbc2) {
+            return Ok(crate::mbc::Mbc2::RAM_SIZE);
+        }
+        match self.ram_size_code {
+            0x00 => Ok(0),
+            0x01 => Ok(0x800),
+            0x02 => Ok(RAM_BANK_SIZE),
+            0x03 => Ok(4 * RAM_BANK_SIZE),
+            0x04 => Ok(16 * RAM_BANK_SIZE),
+            0x05 => Ok(8 * RAM_BANK_SIZE),
+            code => Err(HeaderError::InvalidRamSize(code)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_rom() -> Vec<u8> {
+        let mut rom = vec![0; 2 * ROM_BANK_SIZE];
+        rom[LOGO..LOGO + 48].copy_from_slice(&NINTENDO_LOGO);
+        rom[TITLE..TITLE + 7].copy_from_slice(b"TESTING");
+        rom[MANUFACTURER_CODE..CGB_FLAG].copy_from_slice(b"ABCE");
+        rom[CGB_FLAG] = 0x80;
+        rom[CARTRIDGE_TYPE] = 0x13;
+        rom[RAM_SIZE] = 0x03;
+        rom[OLD_LICENSEE] = 0x33;
+        rom[NEW_LICENSEE..SGB_FLAG].copy_from_slice(b"01");
+        rom[HEADER_CHECKSUM] = compute_header_checksum(&rom);
+        let global = compute_global_checksum(&rom);
+        rom[GLOBAL_CHECKSUM] = (global >> 8) as u8;
+        rom[GLOBAL_CHECKSUM + 1] = global as u8;
+        rom
+    }
+
+    #[test]
+    fn parse_test() {
+        let rom = test_rom();
+        let header = Header::parse(&rom).unwrap();
+
+        assert_eq!(header.title, "TESTING");
+        assert_eq!(header.manufacturer_code, Some("ABCE".to_string()));
+        assert_eq!(header.cgb, CgbSupport::Compatible);
+        assert_eq!(header.licensee(), "01");
+        assert_eq!(header.mapper(), Ok(Mapper::Mbc3));
+        assert_eq!(header.rom_size(), Ok(0x8000));
+        assert_eq!(header.ram_size(), Ok(0x8000));
+        assert!(header.has_battery());
+        assert_eq!(header.validate(&rom), Ok(()));
+    }
+
+    #[test]
+    fn checksum_errors_test() {
+        let mut rom = test_rom();
+        rom[VERSION] = 1;
+        let header = Header::parse(&rom).unwrap();
+        assert!(matches!(
+            header.validate(&rom),
+            Err(HeaderError::HeaderChecksum { .. })
+        ));
+
+        let mut rom = test_rom();
+        rom[0x4000] = 1;
+        let header = Header::parse(&rom).unwrap();
+        assert!(matches!(
+            header.validate(&rom),
+            Err(HeaderError::GlobalChecksum { .. })
+        ));
+
+        rom[LOGO] = 0;
+        assert!(!has_valid_logo(&rom));
+        assert_eq!(
+            Header::parse(&rom[..0x100]),
+            Err(HeaderError::TooShort(0x100))
+        );
+    }
+}
@@ -0,0 +1,16 @@
+use crate::flags::FlagsGbz80;
+
+pub use virtual_cpu_8080::instructions::{
+    apply_offset, byte_arg_from, dec16, inc16, register_for_code, word_arg_from,
+};
+
+// Only the first four 8080 conditions exist on the gbz80
+pub fn condition_for(opcode: u8) -> impl Fn(&FlagsGbz80) -> bool {
+    match (opcode >> 3) & 0x03 {
+        0x0 => FlagsGbz80::is_nz,
+        0x1 => FlagsGbz80::is_z,
+        0x2 => FlagsGbz80::is_nc,
+        0x3 => FlagsGbz80::is_c,
+        _ => panic!("shouldn't happen"),
+    }
+}
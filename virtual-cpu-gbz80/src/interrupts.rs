@@ -0,0 +1,51 @@
+pub const VBLANK: u8 = 0x01;
+pub const LCD_STAT: u8 = 0x02;
+pub const TIMER: u8 = 0x04;
+pub const SERIAL: u8 = 0x08;
+pub const JOYPAD: u8 = 0x10;
+
+// IE (0xffff) and IF (0xff0f). Only the low five bits of IF exist, the rest
+// read back as ones.
+#[derive(Debug, Default)]
+pub struct Interrupts {
+    pub enable: u8,
+    pub flags: u8,
+}
+
+impl Interrupts {
+    pub fn new() -> Interrupts {
+        Interrupts::default()
+    }
+
+    pub fn request(&mut self, mask: u8) {
+        self.flags |= mask & 0x1f;
+    }
+
+    pub fn read_flags(&self) -> u8 {
+        self.flags | 0xe0
+    }
+
+    pub fn write_flags(&mut self, val: u8) {
+        self.flags = val & 0x1f;
+    }
+
+    pub fn pending(&self) -> u8 {
+        self.enable & self.flags & 0x1f
+    }
+
+    // Lower bits have priority: VBlank first, joypad last
+    pub fn highest_priority(&self) -> Option<u8> {
+        match self.pending() {
+            0 => None,
+            pending => Some(pending.trailing_zeros() as u8),
+        }
+    }
+
+    pub fn acknowledge(&mut self, n: u8) {
+        self.flags &= !(1 << n);
+    }
+
+    pub fn vector(n: u8) -> u16 {
+        0x0040 + 0x08 * u16::from(n)
+    }
+}
@@ -0,0 +1,128 @@
+use crate::interrupts::{Interrupts, JOYPAD};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    pub fn from_name(name: &str) -> Option<Button> {
+        match name.to_ascii_lowercase().as_str() {
+            "right" => Some(Button::Right),
+            "left" => Some(Button::Left),
+            "up" => Some(Button::Up),
+            "down" => Some(Button::Down),
+            "a" => Some(Button::A),
+            "b" => Some(Button::B),
+            "select" => Some(Button::Select),
+            "start" => Some(Button::Start),
+            _ => None,
+        }
+    }
+
+    // Directions occupy the low nibble and actions the high nibble, each in
+    // the order of the P1 input lines
+    fn mask(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+// P1/JOYP (0xff00). Bits 4 and 5 select the direction and action rows when
+// written as 0, and the low nibble reads 0 for every pressed button in the
+// selected rows.
+#[derive(Debug)]
+pub struct Joypad {
+    select: u8,
+    pressed: u8,
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad {
+            select: 0x30,
+            pressed: 0,
+        }
+    }
+
+    pub fn read(&self) -> u8 {
+        0xc0 | self.select | self.lines()
+    }
+
+    fn lines(&self) -> u8 {
+        let mut low = 0;
+        if (self.select & 0x10) == 0 {
+            low |= self.pressed & 0x0f;
+        }
+        if (self.select & 0x20) == 0 {
+            low |= self.pressed >> 4;
+        }
+        !low & 0x0f
+    }
+
+    pub fn write(&mut self, val: u8, interrupts: &mut Interrupts) {
+        self.update(interrupts, |joypad| joypad.select = val & 0x30);
+    }
+
+    pub fn press(&mut self, button: Button, interrupts: &mut Interrupts) {
+        self.update(interrupts, |joypad| joypad.pressed |= button.mask());
+    }
+
+    pub fn release(&mut self, button: Button, interrupts: &mut Interrupts) {
+        self.update(interrupts, |joypad| joypad.pressed &= !button.mask());
+    }
+
+    pub fn is_pressed(&self, button: Button) -> bool {
+        (self.pressed & button.mask()) != 0
+    }
+
+    // Any line going from high to low raises the interrupt
+    fn update(&mut self, interrupts: &mut Interrupts, change: impl FnOnce(&mut Joypad)) {
+        let before = self.lines();
+        change(self);
+        if (before & !self.lines()) != 0 {
+            interrupts.request(JOYPAD);
+        }
+    }
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selected_rows_and_interrupt() {
+        let mut joypad = Joypad::new();
+        let mut interrupts = Interrupts::new();
+        joypad.write(0x20, &mut interrupts);
+
+        joypad.press(Button::Start, &mut interrupts);
+        assert_eq!(joypad.read(), 0xef);
+        assert_eq!(interrupts.flags & JOYPAD, 0);
+
+        joypad.press(Button::Down, &mut interrupts);
+        assert_eq!(joypad.read(), 0xe7);
+        assert_eq!(interrupts.flags & JOYPAD, JOYPAD);
+
+        // Start shares the line Down was already pulling low
+        interrupts.flags = 0;
+        joypad.write(0x10, &mut interrupts);
+        assert_eq!(joypad.read(), 0xd7);
+        assert_eq!(interrupts.flags & JOYPAD, 0);
+
+        joypad.release(Button::Start, &mut interrupts);
+        assert_eq!(joypad.read(), 0xdf);
+    }
+}
@@ -1 +1,35 @@
+pub mod apu;
+pub mod assembler;
+pub mod bess;
+pub mod cartridge;
+pub mod cgb;
 pub mod cpu;
+pub mod disassembler;
+pub mod dma;
+pub mod flags;
+pub mod header;
+pub mod image;
+pub mod instructions;
+pub mod interrupts;
+pub mod joypad;
+pub mod mbc;
+pub mod memory;
+pub mod ppu;
+pub mod printer;
+pub mod program;
+pub mod registers;
+pub mod rtc;
+pub mod runner;
+pub mod serial;
+pub mod stack;
+pub mod state;
+pub mod test_rom;
+pub mod timer;
+pub mod vgm;
+pub mod vram_view;
+
+pub use self::{
+    cartridge::Cartridge, cgb::Model, cpu::CpuGbz80, flags::FlagsGbz80, header::Header,
+    joypad::Button, memory::MemoryGbz80, ppu::Ppu, program::ProgramGbz80,
+    registers::RegistersGbz80, stack::StackGbz80, state::StateGbz80,
+};
@@ -0,0 +1,461 @@
+use crate::rtc::Rtc;
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+pub trait Mbc {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> u8;
+    fn write_register(&mut self, addr: u16, val: u8);
+
+    fn read_ram(&self, ram: &[u8], addr: u16) -> u8;
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, val: u8);
+
+    fn rtc(&self) -> Option<&Rtc> {
+        None
+    }
+
+    // Register writes that bring a fresh mapper to the current state
+    fn register_writes(&self) -> Vec<(u16, u8)> {
+        Vec::new()
+    }
+    fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        None
+    }
+}
+
+fn rom_index(rom: &[u8], bank: usize, addr: u16) -> usize {
+    let banks = (rom.len() / ROM_BANK_SIZE).max(1);
+    (bank % banks) * ROM_BANK_SIZE + (addr as usize & (ROM_BANK_SIZE - 1))
+}
+
+fn rom_byte(rom: &[u8], bank: usize, addr: u16) -> u8 {
+    rom.get(rom_index(rom, bank, addr)).copied().unwrap_or(0xff)
+}
+
+fn ram_index(ram: &[u8], bank: usize, addr: u16) -> Option<usize> {
+    if ram.is_empty() {
+        return None;
+    }
+    let banks = (ram.len() / RAM_BANK_SIZE).max(1);
+    Some(((bank % banks) * RAM_BANK_SIZE + (addr as usize & (RAM_BANK_SIZE - 1))) % ram.len())
+}
+
+fn ram_enable(val: u8) -> bool {
+    (val & 0x0f) == 0x0a
+}
+
+fn ram_enable_value(enabled: bool) -> u8 {
+    if enabled {
+        0x0a
+    } else {
+        0x00
+    }
+}
+
+// No mapper: 32K of ROM and optionally 8K of RAM
+#[derive(Debug, Default)]
+pub struct RomOnly;
+
+impl Mbc for RomOnly {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        rom.get(addr as usize).copied().unwrap_or(0xff)
+    }
+
+    fn write_register(&mut self, _addr: u16, _val: u8) {}
+
+    fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        ram_index(ram, 0, addr).map_or(0xff, |i| ram[i])
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, val: u8) {
+        if let Some(i) = ram_index(ram, 0, addr) {
+            ram[i] = val;
+        }
+    }
+}
+
+// BANK1 holds the low five ROM bank bits, BANK2 is shared between the upper
+// ROM bank bits (on 1M+ carts) and the RAM bank (on 32K RAM carts). The mode
+// bit decides whether BANK2 also applies to 0x0000-0x3fff and to RAM.
+#[derive(Debug)]
+pub struct Mbc1 {
+    ram_enabled: bool,
+    bank1: u8,
+    bank2: u8,
+    mode: bool,
+}
+
+impl Mbc1 {
+    pub fn new() -> Mbc1 {
+        Mbc1 {
+            ram_enabled: false,
+            bank1: 1,
+            bank2: 0,
+            mode: false,
+        }
+    }
+
+    fn ram_bank(&self) -> usize {
+        if self.mode {
+            self.bank2 as usize
+        } else {
+            0
+        }
+    }
+}
+
+impl Default for Mbc1 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mbc for Mbc1 {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        let bank = if addr < 0x4000 {
+            if self.mode {
+                (self.bank2 as usize) << 5
+            } else {
+                0
+            }
+        } else {
+            (self.bank2 as usize) << 5 | self.bank1 as usize
+        };
+        rom_byte(rom, bank, addr)
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x1fff => self.ram_enabled = ram_enable(val),
+            0x2000..=0x3fff => self.bank1 = (val & 0x1f).max(1),
+            0x4000..=0x5fff => self.bank2 = val & 0x03,
+            _ => self.mode = (val & 0x01) != 0,
+        }
+    }
+
+    fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        match ram_index(ram, self.ram_bank(), addr) {
+            Some(i) if self.ram_enabled => ram[i],
+            _ => 0xff,
+        }
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, val: u8) {
+        match ram_index(ram, self.ram_bank(), addr) {
+            Some(i) if self.ram_enabled => ram[i] = val,
+            _ => (),
+        }
+    }
+
+    fn register_writes(&self) -> Vec<(u16, u8)> {
+        vec![
+            (0x0000, ram_enable_value(self.ram_enabled)),
+            (0x2000, self.bank1),
+            (0x4000, self.bank2),
+            (0x6000, self.mode as u8),
+        ]
+    }
+}
+
+// Address bit 8 picks between the RAM enable and ROM bank registers. The
+// 512x4 bit RAM is built in and mirrored across 0xa000-0xbfff.
+#[derive(Debug)]
+pub struct Mbc2 {
+    ram_enabled: bool,
+    rom_bank: u8,
+}
+
+impl Mbc2 {
+    pub const RAM_SIZE: usize = 0x200;
+
+    pub fn new() -> Mbc2 {
+        Mbc2 {
+            ram_enabled: false,
+            rom_bank: 1,
+        }
+    }
+}
+
+impl Default for Mbc2 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mbc for Mbc2 {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        let bank = if addr < 0x4000 { 0 } else { self.rom_bank };
+        rom_byte(rom, bank as usize, addr)
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x3fff if (addr & 0x0100) == 0 => self.ram_enabled = ram_enable(val),
+            0x0000..=0x3fff => self.rom_bank = (val & 0x0f).max(1),
+            _ => (),
+        }
+    }
+
+    fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        match ram.get(addr as usize & (Self::RAM_SIZE - 1)) {
+            Some(val) if self.ram_enabled => val | 0xf0,
+            _ => 0xff,
+        }
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, val: u8) {
+        match ram.get_mut(addr as usize & (Self::RAM_SIZE - 1)) {
+            Some(cell) if self.ram_enabled => *cell = val & 0x0f,
+            _ => (),
+        }
+    }
+
+    fn register_writes(&self) -> Vec<(u16, u8)> {
+        vec![
+            (0x0000, ram_enable_value(self.ram_enabled)),
+            (0x0100, self.rom_bank),
+        ]
+    }
+}
+
+// RAM banks 0x00-0x07 select external RAM, 0x08-0x0c select the clock
+// registers on carts that have one.
+#[derive(Debug)]
+pub struct Mbc3 {
+    ram_enabled: bool,
+    rom_bank: u8,
+    ram_bank: u8,
+    rtc: Option<Rtc>,
+}
+
+impl Mbc3 {
+    pub fn new(has_timer: bool) -> Mbc3 {
+        Mbc3 {
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            rtc: if has_timer { Some(Rtc::new()) } else { None },
+        }
+    }
+
+    fn selected_rtc(&self) -> Option<&Rtc> {
+        self.rtc
+            .as_ref()
+            .filter(|_| (0x08..=0x0c).contains(&self.ram_bank))
+    }
+}
+
+impl Mbc for Mbc3 {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        let bank = if addr < 0x4000 { 0 } else { self.rom_bank };
+        rom_byte(rom, bank as usize, addr)
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x1fff => self.ram_enabled = ram_enable(val),
+            0x2000..=0x3fff => self.rom_bank = (val & 0x7f).max(1),
+            0x4000..=0x5fff => self.ram_bank = val & 0x0f,
+            _ => {
+                if let Some(rtc) = self.rtc.as_mut() {
+                    rtc.write_latch(val);
+                }
+            }
+        }
+    }
+
+    fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xff;
+        }
+        if let Some(rtc) = self.selected_rtc() {
+            return rtc.read(self.ram_bank);
+        }
+        match ram_index(ram, self.ram_bank as usize, addr) {
+            Some(i) if self.ram_bank < 0x08 => ram[i],
+            _ => 0xff,
+        }
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, val: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+        let reg = self.ram_bank;
+        if let Some(rtc) = self.rtc.as_mut().filter(|_| (0x08..=0x0c).contains(&reg)) {
+            rtc.write(reg, val);
+            return;
+        }
+        match ram_index(ram, self.ram_bank as usize, addr) {
+            Some(i) if self.ram_bank < 0x08 => ram[i] = val,
+            _ => (),
+        }
+    }
+
+    fn rtc(&self) -> Option<&Rtc> {
+        self.rtc.as_ref()
+    }
+
+    fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        self.rtc.as_mut()
+    }
+
+    fn register_writes(&self) -> Vec<(u16, u8)> {
+        vec![
+            (0x0000, ram_enable_value(self.ram_enabled)),
+            (0x2000, self.rom_bank),
+            (0x4000, self.ram_bank),
+        ]
+    }
+}
+
+// Nine bit ROM bank (bank 0 is selectable in 0x4000-0x7fff) and four bit RAM
+// bank. On rumble carts bit 3 of the RAM bank register drives the motor.
+#[derive(Debug)]
+pub struct Mbc5 {
+    ram_enabled: bool,
+    rom_bank: u16,
+    ram_bank: u8,
+    has_rumble: bool,
+    rumble: bool,
+}
+
+impl Mbc5 {
+    pub fn new(has_rumble: bool) -> Mbc5 {
+        Mbc5 {
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            has_rumble,
+            rumble: false,
+        }
+    }
+
+    pub fn rumble(&self) -> bool {
+        self.rumble
+    }
+}
+
+impl Mbc for Mbc5 {
+    fn read_rom(&self, rom: &[u8], addr: u16) -> u8 {
+        let bank = if addr < 0x4000 { 0 } else { self.rom_bank };
+        rom_byte(rom, bank as usize, addr)
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x1fff => self.ram_enabled = ram_enable(val),
+            0x2000..=0x2fff => self.rom_bank = (self.rom_bank & 0x100) | u16::from(val),
+            0x3000..=0x3fff => self.rom_bank = (self.rom_bank & 0xff) | u16::from(val & 0x01) << 8,
+            0x4000..=0x5fff if self.has_rumble => {
+                self.rumble = (val & 0x08) != 0;
+                self.ram_bank = val & 0x07;
+            }
+            0x4000..=0x5fff => self.ram_bank = val & 0x0f,
+            _ => (),
+        }
+    }
+
+    fn read_ram(&self, ram: &[u8], addr: u16) -> u8 {
+        match ram_index(ram, self.ram_bank as usize, addr) {
+            Some(i) if self.ram_enabled => ram[i],
+            _ => 0xff,
+        }
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], addr: u16, val: u8) {
+        match ram_index(ram, self.ram_bank as usize, addr) {
+            Some(i) if self.ram_enabled => ram[i] = val,
+            _ => (),
+        }
+    }
+
+    fn register_writes(&self) -> Vec<(u16, u8)> {
+        vec![
+            (0x0000, ram_enable_value(self.ram_enabled)),
+            (0x2000, self.rom_bank as u8),
+            (0x3000, (self.rom_bank >> 8) as u8),
+            (0x4000, self.ram_bank | (self.rumble as u8) << 3),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn banked_rom(banks: usize) -> Vec<u8> {
+        let mut rom = vec![0; banks * ROM_BANK_SIZE];
+        for bank in 0..banks {
+            rom[bank * ROM_BANK_SIZE] = bank as u8;
+            rom[bank * ROM_BANK_SIZE + 1] = (bank >> 8) as u8;
+        }
+        rom
+    }
+
+    #[test]
+    fn mbc1_bank_switching() {
+        let rom = banked_rom(128);
+        let mut mbc = Mbc1::new();
+
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 1);
+        mbc.write_register(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 1);
+        mbc.write_register(0x2000, 0x1f);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x1f);
+        mbc.write_register(0x4000, 0x02);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x5f);
+        assert_eq!(mbc.read_rom(&rom, 0x0000), 0);
+        mbc.write_register(0x6000, 0x01);
+        assert_eq!(mbc.read_rom(&rom, 0x0000), 0x40);
+    }
+
+    #[test]
+    fn mbc1_ram_banking() {
+        let mut ram = vec![0; 4 * RAM_BANK_SIZE];
+        let mut mbc = Mbc1::new();
+
+        mbc.write_ram(&mut ram, 0xa000, 0x12);
+        assert_eq!(mbc.read_ram(&ram, 0xa000), 0xff);
+
+        mbc.write_register(0x0000, 0x0a);
+        mbc.write_register(0x6000, 0x01);
+        mbc.write_register(0x4000, 0x02);
+        mbc.write_ram(&mut ram, 0xa000, 0x12);
+        assert_eq!(ram[2 * RAM_BANK_SIZE], 0x12);
+
+        mbc.write_register(0x6000, 0x00);
+        assert_eq!(mbc.read_ram(&ram, 0xa000), 0x00);
+    }
+
+    #[test]
+    fn mbc2_nibble_ram() {
+        let mut ram = vec![0; Mbc2::RAM_SIZE];
+        let mut mbc = Mbc2::new();
+
+        mbc.write_register(0x0000, 0x0a);
+        mbc.write_ram(&mut ram, 0xa001, 0x5a);
+        assert_eq!(mbc.read_ram(&ram, 0xa001), 0xfa);
+        assert_eq!(mbc.read_ram(&ram, 0xa201), 0xfa);
+
+        let rom = banked_rom(16);
+        mbc.write_register(0x0100, 0x03);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 3);
+    }
+
+    #[test]
+    fn mbc5_nine_bit_bank() {
+        let rom = banked_rom(512);
+        let mut mbc = Mbc5::new(true);
+
+        mbc.write_register(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0);
+        mbc.write_register(0x2000, 0x23);
+        mbc.write_register(0x3000, 0x01);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x23);
+        assert_eq!(mbc.read_rom(&rom, 0x4001), 0x01);
+
+        mbc.write_register(0x4000, 0x0b);
+        assert!(mbc.rumble());
+    }
+}
@@ -0,0 +1,408 @@
+use std::fmt;
+use virtual_cpu_core::{Bus, MappedMemory, Memory};
+
+use crate::apu::Apu;
+use crate::cartridge::Cartridge;
+use crate::cgb::{Hdma, Model};
+use crate::dma::Dma;
+use crate::interrupts::Interrupts;
+use crate::joypad::{Button, Joypad};
+use crate::ppu::Ppu;
+use crate::serial::Serial;
+use crate::timer::Timer;
+
+// Values the DMG boot ROM leaves in the I/O registers
+static POST_BOOT_IO: [(u16, u8); 37] = [
+    (0xff00, 0xcf),
+    (0xff02, 0x7e),
+    (0xff07, 0xf8),
+    (0xff0f, 0xe1),
+    (0xff10, 0x80),
+    (0xff11, 0xbf),
+    (0xff12, 0xf3),
+    (0xff13, 0xff),
+    (0xff14, 0xbf),
+    (0xff16, 0x3f),
+    (0xff17, 0x00),
+    (0xff18, 0xff),
+    (0xff19, 0xbf),
+    (0xff1a, 0x7f),
+    (0xff1b, 0xff),
+    (0xff1c, 0x9f),
+    (0xff1d, 0xff),
+    (0xff1e, 0xbf),
+    (0xff20, 0xff),
+    (0xff21, 0x00),
+    (0xff22, 0x00),
+    (0xff23, 0xbf),
+    (0xff24, 0x77),
+    (0xff25, 0xf3),
+    (0xff26, 0xf1),
+    (0xff40, 0x91),
+    (0xff41, 0x85),
+    (0xff42, 0x00),
+    (0xff43, 0x00),
+    (0xff44, 0x00),
+    (0xff45, 0x00),
+    (0xff47, 0xfc),
+    (0xff48, 0xff),
+    (0xff49, 0xff),
+    (0xff4a, 0x00),
+    (0xff4b, 0x00),
+    (0xffff, 0x00),
+];
+
+pub struct MemoryGbz80 {
+    pub cartridge: Cartridge,
+    // Hardware in the cartridge slot that takes the cartridge's place,
+    // answering for 0x0000-0x7fff and 0xa000-0xbfff
+    pub slot: Option<MappedMemory>,
+    model: Model,
+    boot_rom: Option<Vec<u8>>,
+    // Eight 4KB banks on the CGB, with SVBK selecting the one at 0xd000
+    wram: [u8; 0x8000],
+    wram_bank: usize,
+    double_speed: bool,
+    speed_switch_armed: bool,
+    io: [u8; 0x80],
+    hram: [u8; 0x7f],
+    pub interrupts: Interrupts,
+    pub ppu: Ppu,
+    pub dma: Dma,
+    pub timer: Timer,
+    pub joypad: Joypad,
+    pub apu: Apu,
+    pub serial: Serial,
+    pub hdma: Hdma,
+}
+
+impl MemoryGbz80 {
+    pub fn new() -> MemoryGbz80 {
+        MemoryGbz80::with_cartridge(Cartridge::default())
+    }
+
+    pub fn with_cartridge(cartridge: Cartridge) -> MemoryGbz80 {
+        MemoryGbz80::with_model(cartridge, Model::Dmg)
+    }
+
+    pub fn with_model(cartridge: Cartridge, model: Model) -> MemoryGbz80 {
+        MemoryGbz80 {
+            cartridge,
+            slot: None,
+            model,
+            boot_rom: None,
+            wram: [0; 0x8000],
+            wram_bank: 1,
+            double_speed: false,
+            speed_switch_armed: false,
+            io: [0; 0x80],
+            hram: [0; 0x7f],
+            interrupts: Interrupts::new(),
+            ppu: Ppu::with_model(model),
+            dma: Dma::new(),
+            timer: Timer::new(),
+            joypad: Joypad::new(),
+            apu: Apu::new(),
+            serial: Serial::new(),
+            hdma: Hdma::new(),
+        }
+    }
+
+    pub fn with_slot(slot: MappedMemory, model: Model) -> MemoryGbz80 {
+        let mut m = MemoryGbz80::with_model(Cartridge::default(), model);
+        m.slot = Some(slot);
+        m
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    pub fn cgb(&self) -> bool {
+        self.model == Model::Cgb
+    }
+
+    pub fn double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    // Set through KEY1, the switch itself happens on the next STOP
+    pub fn speed_switch_armed(&self) -> bool {
+        self.speed_switch_armed
+    }
+
+    pub fn switch_speed(&mut self) {
+        self.double_speed = !self.double_speed;
+        self.speed_switch_armed = false;
+    }
+
+    // Work RAM as the model has it: 8KB on the DMG, 32KB on the CGB
+    pub fn wram(&self) -> &[u8] {
+        &self.wram[..self.wram_size()]
+    }
+
+    pub fn wram_mut(&mut self) -> &mut [u8] {
+        let size = self.wram_size();
+        &mut self.wram[..size]
+    }
+
+    fn wram_size(&self) -> usize {
+        match self.model {
+            Model::Dmg => 0x2000,
+            Model::Cgb => 0x8000,
+        }
+    }
+
+    pub fn hram(&self) -> &[u8] {
+        &self.hram
+    }
+
+    pub fn hram_mut(&mut self) -> &mut [u8] {
+        &mut self.hram
+    }
+
+    pub fn map_boot_rom(&mut self, boot_rom: Vec<u8>) {
+        self.boot_rom = Some(boot_rom);
+    }
+
+    pub fn boot_rom_mapped(&self) -> bool {
+        self.boot_rom.is_some()
+    }
+
+    pub fn reset_post_boot(&mut self) {
+        self.boot_rom = None;
+        // Writing DIV would clear the system counter instead of setting it
+        self.timer = Timer::with_counter(0xabcc);
+        // The sound registers ignore writes until NR52 powers the APU on
+        self.apu.write(0xff26, 0x80);
+        for &(addr, val) in POST_BOOT_IO.iter() {
+            self.set_byte(addr, val);
+        }
+    }
+
+    pub fn press(&mut self, button: Button) {
+        self.joypad.press(button, &mut self.interrupts);
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.joypad.release(button, &mut self.interrupts);
+    }
+
+    // The timer, OAM DMA and serial port run off the CPU clock, everything
+    // else keeps its normal speed in double speed mode
+    pub fn tick(&mut self, cycles: usize) {
+        let dots = if self.double_speed {
+            cycles / 2
+        } else {
+            cycles
+        };
+        self.cartridge.tick(dots);
+        if let Some(slot) = &mut self.slot {
+            slot.tick(dots);
+        }
+        for _ in 0..cycles / 4 {
+            self.timer.step(&mut self.interrupts);
+            if let Some((source, index)) = self.dma.step() {
+                self.ppu.oam[index] = self.read_bus(source);
+            }
+        }
+        self.ppu.tick(dots, &mut self.interrupts);
+        if self.ppu.take_hblank_entered() && self.hdma.hblank_active() {
+            self.copy_hdma_block();
+        }
+        self.apu.tick(dots);
+        self.serial.tick(cycles, &mut self.interrupts);
+    }
+
+    fn copy_hdma_block(&mut self) {
+        let (source, dest) = self.hdma.next_block();
+        for i in 0..0x10 {
+            let val = self.read_bus(source.wrapping_add(i));
+            self.ppu.write_vram(dest + i, val);
+        }
+    }
+
+    fn wram_index(&self, addr: u16) -> usize {
+        match addr & 0x1fff {
+            offset @ 0x0000..=0x0fff => offset as usize,
+            offset => self.wram_bank * 0x1000 + (offset & 0x0fff) as usize,
+        }
+    }
+
+    // While OAM DMA runs the CPU only reaches HRAM and IE. Writes to 0xff46
+    // still get through to restart the transfer.
+    fn dma_blocks(&self, addr: u16) -> bool {
+        self.dma.active() && addr < 0xff80
+    }
+
+    // Reads without the restrictions of a running OAM DMA
+    pub fn read_bus(&self, addr: u16) -> u8 {
+        if let Some(boot_rom) = &self.boot_rom {
+            // The CGB boot ROM leaves a gap for the cartridge header
+            let mapped = addr < 0x0100 || (0x0200..0x0900).contains(&addr);
+            if let Some(&val) = boot_rom.get(addr as usize).filter(|_| mapped) {
+                return val;
+            }
+        }
+        match addr {
+            0x0000..=0x7fff | 0xa000..=0xbfff if self.slot.is_some() => {
+                self.slot.as_ref().unwrap().get_byte(addr)
+            }
+            0x0000..=0x7fff => self.cartridge.read_rom(addr),
+            0x8000..=0x9fff => self.ppu.read_vram(addr),
+            0xa000..=0xbfff => self.cartridge.read_ram(addr),
+            0xc000..=0xfdff => self.wram[self.wram_index(addr)],
+            0xfe00..=0xfe9f => self.ppu.oam[(addr - 0xfe00) as usize],
+            0xfea0..=0xfeff => 0xff,
+            0xff00 => self.joypad.read(),
+            0xff01..=0xff02 => self.serial.read(addr),
+            0xff04..=0xff07 => self.timer.read(addr),
+            0xff0f => self.interrupts.read_flags(),
+            0xff10..=0xff26 | 0xff30..=0xff3f => self.apu.read(addr),
+            0xff40..=0xff45 | 0xff47..=0xff4b => self.ppu.read(addr),
+            0xff46 => self.dma.read(),
+            0xff4d if self.cgb() => {
+                0x7e | (self.double_speed as u8) << 7 | self.speed_switch_armed as u8
+            }
+            0xff4f | 0xff68..=0xff6b => self.ppu.read(addr),
+            0xff51..=0xff55 if self.cgb() => self.hdma.read(addr),
+            0xff70 if self.cgb() => 0xf8 | self.wram_bank as u8,
+            0xff03..=0xff7f => self.io[(addr - 0xff00) as usize],
+            0xff80..=0xfffe => self.hram[(addr - 0xff80) as usize],
+            0xffff => self.interrupts.enable,
+        }
+    }
+}
+
+impl Default for MemoryGbz80 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Memory for MemoryGbz80 {
+    type Address = u16;
+
+    fn get_byte(&self, addr: u16) -> u8 {
+        if self.dma_blocks(addr) {
+            return 0xff;
+        }
+        self.read_bus(addr)
+    }
+
+    fn set_byte(&mut self, addr: u16, val: u8) {
+        if self.dma_blocks(addr) && addr != 0xff46 {
+            return;
+        }
+        match addr {
+            0x0000..=0x7fff | 0xa000..=0xbfff if self.slot.is_some() => {
+                self.slot.as_mut().unwrap().set_byte(addr, val)
+            }
+            0x0000..=0x7fff => self.cartridge.write_rom(addr, val),
+            0x8000..=0x9fff => self.ppu.write_vram(addr, val),
+            0xa000..=0xbfff => self.cartridge.write_ram(addr, val),
+            0xc000..=0xfdff => {
+                let i = self.wram_index(addr);
+                self.wram[i] = val;
+            }
+            0xfe00..=0xfe9f => self.ppu.oam[(addr - 0xfe00) as usize] = val,
+            0xfea0..=0xfeff => (),
+            0xff00 => self.joypad.write(val, &mut self.interrupts),
+            0xff01..=0xff02 => self.serial.write(addr, val),
+            0xff04..=0xff07 => self.timer.write(addr, val),
+            0xff0f => self.interrupts.write_flags(val),
+            0xff10..=0xff26 | 0xff30..=0xff3f => self.apu.write(addr, val),
+            0xff40..=0xff45 | 0xff47..=0xff4b => self.ppu.write(addr, val),
+            0xff46 => self.dma.write(val),
+            0xff4d if self.cgb() => self.speed_switch_armed = (val & 0x01) != 0,
+            0xff4f | 0xff68..=0xff6b => self.ppu.write(addr, val),
+            0xff51..=0xff55 if self.cgb() => {
+                for _ in 0..self.hdma.write(addr, val) {
+                    self.copy_hdma_block();
+                }
+            }
+            0xff70 if self.cgb() => self.wram_bank = ((val & 0x07) as usize).max(1),
+            0xff50 if val != 0 => self.boot_rom = None,
+            0xff03..=0xff7f => self.io[(addr - 0xff00) as usize] = val,
+            0xff80..=0xfffe => self.hram[(addr - 0xff80) as usize] = val,
+            0xffff => self.interrupts.enable = val,
+        }
+    }
+
+    // Loading bypasses the mapper, so data below 0x8000 goes straight into
+    // the cartridge ROM image, or is loaded into whatever is in the slot.
+    fn load(&mut self, base: u16, data: &[u8]) {
+        for (i, &val) in data.iter().enumerate() {
+            let addr = base.wrapping_add(i as u16);
+            match addr {
+                0x0000..=0x7fff if self.slot.is_some() => {
+                    self.slot.as_mut().unwrap().load(addr, &[val])
+                }
+                0x0000..=0x7fff => {
+                    let rom = self.cartridge.rom_mut();
+                    if rom.len() <= addr as usize {
+                        rom.resize(addr as usize + 1, 0);
+                    }
+                    rom[addr as usize] = val;
+                }
+                _ => self.set_byte(addr, val),
+            }
+        }
+    }
+
+    // The I/O registers live in their devices rather than in memory, so they
+    // can't be viewed
+    fn view(&self, start: u16, end: u16) -> &[u8] {
+        let (s, e) = (start as usize, end as usize);
+        match (start, end) {
+            (0x0000..=0x7fff, 0x0000..=0x7fff) | (0xa000..=0xbfff, 0xa000..=0xbfff)
+                if self.slot.is_some() =>
+            {
+                self.slot.as_ref().unwrap().view(start, end)
+            }
+            (0x0000..=0x3fff, 0x0000..=0x3fff) => &self.cartridge.rom()[s..=e],
+            (0x8000..=0x9fff, 0x8000..=0x9fff) => {
+                let bank = self.ppu.vram_bank() * 0x2000;
+                &self.ppu.vram[(bank + s - 0x8000)..=(bank + e - 0x8000)]
+            }
+            (0xc000..=0xdfff, 0xc000..=0xdfff) if start >= 0xd000 || end < 0xd000 => {
+                &self.wram[self.wram_index(start)..=self.wram_index(end)]
+            }
+            (0xc000..=0xdfff, 0xc000..=0xdfff) if self.wram_bank == 1 => {
+                &self.wram[(s - 0xc000)..=(e - 0xc000)]
+            }
+            (0xfe00..=0xfe9f, 0xfe00..=0xfe9f) => &self.ppu.oam[(s - 0xfe00)..=(e - 0xfe00)],
+            (0xff80..=0xfffe, 0xff80..=0xfffe) => &self.hram[(s - 0xff80)..=(e - 0xff80)],
+            _ => panic!("Cannot view 0x{:04x}..0x{:04x} as one region", start, end),
+        }
+    }
+}
+
+// There's no separate port space, so ports are the 0xff00 page that LDH
+// reaches
+impl Bus for MemoryGbz80 {
+    fn input(&mut self, port: u8) -> u8 {
+        self.get_byte(0xff00 | u16::from(port))
+    }
+
+    fn output(&mut self, port: u8, val: u8) {
+        self.set_byte(0xff00 | u16::from(port), val);
+    }
+
+    // Ignores IME, which belongs to the CPU
+    fn acknowledge_interrupt(&mut self) -> Option<u8> {
+        let n = self.interrupts.highest_priority()?;
+        self.interrupts.acknowledge(n);
+        Some(n)
+    }
+
+    fn tick(&mut self, cycles: usize) {
+        MemoryGbz80::tick(self, cycles);
+    }
+}
+
+impl fmt::Debug for MemoryGbz80 {
+    fn fmt(&self, _f: &mut fmt::Formatter) -> fmt::Result {
+        Ok(())
+    }
+}
@@ -0,0 +1,577 @@
+use crate::cgb::{ColorPalettes, Model};
+use crate::interrupts::{Interrupts, LCD_STAT, VBLANK};
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+const LINE_DOTS: usize = 456;
+const OAM_SCAN_DOTS: usize = 80;
+const DRAWING_DOTS: usize = 172;
+const LINES: u8 = 154;
+const MAX_SPRITES_PER_LINE: usize = 10;
+
+// Shades 0-3 from lightest to darkest
+pub const DMG_SHADES: [[u8; 4]; 4] = [
+    [0xff, 0xff, 0xff, 0xff],
+    [0xaa, 0xaa, 0xaa, 0xff],
+    [0x55, 0x55, 0x55, 0xff],
+    [0x00, 0x00, 0x00, 0xff],
+];
+
+// The same shades as RGB555
+const DMG_COLORS: [u16; 4] = [0x7fff, 0x56b5, 0x294a, 0x0000];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3,
+}
+
+#[derive(Debug)]
+pub struct Ppu {
+    // Two banks on the CGB, selected by VBK
+    pub vram: [u8; 0x4000],
+    pub oam: [u8; 0xa0],
+    pub lcdc: u8,
+    stat: u8,
+    pub scy: u8,
+    pub scx: u8,
+    ly: u8,
+    pub lyc: u8,
+    pub bgp: u8,
+    pub obp0: u8,
+    pub obp1: u8,
+    pub wy: u8,
+    pub wx: u8,
+    cgb: bool,
+    vram_bank: usize,
+    pub bg_palettes: ColorPalettes,
+    pub obj_palettes: ColorPalettes,
+
+    mode: Mode,
+    dot: usize,
+    drawing_end: usize,
+    window_line: u8,
+    stat_line: bool,
+    line_sprites: Vec<usize>,
+    framebuffer: Vec<u8>,
+    color_framebuffer: Vec<u16>,
+    hblank_entered: bool,
+    frames: u64,
+}
+
+pub fn apply_palette(palette: u8, color: u8) -> u8 {
+    (palette >> (color * 2)) & 0x03
+}
+
+pub fn rgb555_to_rgba(c: u16) -> [u8; 4] {
+    let expand = |c: u16| ((c & 0x1f) << 3 | (c & 0x1f) >> 2) as u8;
+    [expand(c), expand(c >> 5), expand(c >> 10), 0xff]
+}
+
+impl Ppu {
+    pub fn new() -> Ppu {
+        Ppu::with_model(Model::Dmg)
+    }
+
+    pub fn with_model(model: Model) -> Ppu {
+        Ppu {
+            vram: [0; 0x4000],
+            oam: [0; 0xa0],
+            lcdc: 0,
+            stat: 0,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0,
+            obp0: 0,
+            obp1: 0,
+            wy: 0,
+            wx: 0,
+            cgb: model == Model::Cgb,
+            vram_bank: 0,
+            bg_palettes: ColorPalettes::new(),
+            obj_palettes: ColorPalettes::new(),
+            mode: Mode::HBlank,
+            dot: 0,
+            drawing_end: OAM_SCAN_DOTS + DRAWING_DOTS,
+            window_line: 0,
+            stat_line: false,
+            line_sprites: Vec::with_capacity(MAX_SPRITES_PER_LINE),
+            framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            color_framebuffer: vec![DMG_COLORS[0]; SCREEN_WIDTH * SCREEN_HEIGHT],
+            hblank_entered: false,
+            frames: 0,
+        }
+    }
+
+    pub fn lcd_enabled(&self) -> bool {
+        (self.lcdc & 0x80) != 0
+    }
+
+    pub fn cgb(&self) -> bool {
+        self.cgb
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn ly(&self) -> u8 {
+        self.ly
+    }
+
+    // Number of frames completed, counted at the start of each VBlank
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    // One shade index (0-3) per pixel, row by row. Only meaningful on the
+    // DMG.
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    // One RGB555 colour per pixel, row by row
+    pub fn framebuffer_rgb555(&self) -> &[u16] {
+        &self.color_framebuffer
+    }
+
+    pub fn framebuffer_rgba(&self) -> Vec<u8> {
+        if !self.cgb {
+            return self
+                .framebuffer
+                .iter()
+                .flat_map(|&shade| DMG_SHADES[shade as usize].iter().copied())
+                .collect();
+        }
+        self.color_framebuffer
+            .iter()
+            .flat_map(|&c| rgb555_to_rgba(c))
+            .collect()
+    }
+
+    // Whether an HBlank started since the last call, for HBlank DMA
+    pub fn take_hblank_entered(&mut self) -> bool {
+        std::mem::replace(&mut self.hblank_entered, false)
+    }
+
+    pub fn vram_bank(&self) -> usize {
+        self.vram_bank
+    }
+
+    pub fn read_vram(&self, addr: u16) -> u8 {
+        self.vram[self.vram_bank * 0x2000 + (addr - 0x8000) as usize]
+    }
+
+    pub fn write_vram(&mut self, addr: u16, val: u8) {
+        self.vram[self.vram_bank * 0x2000 + (addr - 0x8000) as usize] = val;
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xff40 => self.lcdc,
+            0xff41 => {
+                let coincidence = ((self.ly == self.lyc) as u8) << 2;
+                0x80 | (self.stat & 0x78) | coincidence | self.mode as u8
+            }
+            0xff42 => self.scy,
+            0xff43 => self.scx,
+            0xff44 => self.ly,
+            0xff45 => self.lyc,
+            0xff47 => self.bgp,
+            0xff48 => self.obp0,
+            0xff49 => self.obp1,
+            0xff4a => self.wy,
+            0xff4b => self.wx,
+            0xff4f if self.cgb => 0xfe | self.vram_bank as u8,
+            0xff68 if self.cgb => self.bg_palettes.read_index(),
+            0xff69 if self.cgb => self.bg_palettes.read_data(),
+            0xff6a if self.cgb => self.obj_palettes.read_index(),
+            0xff6b if self.cgb => self.obj_palettes.read_data(),
+            _ => 0xff,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xff40 => self.write_lcdc(val),
+            0xff41 => self.stat = val & 0x78,
+            0xff42 => self.scy = val,
+            0xff43 => self.scx = val,
+            0xff44 => (),
+            0xff45 => self.lyc = val,
+            0xff47 => self.bgp = val,
+            0xff48 => self.obp0 = val,
+            0xff49 => self.obp1 = val,
+            0xff4a => self.wy = val,
+            0xff4b => self.wx = val,
+            0xff4f if self.cgb => self.vram_bank = (val & 0x01) as usize,
+            0xff68 if self.cgb => self.bg_palettes.write_index(val),
+            0xff69 if self.cgb => self.bg_palettes.write_data(val),
+            0xff6a if self.cgb => self.obj_palettes.write_index(val),
+            0xff6b if self.cgb => self.obj_palettes.write_data(val),
+            _ => (),
+        }
+    }
+
+    // Turning the LCD off resets LY and the mode; turning it back on starts
+    // a fresh frame
+    fn write_lcdc(&mut self, val: u8) {
+        let was_enabled = self.lcd_enabled();
+        self.lcdc = val;
+        if was_enabled && !self.lcd_enabled() {
+            self.ly = 0;
+            self.dot = 0;
+            self.mode = Mode::HBlank;
+            self.window_line = 0;
+        } else if !was_enabled && self.lcd_enabled() {
+            self.ly = 0;
+            self.dot = 0;
+            self.mode = Mode::OamScan;
+        }
+    }
+
+    // Save states only record LY, so the line starts over from its first dot
+    pub fn restore_line(&mut self, ly: u8) {
+        if !self.lcd_enabled() {
+            return;
+        }
+        self.ly = ly % LINES;
+        self.dot = 0;
+        self.mode = if self.ly as usize >= SCREEN_HEIGHT {
+            Mode::VBlank
+        } else {
+            Mode::OamScan
+        };
+    }
+
+    pub fn tick(&mut self, cycles: usize, interrupts: &mut Interrupts) {
+        if !self.lcd_enabled() {
+            return;
+        }
+        for _ in 0..cycles {
+            self.step(interrupts);
+        }
+    }
+
+    fn step(&mut self, interrupts: &mut Interrupts) {
+        self.dot += 1;
+
+        if self.ly < SCREEN_HEIGHT as u8 {
+            if self.dot == OAM_SCAN_DOTS {
+                self.scan_oam();
+                self.drawing_end = OAM_SCAN_DOTS
+                    + DRAWING_DOTS
+                    + (self.scx & 0x07) as usize
+                    + 6 * self.line_sprites.len();
+                self.mode = Mode::Drawing;
+            } else if self.dot == self.drawing_end {
+                self.render_line();
+                self.mode = Mode::HBlank;
+                self.hblank_entered = true;
+            }
+        }
+
+        if self.dot == LINE_DOTS {
+            self.dot = 0;
+            self.ly = (self.ly + 1) % LINES;
+            if self.ly == SCREEN_HEIGHT as u8 {
+                self.mode = Mode::VBlank;
+                self.window_line = 0;
+                self.frames += 1;
+                interrupts.request(VBLANK);
+            } else if self.ly < SCREEN_HEIGHT as u8 {
+                self.mode = Mode::OamScan;
+            }
+        }
+
+        self.update_stat_line(interrupts);
+    }
+
+    // The STAT interrupt fires on the rising edge of the OR of all enabled
+    // sources, so overlapping sources only trigger once
+    fn update_stat_line(&mut self, interrupts: &mut Interrupts) {
+        let line = (self.stat & 0x40 != 0 && self.ly == self.lyc)
+            || (self.stat & 0x08 != 0 && self.mode == Mode::HBlank)
+            || (self.stat & 0x10 != 0 && self.mode == Mode::VBlank)
+            || (self.stat & 0x20 != 0 && self.mode == Mode::OamScan);
+        if line && !self.stat_line {
+            interrupts.request(LCD_STAT);
+        }
+        self.stat_line = line;
+    }
+
+    pub fn sprite_height(&self) -> u8 {
+        if (self.lcdc & 0x04) != 0 {
+            16
+        } else {
+            8
+        }
+    }
+
+    // Picks the first ten sprites in OAM order that cover this line
+    fn scan_oam(&mut self) {
+        let height = i16::from(self.sprite_height());
+        let ly = i16::from(self.ly);
+        self.line_sprites.clear();
+        for i in 0..40 {
+            let y = i16::from(self.oam[i * 4]) - 16;
+            if ly >= y && ly < y + height {
+                self.line_sprites.push(i);
+                if self.line_sprites.len() == MAX_SPRITES_PER_LINE {
+                    break;
+                }
+            }
+        }
+    }
+
+    pub fn tile_row(&self, bank: usize, tile_addr: usize, row: usize) -> (u8, u8) {
+        let addr = bank * 0x2000 + (tile_addr - 0x8000) + row * 2;
+        (self.vram[addr], self.vram[addr + 1])
+    }
+
+    pub fn tile_color(row: (u8, u8), bit: usize) -> u8 {
+        ((row.1 >> bit) & 0x01) << 1 | ((row.0 >> bit) & 0x01)
+    }
+
+    // Colour index and CGB attributes at (x, y) within the 256x256 map
+    // selected by high_map. The attributes live in VRAM bank 1.
+    pub fn map_pixel(&self, high_map: bool, x: usize, y: usize) -> (u8, u8) {
+        let map = if high_map { 0x1c00 } else { 0x1800 } + (y / 8) * 32 + x / 8;
+        let tile = self.vram[map];
+        let attributes = if self.cgb { self.vram[0x2000 + map] } else { 0 };
+        let tile_addr = if (self.lcdc & 0x10) != 0 {
+            0x8000 + tile as usize * 16
+        } else {
+            (0x9000 + i32::from(tile as i8) * 16) as usize
+        };
+        let mut row = y % 8;
+        if (attributes & 0x40) != 0 {
+            row = 7 - row;
+        }
+        let mut bit = 7 - x % 8;
+        if (attributes & 0x20) != 0 {
+            bit = 7 - bit;
+        }
+        let bank = ((attributes >> 3) & 0x01) as usize;
+        let color = Self::tile_color(self.tile_row(bank, tile_addr, row), bit);
+        (color, attributes)
+    }
+
+    fn render_line(&mut self) {
+        let ly = self.ly as usize;
+        let mut bg = [(0u8, 0u8); SCREEN_WIDTH];
+
+        // On the CGB LCDC bit 0 only takes priority away from the background
+        let bg_enabled = self.cgb || (self.lcdc & 0x01) != 0;
+        if bg_enabled {
+            let window_x = self.wx as usize;
+            let window_visible =
+                (self.lcdc & 0x20) != 0 && self.ly >= self.wy && window_x < SCREEN_WIDTH + 7;
+            for (x, pixel) in bg.iter_mut().enumerate() {
+                *pixel = if window_visible && x + 7 >= window_x {
+                    self.map_pixel(
+                        (self.lcdc & 0x40) != 0,
+                        x + 7 - window_x,
+                        self.window_line as usize,
+                    )
+                } else {
+                    self.map_pixel(
+                        (self.lcdc & 0x08) != 0,
+                        (x + self.scx as usize) & 0xff,
+                        (ly + self.scy as usize) & 0xff,
+                    )
+                };
+            }
+            if window_visible {
+                self.window_line += 1;
+            }
+        }
+
+        let mut shades = [0u8; SCREEN_WIDTH];
+        let mut colors = [DMG_COLORS[0]; SCREEN_WIDTH];
+        for (x, &(color, attributes)) in bg.iter().enumerate() {
+            if self.cgb {
+                colors[x] = self.bg_palettes.color(attributes & 0x07, color);
+            } else if bg_enabled {
+                shades[x] = apply_palette(self.bgp, color);
+                colors[x] = DMG_COLORS[shades[x] as usize];
+            }
+        }
+
+        if (self.lcdc & 0x02) != 0 {
+            self.render_sprites(&mut shades, &mut colors, &bg);
+        }
+
+        let row = ly * SCREEN_WIDTH..(ly + 1) * SCREEN_WIDTH;
+        self.framebuffer[row.clone()].copy_from_slice(&shades);
+        self.color_framebuffer[row].copy_from_slice(&colors);
+    }
+
+    // On the DMG the sprite with the smallest X wins, then the earliest in
+    // OAM; on the CGB only OAM order counts. The winning sprite is hidden
+    // behind BG colours 1-3 when its priority bit is set, even if a lower
+    // priority sprite is underneath. CGB tiles can claim the same priority
+    // through their attributes unless LCDC bit 0 is clear.
+    fn render_sprites(&self, shades: &mut [u8], colors: &mut [u16], bg: &[(u8, u8)]) {
+        let mut sprites = self.line_sprites.clone();
+        if !self.cgb {
+            sprites.sort_by_key(|&i| (self.oam[i * 4 + 1], i));
+        }
+
+        let height = self.sprite_height() as usize;
+        for x in 0..SCREEN_WIDTH {
+            for &i in sprites.iter() {
+                let sprite = &self.oam[i * 4..i * 4 + 4];
+                let sprite_x = sprite[1] as usize;
+                if x + 8 < sprite_x || x + 8 >= sprite_x + 8 {
+                    continue;
+                }
+                let attributes = sprite[3];
+                let mut row = (self.ly as usize + 16) - sprite[0] as usize;
+                if (attributes & 0x40) != 0 {
+                    row = height - 1 - row;
+                }
+                let mut tile = sprite[2] as usize;
+                if height == 16 {
+                    tile &= 0xfe;
+                }
+                let mut bit = 7 - (x + 8 - sprite_x);
+                if (attributes & 0x20) != 0 {
+                    bit = 7 - bit;
+                }
+                let bank = if self.cgb {
+                    ((attributes >> 3) & 0x01) as usize
+                } else {
+                    0
+                };
+                let color = Self::tile_color(self.tile_row(bank, 0x8000 + tile * 16, row), bit);
+                if color == 0 {
+                    continue;
+                }
+                let (bg_color, bg_attributes) = bg[x];
+                let bg_wins = bg_color != 0
+                    && ((attributes | bg_attributes) & 0x80) != 0
+                    && (!self.cgb || (self.lcdc & 0x01) != 0);
+                if !bg_wins {
+                    if self.cgb {
+                        colors[x] = self.obj_palettes.color(attributes & 0x07, color);
+                    } else {
+                        let palette = if (attributes & 0x10) != 0 {
+                            self.obp1
+                        } else {
+                            self.obp0
+                        };
+                        shades[x] = apply_palette(palette, color);
+                        colors[x] = DMG_COLORS[shades[x] as usize];
+                    }
+                }
+                break;
+            }
+        }
+    }
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_lines(ppu: &mut Ppu, interrupts: &mut Interrupts, lines: usize) {
+        ppu.tick(lines * LINE_DOTS, interrupts);
+    }
+
+    #[test]
+    fn mode_timing() {
+        let mut ppu = Ppu::new();
+        let mut interrupts = Interrupts::new();
+        ppu.write(0xff40, 0x91);
+
+        assert_eq!(ppu.mode(), Mode::OamScan);
+        ppu.tick(OAM_SCAN_DOTS, &mut interrupts);
+        assert_eq!(ppu.mode(), Mode::Drawing);
+        ppu.tick(DRAWING_DOTS, &mut interrupts);
+        assert_eq!(ppu.mode(), Mode::HBlank);
+        ppu.tick(LINE_DOTS - OAM_SCAN_DOTS - DRAWING_DOTS, &mut interrupts);
+        assert_eq!(ppu.ly(), 1);
+
+        run_lines(&mut ppu, &mut interrupts, 143);
+        assert_eq!(ppu.mode(), Mode::VBlank);
+        assert_eq!(interrupts.flags & VBLANK, VBLANK);
+        assert_eq!(ppu.frames(), 1);
+
+        run_lines(&mut ppu, &mut interrupts, 10);
+        assert_eq!(ppu.ly(), 0);
+        assert_eq!(ppu.mode(), Mode::OamScan);
+    }
+
+    #[test]
+    fn lyc_stat_interrupt() {
+        let mut ppu = Ppu::new();
+        let mut interrupts = Interrupts::new();
+        ppu.write(0xff40, 0x91);
+        ppu.write(0xff41, 0x40);
+        ppu.write(0xff45, 3);
+
+        run_lines(&mut ppu, &mut interrupts, 2);
+        assert_eq!(interrupts.flags & LCD_STAT, 0);
+        run_lines(&mut ppu, &mut interrupts, 1);
+        assert_eq!(interrupts.flags & LCD_STAT, LCD_STAT);
+        assert_eq!(ppu.read(0xff41) & 0x04, 0x04);
+    }
+
+    #[test]
+    fn renders_background_and_sprite() {
+        let mut ppu = Ppu::new();
+        let mut interrupts = Interrupts::new();
+        // Tile 1 is solid colour 3, tile 2 has its left column in colour 1
+        for row in 0..8 {
+            ppu.vram[0x10 + row * 2] = 0xff;
+            ppu.vram[0x10 + row * 2 + 1] = 0xff;
+            ppu.vram[0x20 + row * 2] = 0x80;
+        }
+        ppu.vram[0x1800] = 1;
+        ppu.oam[0..4].copy_from_slice(&[16, 16, 2, 0x00]);
+        ppu.bgp = 0xe4;
+        ppu.obp0 = 0xe4;
+        ppu.write(0xff40, 0x93);
+
+        run_lines(&mut ppu, &mut interrupts, 1);
+        let line = &ppu.framebuffer()[0..SCREEN_WIDTH];
+        assert_eq!(line[0], 3);
+        assert_eq!(line[7], 3);
+        assert_eq!(line[8], 1);
+        assert_eq!(line[9], 0);
+    }
+
+    #[test]
+    fn cgb_attributes_select_bank_and_palette() {
+        let mut ppu = Ppu::with_model(Model::Cgb);
+        let mut interrupts = Interrupts::new();
+        // Tile 0 in bank 1 has only its rightmost column in colour 1
+        for row in 0..8 {
+            ppu.vram[0x2000 + row * 2] = 0x01;
+        }
+        // Map entry 0 uses bank 1 and palette 2, flipped horizontally
+        ppu.vram[0x3800] = 0x2a;
+        ppu.write(0xff68, 0x92);
+        ppu.write(0xff69, 0x1f);
+        ppu.write(0xff69, 0x00);
+        ppu.write(0xff40, 0x91);
+
+        run_lines(&mut ppu, &mut interrupts, 1);
+        let line = &ppu.framebuffer_rgb555()[0..SCREEN_WIDTH];
+        assert_eq!(line[0], 0x001f);
+        assert_eq!(line[1], 0x7fff);
+        assert_eq!(&ppu.framebuffer_rgba()[0..4], &[0xff, 0x00, 0x00, 0xff]);
+    }
+}
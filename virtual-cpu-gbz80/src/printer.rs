@@ -0,0 +1,373 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::serial::SerialDevice;
+
+pub const PRINT_WIDTH: usize = 160;
+
+const MAGIC: [u8; 2] = [0x88, 0x33];
+const BAND_BYTES: usize = 20 * 2 * 16;
+const GRAYS: [u8; 4] = [0xff, 0xaa, 0x55, 0x00];
+
+pub const COMMAND_INIT: u8 = 0x01;
+pub const COMMAND_PRINT: u8 = 0x02;
+pub const COMMAND_DATA: u8 = 0x04;
+pub const COMMAND_STATUS: u8 = 0x0f;
+
+pub const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+pub const STATUS_UNPROCESSED_DATA: u8 = 0x08;
+
+// A printed sheet, one grayscale byte per pixel
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrintedImage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl PrintedImage {
+    pub fn write_pgm<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        write!(file, "P5\n{} {}\n255\n", self.width, self.height)?;
+        file.write_all(&self.pixels)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Receiving {
+    Magic(usize),
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+// Packets are 0x88 0x33, command, compression, a little-endian length, the
+// data, and a little-endian checksum of everything after the magic bytes.
+// The printer answers the two trailing bytes with 0x81 and its status.
+#[derive(Debug)]
+pub struct Printer {
+    receiving: Receiving,
+    command: u8,
+    compressed: bool,
+    length: usize,
+    data: Vec<u8>,
+    checksum: u16,
+    status: u8,
+    buffer: Vec<u8>,
+    images: Vec<PrintedImage>,
+    output_dir: Option<PathBuf>,
+    error: Option<io::Error>,
+}
+
+impl Printer {
+    pub fn new() -> Printer {
+        Printer {
+            receiving: Receiving::Magic(0),
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            status: 0,
+            buffer: Vec::new(),
+            images: Vec::new(),
+            output_dir: None,
+            error: None,
+        }
+    }
+
+    // Writes each printed image to the directory as print-NNN.pgm
+    pub fn with_output_dir<P: Into<PathBuf>>(mut self, dir: P) -> Printer {
+        self.output_dir = Some(dir.into());
+        self
+    }
+
+    pub fn images(&self) -> &[PrintedImage] {
+        &self.images
+    }
+
+    pub fn take_images(&mut self) -> Vec<PrintedImage> {
+        std::mem::take(&mut self.images)
+    }
+
+    pub fn status(&self) -> u8 {
+        self.status
+    }
+
+    // The printer can't report a failed write through the link cable, so
+    // the last one is kept until the caller collects it
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    fn receive_byte(&mut self, byte: u8) -> u8 {
+        let mut response = 0x00;
+        self.receiving = match self.receiving {
+            Receiving::Magic(i) if byte == MAGIC[i] => {
+                if i + 1 == MAGIC.len() {
+                    self.checksum = 0;
+                    Receiving::Command
+                } else {
+                    Receiving::Magic(i + 1)
+                }
+            }
+            // A stray 0x88 may be followed by the real start of a packet
+            Receiving::Magic(_) if byte == MAGIC[0] => Receiving::Magic(1),
+            Receiving::Magic(_) => Receiving::Magic(0),
+            Receiving::Command => {
+                self.command = byte;
+                self.checksum = u16::from(byte);
+                Receiving::Compression
+            }
+            Receiving::Compression => {
+                self.compressed = (byte & 0x01) != 0;
+                self.checksum = self.checksum.wrapping_add(u16::from(byte));
+                Receiving::LengthLow
+            }
+            Receiving::LengthLow => {
+                self.length = byte as usize;
+                self.checksum = self.checksum.wrapping_add(u16::from(byte));
+                Receiving::LengthHigh
+            }
+            Receiving::LengthHigh => {
+                self.length |= (byte as usize) << 8;
+                self.checksum = self.checksum.wrapping_add(u16::from(byte));
+                self.data.clear();
+                if self.length == 0 {
+                    Receiving::ChecksumLow
+                } else {
+                    Receiving::Data
+                }
+            }
+            Receiving::Data => {
+                self.data.push(byte);
+                self.checksum = self.checksum.wrapping_add(u16::from(byte));
+                if self.data.len() == self.length {
+                    Receiving::ChecksumLow
+                } else {
+                    Receiving::Data
+                }
+            }
+            Receiving::ChecksumLow => {
+                self.checksum ^= u16::from(byte);
+                Receiving::ChecksumHigh
+            }
+            Receiving::ChecksumHigh => {
+                self.checksum ^= u16::from(byte) << 8;
+                if self.checksum == 0 {
+                    self.status &= !STATUS_CHECKSUM_ERROR;
+                    self.execute();
+                } else {
+                    self.status |= STATUS_CHECKSUM_ERROR;
+                }
+                Receiving::Alive
+            }
+            Receiving::Alive => {
+                response = 0x81;
+                Receiving::Status
+            }
+            Receiving::Status => {
+                response = self.status;
+                Receiving::Magic(0)
+            }
+        };
+        response
+    }
+
+    fn execute(&mut self) {
+        match self.command {
+            COMMAND_INIT => {
+                self.buffer.clear();
+                self.status = 0;
+            }
+            COMMAND_DATA => {
+                let data = if self.compressed {
+                    decompress(&self.data)
+                } else {
+                    self.data.clone()
+                };
+                self.buffer.extend_from_slice(&data);
+                if !self.buffer.is_empty() {
+                    self.status |= STATUS_UNPROCESSED_DATA;
+                }
+            }
+            COMMAND_PRINT => {
+                let palette = match self.data.get(2) {
+                    Some(&0) | None => 0xe4,
+                    Some(&palette) => palette,
+                };
+                self.print(palette);
+            }
+            _ => (),
+        }
+    }
+
+    fn print(&mut self, palette: u8) {
+        let image = render(&self.buffer, palette);
+        self.buffer.clear();
+        self.status &= !STATUS_UNPROCESSED_DATA;
+        if let Some(dir) = &self.output_dir {
+            let path = dir.join(format!("print-{:03}.pgm", self.images.len()));
+            if let Err(e) = image.write_pgm(&path) {
+                let message = format!("could not write {}: {}", path.display(), e);
+                self.error = Some(io::Error::new(e.kind(), message));
+            }
+        }
+        self.images.push(image);
+    }
+}
+
+impl Default for Printer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SerialDevice for Printer {
+    fn exchange(&mut self, out: u8) -> u8 {
+        self.receive_byte(out)
+    }
+}
+
+// A control byte with the top bit set repeats the next byte (n & 0x7f) + 2
+// times, otherwise the next n + 1 bytes are copied as is
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+        if (control & 0x80) != 0 {
+            if let Some(&byte) = data.get(i) {
+                out.extend(std::iter::repeat_n(byte, (control & 0x7f) as usize + 2));
+            }
+            i += 1;
+        } else {
+            let end = (i + control as usize + 1).min(data.len());
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+    out
+}
+
+// The buffer holds bands of 20x2 tiles in 2bpp tile format
+fn render(buffer: &[u8], palette: u8) -> PrintedImage {
+    let bands = buffer.len() / BAND_BYTES;
+    let height = bands * 16;
+    let mut pixels = vec![GRAYS[0]; PRINT_WIDTH * height];
+    for (tile_index, tile) in buffer[..bands * BAND_BYTES].chunks(16).enumerate() {
+        let band = tile_index / 40;
+        let tile_row = (tile_index % 40) / 20;
+        let tile_col = tile_index % 20;
+        for row in 0..8 {
+            let (low, high) = (tile[row * 2], tile[row * 2 + 1]);
+            let y = band * 16 + tile_row * 8 + row;
+            for col in 0..8 {
+                let bit = 7 - col;
+                let color = ((high >> bit) & 0x01) << 1 | ((low >> bit) & 0x01);
+                let shade = (palette >> (color * 2)) & 0x03;
+                pixels[y * PRINT_WIDTH + tile_col * 8 + col] = GRAYS[shade as usize];
+            }
+        }
+    }
+    PrintedImage {
+        width: PRINT_WIDTH,
+        height,
+        pixels,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send_packet(printer: &mut Printer, command: u8, compressed: bool, data: &[u8]) -> u8 {
+        let mut body = vec![
+            command,
+            compressed as u8,
+            data.len() as u8,
+            (data.len() >> 8) as u8,
+        ];
+        body.extend_from_slice(data);
+        let checksum = body
+            .iter()
+            .fold(0u16, |sum, &b| sum.wrapping_add(u16::from(b)));
+        let mut packet = MAGIC.to_vec();
+        packet.extend_from_slice(&body);
+        packet.extend_from_slice(&[checksum as u8, (checksum >> 8) as u8]);
+        for &byte in packet.iter() {
+            assert_eq!(printer.exchange(byte), 0x00);
+        }
+        assert_eq!(printer.exchange(0x00), 0x81);
+        printer.exchange(0x00)
+    }
+
+    #[test]
+    fn prints_compressed_band() {
+        let mut printer = Printer::new();
+        assert_eq!(send_packet(&mut printer, COMMAND_INIT, false, &[]), 0x00);
+        // Every tile row is 0xff 0x00, colour 1
+        let data = [0xff, 0x00].repeat(BAND_BYTES / 2);
+        let compressed: Vec<u8> = data
+            .chunks(2)
+            .flat_map(|_| vec![0x01, 0xff, 0x00])
+            .collect();
+        let status = send_packet(&mut printer, COMMAND_DATA, true, &compressed);
+        assert_eq!(status, STATUS_UNPROCESSED_DATA);
+        send_packet(&mut printer, COMMAND_DATA, false, &[]);
+        let status = send_packet(&mut printer, COMMAND_PRINT, false, &[1, 0x13, 0xe4, 0x40]);
+        assert_eq!(status, 0x00);
+
+        let image = &printer.images()[0];
+        assert_eq!((image.width, image.height), (160, 16));
+        assert!(image.pixels.iter().all(|&p| p == 0xaa));
+        assert!(printer.take_error().is_none());
+    }
+
+    #[test]
+    fn keeps_write_errors() {
+        let dir = std::env::temp_dir().join("gbz80-printer-missing/nested");
+        let mut printer = Printer::new().with_output_dir(&dir);
+        send_packet(&mut printer, COMMAND_DATA, false, &[0; BAND_BYTES]);
+        send_packet(&mut printer, COMMAND_PRINT, false, &[1, 0x13, 0xe4, 0x40]);
+        assert_eq!(printer.images().len(), 1);
+        let error = printer.take_error().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(error.to_string().contains("print-000.pgm"));
+        assert!(printer.take_error().is_none());
+    }
+
+    #[test]
+    fn bad_checksum_sets_status() {
+        let mut printer = Printer::new();
+        for &byte in [0x88, 0x33, COMMAND_INIT, 0, 0, 0, 0x02, 0x00].iter() {
+            printer.exchange(byte);
+        }
+        assert_eq!(printer.exchange(0x00), 0x81);
+        assert_eq!(printer.exchange(0x00), STATUS_CHECKSUM_ERROR);
+    }
+
+    #[test]
+    fn resyncs_on_repeated_magic() {
+        let mut printer = Printer::new();
+        printer.exchange(0x88);
+        send_packet(&mut printer, COMMAND_DATA, false, &[0; BAND_BYTES]);
+        assert_eq!(printer.status(), STATUS_UNPROCESSED_DATA);
+    }
+
+    #[test]
+    fn run_length_decoding() {
+        assert_eq!(
+            decompress(&[0x81, 0xaa, 0x01, 0x01, 0x02]),
+            vec![0xaa, 0xaa, 0xaa, 0x01, 0x02]
+        );
+    }
+}
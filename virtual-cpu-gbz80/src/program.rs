@@ -0,0 +1,119 @@
+use virtual_cpu_8080::instructions::apply_offset;
+use virtual_cpu_core::{Memory, Program, Stack};
+
+use crate::memory::MemoryGbz80;
+use crate::stack::StackGbz80;
+
+static INSTRUCTION_LENGTH: [u16; 256] = [
+    1, 3, 1, 1, 1, 1, 2, 1, 3, 1, 1, 1, 1, 1, 2, 1, // 0x00..0x0f
+    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 0x10..0x1f
+    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 0x20..0x2f
+    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 0x30..0x3f
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x40..0x4f
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x50..0x5f
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x60..0x6f
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x70..0x7f
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x80..0x8f
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x90..0x9f
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0xa0..0xaf
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0xb0..0xbf
+    1, 1, 3, 3, 3, 1, 2, 1, 1, 1, 3, 2, 3, 3, 2, 1, // 0xc0..0xcf
+    1, 1, 3, 1, 3, 1, 2, 1, 1, 1, 3, 1, 3, 1, 2, 1, // 0xd0..0xdf
+    2, 1, 1, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1, // 0xe0..0xef
+    2, 1, 1, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1, // 0xf0..0xff
+];
+
+#[derive(Default, Debug)]
+pub struct ProgramGbz80 {
+    pc: u16,
+    instruction_length: u16,
+    jumped: bool,
+    halt_bug: bool,
+}
+
+impl ProgramGbz80 {
+    pub fn new() -> ProgramGbz80 {
+        ProgramGbz80::default()
+    }
+
+    // Whether the current instruction has transferred control
+    pub fn jumped(&self) -> bool {
+        self.jumped
+    }
+
+    // The next fetch fails to increment PC after the opcode, so the opcode
+    // byte is read again as the first operand byte
+    pub fn repeat_next_byte(&mut self) {
+        self.halt_bug = true;
+    }
+
+    // The address after the instruction, where relative jumps are taken
+    // from and calls return to
+    pub fn next_pc(&self) -> u16 {
+        self.pc.wrapping_add(self.instruction_length)
+    }
+
+    pub fn jr(&mut self, offset: u8) {
+        self.jump(apply_offset(self.next_pc(), offset));
+    }
+
+    // An instruction is fetched a byte at a time: the opcode gives the
+    // length, then each operand byte comes from operand_address
+    pub fn start_fetch(&mut self, opcode: u8) -> u16 {
+        self.instruction_length = INSTRUCTION_LENGTH[opcode as usize];
+        self.jumped = false;
+        self.instruction_length
+    }
+
+    pub fn operand_address(&self, i: u16) -> u16 {
+        self.pc.wrapping_add(i - self.halt_bug as u16)
+    }
+
+    pub fn end_fetch(&mut self) {
+        if self.halt_bug {
+            self.halt_bug = false;
+            self.instruction_length -= 1;
+        }
+    }
+}
+
+impl Program for ProgramGbz80 {
+    type Address = u16;
+    type Mem = MemoryGbz80;
+    type Stk = StackGbz80;
+
+    fn get_pc(&self) -> u16 {
+        self.pc
+    }
+
+    // The instruction may straddle a bank or region boundary, so it is read
+    // a byte at a time rather than through view()
+    fn get_instruction(&mut self, m: &MemoryGbz80) -> Vec<u8> {
+        let opcode = m.get_byte(self.pc);
+        let length = self.start_fetch(opcode);
+        let mut instruction = vec![opcode];
+        instruction.extend((1..length).map(|i| m.get_byte(self.operand_address(i))));
+        self.end_fetch();
+        instruction
+    }
+
+    fn advance(&mut self) {
+        self.pc = self.pc.wrapping_add(self.instruction_length);
+        self.instruction_length = 0;
+    }
+
+    fn jump(&mut self, addr: u16) {
+        self.pc = addr;
+        self.instruction_length = 0;
+        self.jumped = true;
+    }
+
+    fn call(&mut self, m: &mut MemoryGbz80, s: &mut StackGbz80, addr: u16) {
+        s.push_word(m, self.next_pc());
+        self.jump(addr);
+    }
+
+    fn ret(&mut self, m: &mut MemoryGbz80, s: &mut StackGbz80) {
+        self.jump(s.pop_word(m));
+    }
+}
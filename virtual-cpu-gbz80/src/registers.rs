@@ -0,0 +1,87 @@
+use crate::flags::FlagsGbz80;
+use virtual_cpu_core::bytes::*;
+use virtual_cpu_core::{Flags, Registers16, Registers8};
+
+pub use virtual_cpu_8080::registers::{Name16, Name8};
+
+#[derive(Debug, Default)]
+pub struct RegistersGbz80 {
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub cc: FlagsGbz80,
+}
+
+impl RegistersGbz80 {
+    pub fn new() -> RegistersGbz80 {
+        RegistersGbz80::default()
+    }
+}
+
+impl Registers8 for RegistersGbz80 {
+    type Name = Name8;
+
+    fn get8(&self, reg: Name8) -> u8 {
+        match reg {
+            Name8::A => self.a,
+            Name8::B => self.b,
+            Name8::C => self.c,
+            Name8::D => self.d,
+            Name8::E => self.e,
+            Name8::F => self.cc.serialize(),
+            Name8::H => self.h,
+            Name8::L => self.l,
+        }
+    }
+
+    fn set8(&mut self, reg: Name8, val: u8) {
+        match reg {
+            Name8::A => self.a = val,
+            Name8::B => self.b = val,
+            Name8::C => self.c = val,
+            Name8::D => self.d = val,
+            Name8::E => self.e = val,
+            Name8::F => self.cc.deserialize(val),
+            Name8::H => self.h = val,
+            Name8::L => self.l = val,
+        }
+    }
+}
+
+impl Registers16 for RegistersGbz80 {
+    type Name = Name16;
+
+    fn get16(&self, reg: Name16) -> u16 {
+        match reg {
+            Name16::AF => assemble_word(self.a, self.get8(Name8::F)),
+            Name16::BC => assemble_word(self.b, self.c),
+            Name16::DE => assemble_word(self.d, self.e),
+            Name16::HL => assemble_word(self.h, self.l),
+        }
+    }
+
+    fn set16(&mut self, reg: Name16, val: u16) {
+        match reg {
+            Name16::AF => {
+                self.a = high_order_byte(val);
+                self.set8(Name8::F, low_order_byte(val));
+            }
+            Name16::BC => {
+                self.b = high_order_byte(val);
+                self.c = low_order_byte(val);
+            }
+            Name16::DE => {
+                self.d = high_order_byte(val);
+                self.e = low_order_byte(val);
+            }
+            Name16::HL => {
+                self.h = high_order_byte(val);
+                self.l = low_order_byte(val);
+            }
+        }
+    }
+}
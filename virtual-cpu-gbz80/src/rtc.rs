@@ -0,0 +1,281 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const CYCLES_PER_SECOND: usize = 4_194_304;
+
+// Size of the RTC trailer appended to .sav files by most emulators: the
+// current and latched registers as little-endian u32s followed by a u64
+// UNIX timestamp.
+pub const RTC_SAVE_SIZE: usize = 48;
+
+const HALT: u8 = 0x40;
+const DAY_CARRY: u8 = 0x80;
+
+pub trait Clock {
+    fn now(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+impl<F: Fn() -> u64> Clock for F {
+    fn now(&self) -> u64 {
+        self()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct RtcRegisters {
+    pub seconds: u8,
+    pub minutes: u8,
+    pub hours: u8,
+    pub day_low: u8,
+    pub day_high: u8,
+}
+
+impl RtcRegisters {
+    fn get(&self, reg: u8) -> u8 {
+        match reg {
+            0x08 => self.seconds | 0xc0,
+            0x09 => self.minutes | 0xc0,
+            0x0a => self.hours | 0xe0,
+            0x0b => self.day_low,
+            _ => self.day_high | 0x3e,
+        }
+    }
+
+    fn to_bytes(self) -> [u8; 20] {
+        let mut bytes = [0; 20];
+        let values = [
+            self.seconds,
+            self.minutes,
+            self.hours,
+            self.day_low,
+            self.day_high,
+        ];
+        for (i, &v) in values.iter().enumerate() {
+            bytes[i * 4..i * 4 + 4].copy_from_slice(&u32::from(v).to_le_bytes());
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> RtcRegisters {
+        let v = |i: usize| bytes[i * 4];
+        RtcRegisters {
+            seconds: v(0) & 0x3f,
+            minutes: v(1) & 0x3f,
+            hours: v(2) & 0x1f,
+            day_low: v(3),
+            day_high: v(4) & 0xc1,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Rtc {
+    current: RtcRegisters,
+    latched: RtcRegisters,
+    latch_armed: bool,
+    subsecond: usize,
+}
+
+impl Rtc {
+    pub fn new() -> Rtc {
+        Rtc::default()
+    }
+
+    pub fn registers(&self) -> RtcRegisters {
+        self.current
+    }
+
+    pub fn latched(&self) -> RtcRegisters {
+        self.latched
+    }
+
+    pub fn halted(&self) -> bool {
+        (self.current.day_high & HALT) != 0
+    }
+
+    pub fn days(&self) -> u16 {
+        u16::from(self.current.day_high & 0x01) << 8 | u16::from(self.current.day_low)
+    }
+
+    // Writing 0x00 then 0x01 to 0x6000-0x7fff copies the counters into the
+    // registers visible through 0xa000-0xbfff
+    pub fn write_latch(&mut self, val: u8) {
+        if self.latch_armed && val == 0x01 {
+            self.latched = self.current;
+        }
+        self.latch_armed = val == 0x00;
+    }
+
+    pub fn read(&self, reg: u8) -> u8 {
+        self.latched.get(reg)
+    }
+
+    pub fn write(&mut self, reg: u8, val: u8) {
+        match reg {
+            0x08 => {
+                self.current.seconds = val & 0x3f;
+                self.subsecond = 0;
+            }
+            0x09 => self.current.minutes = val & 0x3f,
+            0x0a => self.current.hours = val & 0x1f,
+            0x0b => self.current.day_low = val,
+            _ => self.current.day_high = val & 0xc1,
+        }
+        self.latched = self.current;
+    }
+
+    pub fn tick(&mut self, cycles: usize) {
+        if self.halted() {
+            return;
+        }
+        self.subsecond += cycles;
+        while self.subsecond >= CYCLES_PER_SECOND {
+            self.subsecond -= CYCLES_PER_SECOND;
+            self.advance_second();
+        }
+    }
+
+    pub fn advance_seconds(&mut self, mut seconds: u64) {
+        if self.halted() {
+            return;
+        }
+        while seconds > 0 && !self.in_range() {
+            self.advance_second();
+            seconds -= 1;
+        }
+
+        let days = u64::from(self.days());
+        let r = &mut self.current;
+        let total = days * 86400
+            + u64::from(r.hours) * 3600
+            + u64::from(r.minutes) * 60
+            + u64::from(r.seconds)
+            + seconds;
+        let days = total / 86400;
+        r.seconds = (total % 60) as u8;
+        r.minutes = (total / 60 % 60) as u8;
+        r.hours = (total / 3600 % 24) as u8;
+        r.day_low = days as u8;
+        r.day_high = (r.day_high & !0x01) | ((days >> 8) & 0x01) as u8;
+        if days > 0x1ff {
+            r.day_high |= DAY_CARRY;
+        }
+    }
+
+    fn in_range(&self) -> bool {
+        let r = &self.current;
+        r.seconds < 60 && r.minutes < 60 && r.hours < 24
+    }
+
+    // Counters wrap at their bit width rather than their modulus when set
+    // out of range, and only carry when passing through the real limit
+    fn advance_second(&mut self) {
+        let r = &mut self.current;
+        r.seconds = (r.seconds + 1) & 0x3f;
+        if r.seconds != 60 {
+            return;
+        }
+        r.seconds = 0;
+        r.minutes = (r.minutes + 1) & 0x3f;
+        if r.minutes != 60 {
+            return;
+        }
+        r.minutes = 0;
+        r.hours = (r.hours + 1) & 0x1f;
+        if r.hours != 24 {
+            return;
+        }
+        r.hours = 0;
+        let (day_low, carry) = r.day_low.overflowing_add(1);
+        r.day_low = day_low;
+        if carry {
+            if (r.day_high & 0x01) != 0 {
+                r.day_high = (r.day_high & !0x01) | DAY_CARRY;
+            } else {
+                r.day_high |= 0x01;
+            }
+        }
+    }
+
+    pub fn save_data(&self, now: u64) -> [u8; RTC_SAVE_SIZE] {
+        let mut bytes = [0; RTC_SAVE_SIZE];
+        bytes[0..20].copy_from_slice(&self.current.to_bytes());
+        bytes[20..40].copy_from_slice(&self.latched.to_bytes());
+        bytes[40..48].copy_from_slice(&now.to_le_bytes());
+        bytes
+    }
+
+    // Restores the registers and catches up on the time elapsed since the
+    // save was written. Older saves use a 32-bit timestamp.
+    pub fn load_save_data(&mut self, bytes: &[u8], now: u64) {
+        if bytes.len() < 44 {
+            return;
+        }
+        self.current = RtcRegisters::from_bytes(&bytes[0..20]);
+        self.latched = RtcRegisters::from_bytes(&bytes[20..40]);
+        let mut timestamp = [0; 8];
+        let len = bytes.len().min(48) - 40;
+        timestamp[..len].copy_from_slice(&bytes[40..40 + len]);
+        let saved_at = u64::from_le_bytes(timestamp);
+        self.advance_seconds(now.saturating_sub(saved_at));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_and_latches() {
+        let mut rtc = Rtc::new();
+        rtc.write(0x08, 59);
+        rtc.write(0x09, 59);
+        rtc.write(0x0a, 23);
+        rtc.write(0x0b, 0xff);
+        rtc.write(0x0c, 0x01);
+
+        rtc.tick(CYCLES_PER_SECOND - 1);
+        assert_eq!(rtc.registers().seconds, 59);
+        rtc.tick(1);
+        assert_eq!(rtc.registers().seconds, 0);
+        assert_eq!(rtc.registers().hours, 0);
+        assert_eq!(rtc.days(), 0);
+        assert_eq!(rtc.registers().day_high & DAY_CARRY, DAY_CARRY);
+
+        assert_eq!(rtc.read(0x08), 59 | 0xc0);
+        rtc.write_latch(0x00);
+        rtc.write_latch(0x01);
+        assert_eq!(rtc.read(0x08), 0xc0);
+    }
+
+    #[test]
+    fn halt_stops_counting() {
+        let mut rtc = Rtc::new();
+        rtc.write(0x0c, HALT);
+        rtc.tick(10 * CYCLES_PER_SECOND);
+        assert_eq!(rtc.registers().seconds, 0);
+    }
+
+    #[test]
+    fn save_round_trip() {
+        let mut rtc = Rtc::new();
+        rtc.write(0x09, 10);
+        let data = rtc.save_data(1000);
+
+        let mut restored = Rtc::new();
+        restored.load_save_data(&data, 1000 + 3600 + 5);
+        assert_eq!(restored.registers().seconds, 5);
+        assert_eq!(restored.registers().minutes, 10);
+        assert_eq!(restored.registers().hours, 1);
+    }
+}
@@ -0,0 +1,240 @@
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use crate::cartridge::Cartridge;
+use crate::cgb::Model;
+use crate::cpu::emulate_instruction;
+use crate::header::HeaderError;
+use crate::image::Image;
+use crate::joypad::Button;
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::state::StateGbz80;
+
+// Dots in one frame of 154 lines
+pub const FRAME_DOTS: usize = 456 * 154;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InputEvent {
+    pub frame: u64,
+    pub button: Button,
+    pub pressed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+// One "<frame> press|release <button>" per line, with # comments
+pub fn parse_script(text: &str) -> Result<Vec<InputEvent>, ScriptError> {
+    let mut events = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let error = |message: String| ScriptError {
+            line: i + 1,
+            message,
+        };
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (frame, action, button) = match words.as_slice() {
+            [frame, action, button] => (frame, action, button),
+            _ => {
+                return Err(error(format!(
+                    "expected <frame> press|release <button>, got \"{}\"",
+                    line
+                )))
+            }
+        };
+        let frame = frame
+            .parse()
+            .map_err(|_| error(format!("bad frame number {}", frame)))?;
+        let pressed = match action.to_ascii_lowercase().as_str() {
+            "press" => true,
+            "release" => false,
+            _ => return Err(error(format!("unknown action {}", action))),
+        };
+        let button =
+            Button::from_name(button).ok_or_else(|| error(format!("unknown button {}", button)))?;
+        events.push(InputEvent {
+            frame,
+            button,
+            pressed,
+        });
+    }
+    events.sort_by_key(|e| e.frame);
+    Ok(events)
+}
+
+// Runs a machine frame by frame without a display. Frames count from 1, and
+// input scheduled for frame N is applied just before frame N runs.
+pub struct Runner {
+    pub state: StateGbz80,
+    script: Vec<InputEvent>,
+    next_event: usize,
+    frame: u64,
+}
+
+impl Runner {
+    pub fn new(state: StateGbz80) -> Runner {
+        Runner {
+            state,
+            script: Vec::new(),
+            next_event: 0,
+            frame: 0,
+        }
+    }
+
+    // Picks the model from the header and stops the cartridge clock so runs
+    // are reproducible
+    pub fn from_rom(rom: Vec<u8>) -> Result<Runner, HeaderError> {
+        let cartridge = Cartridge::new(rom)?.with_clock(|| 0);
+        Ok(Runner::from_cartridge(cartridge))
+    }
+
+    // Battery RAM comes from the .sav next to the ROM and goes back there on
+    // save(). The clock keeps running so the RTC stays in step with it.
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Runner> {
+        Ok(Runner::from_cartridge(Cartridge::from_file(path)?))
+    }
+
+    pub fn from_cartridge(cartridge: Cartridge) -> Runner {
+        let model = Model::for_header(cartridge.header());
+        Runner::new(StateGbz80::with_model(cartridge, model))
+    }
+
+    // Writes battery RAM out, if the cartridge has a save file
+    pub fn save(&self) -> io::Result<()> {
+        self.state.m.cartridge.save()
+    }
+
+    pub fn with_script(mut self, mut script: Vec<InputEvent>) -> Runner {
+        script.sort_by_key(|e| e.frame);
+        self.script = script;
+        self.next_event = 0;
+        self
+    }
+
+    // The last frame completed
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    // Runs until the PPU finishes a frame, or for a frame's worth of cycles
+    // while the LCD is off
+    pub fn run_frame(&mut self) {
+        self.frame += 1;
+        while let Some(event) = self.script.get(self.next_event) {
+            if event.frame > self.frame {
+                break;
+            }
+            if event.pressed {
+                self.state.m.press(event.button);
+            } else {
+                self.state.m.release(event.button);
+            }
+            self.next_event += 1;
+        }
+
+        let start = self.state.m.ppu.frames();
+        let mut dots = 0;
+        while self.state.m.ppu.frames() == start
+            && (self.state.m.ppu.lcd_enabled() || dots < FRAME_DOTS)
+        {
+            let cycles = emulate_instruction(&mut self.state);
+            dots += if self.state.m.double_speed() {
+                cycles / 2
+            } else {
+                cycles
+            };
+        }
+    }
+
+    pub fn screenshot(&self) -> Image {
+        Image::from_rgba(
+            SCREEN_WIDTH,
+            SCREEN_HEIGHT,
+            self.state.m.ppu.framebuffer_rgba(),
+        )
+    }
+
+    pub fn frame_hash(&self) -> u64 {
+        self.screenshot().hash()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    // Shows the joypad action row on the background: tile 0 is filled with
+    // colour 3 and P1 is copied into SCX
+    fn test_runner() -> Runner {
+        let rom = assemble(
+            r#"
+SECTION "Entry", ROM0[$100]
+    nop
+    jp Main
+    ds $150 - @, 0
+Main:
+    ld hl, $8000
+    ld a, $ff
+    ld b, 16
+.tile:
+    ld [hl+], a
+    dec b
+    jr nz, .tile
+    ld a, $10
+    ldh [$ff00], a
+.loop:
+    ldh a, [$ff00]
+    ldh [$ff43], a
+    jr .loop
+"#,
+        )
+        .unwrap();
+        Runner::from_rom(rom).unwrap()
+    }
+
+    #[test]
+    fn hashes_are_reproducible() {
+        let mut first = test_runner();
+        let mut second = test_runner();
+        for _ in 0..3 {
+            first.run_frame();
+            second.run_frame();
+            assert_eq!(first.frame_hash(), second.frame_hash());
+        }
+        assert_eq!(first.frame(), 3);
+        assert_eq!(first.state.m.ppu.frames(), 3);
+    }
+
+    #[test]
+    fn script_presses_buttons() {
+        let script = parse_script("# start\n2 press a\n3 release A\n").unwrap();
+        assert_eq!(script.len(), 2);
+        let mut runner = test_runner().with_script(script);
+        runner.run_frame();
+        assert!(!runner.state.m.joypad.is_pressed(Button::A));
+        runner.run_frame();
+        assert!(runner.state.m.joypad.is_pressed(Button::A));
+        runner.run_frame();
+        assert!(!runner.state.m.joypad.is_pressed(Button::A));
+        assert_eq!(
+            parse_script("1 hold a").unwrap_err().message,
+            "unknown action hold"
+        );
+    }
+}
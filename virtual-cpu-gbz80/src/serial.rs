@@ -0,0 +1,264 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::interrupts::{Interrupts, SERIAL};
+
+// 8 bits at 8192 Hz
+const TRANSFER_CYCLES: usize = 8 * 512;
+
+// Something on the other end of the link port
+pub trait SerialDevice {
+    // Called when this console clocks a byte out with the internal clock,
+    // returning the byte shifted back in
+    fn exchange(&mut self, out: u8) -> u8;
+
+    // Called when this console waits for an external clock with `out` in SB
+    fn listen(&mut self, _out: u8) {}
+
+    // The byte received once the other side has clocked a waiting transfer
+    fn receive(&mut self) -> Option<u8> {
+        None
+    }
+}
+
+// Lets the caller keep a handle on a device plugged into a console
+impl<D: SerialDevice> SerialDevice for Rc<RefCell<D>> {
+    fn exchange(&mut self, out: u8) -> u8 {
+        self.borrow_mut().exchange(out)
+    }
+
+    fn listen(&mut self, out: u8) {
+        self.borrow_mut().listen(out)
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        self.borrow_mut().receive()
+    }
+}
+
+// SB (0xff01) and SC (0xff02). Without a device, internally clocked
+// transfers shift in 0xff and externally clocked ones never finish.
+pub struct Serial {
+    sb: u8,
+    sc: u8,
+    cycles: usize,
+    device: Option<Box<dyn SerialDevice>>,
+    capture: bool,
+    output: Vec<u8>,
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            sb: 0,
+            sc: 0x7e,
+            cycles: 0,
+            device: None,
+            capture: false,
+            output: Vec::new(),
+        }
+    }
+
+    pub fn connect(&mut self, device: Box<dyn SerialDevice>) {
+        self.device = Some(device);
+    }
+
+    pub fn disconnect(&mut self) -> Option<Box<dyn SerialDevice>> {
+        self.device.take()
+    }
+
+    // Keeps the bytes clocked out from here on, for the caller to drain
+    pub fn capture_output(&mut self, capture: bool) {
+        self.capture = capture;
+        if !capture {
+            self.output.clear();
+        }
+    }
+
+    // Every byte this console has clocked out while capturing, in order
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    fn transferring(&self) -> bool {
+        (self.sc & 0x80) != 0
+    }
+
+    fn internal_clock(&self) -> bool {
+        (self.sc & 0x01) != 0
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xff01 => self.sb,
+            0xff02 => self.sc | 0x7e,
+            _ => 0xff,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xff01 => {
+                self.sb = val;
+                if self.transferring() && !self.internal_clock() {
+                    self.listen();
+                }
+            }
+            0xff02 => {
+                self.sc = val | 0x7e;
+                if self.transferring() {
+                    if self.internal_clock() {
+                        self.cycles = TRANSFER_CYCLES;
+                        if self.capture {
+                            self.output.push(self.sb);
+                        }
+                    } else {
+                        self.listen();
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn listen(&mut self) {
+        let sb = self.sb;
+        if let Some(device) = self.device.as_mut() {
+            device.listen(sb);
+        }
+    }
+
+    fn complete(&mut self, received: u8, interrupts: &mut Interrupts) {
+        self.sb = received;
+        self.sc &= 0x7f;
+        interrupts.request(SERIAL);
+    }
+
+    pub fn tick(&mut self, cycles: usize, interrupts: &mut Interrupts) {
+        if !self.transferring() {
+            return;
+        }
+        if self.internal_clock() {
+            self.cycles = self.cycles.saturating_sub(cycles);
+            if self.cycles == 0 {
+                let sb = self.sb;
+                let received = match self.device.as_mut() {
+                    Some(device) => device.exchange(sb),
+                    None => 0xff,
+                };
+                self.complete(received, interrupts);
+            }
+        } else if let Some(received) = self.device.as_mut().and_then(|d| d.receive()) {
+            self.complete(received, interrupts);
+        }
+    }
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Side {
+    listening: Option<u8>,
+    received: Option<u8>,
+}
+
+// One end of an in-process link cable. The console driving the clock swaps
+// bytes with the other end if it is waiting on an external clock, and the
+// waiting console finishes its transfer on its next tick.
+#[derive(Debug)]
+pub struct LinkPort {
+    cable: Rc<RefCell<[Side; 2]>>,
+    side: usize,
+}
+
+impl LinkPort {
+    pub fn pair() -> (LinkPort, LinkPort) {
+        let cable = Rc::new(RefCell::new([Side::default(), Side::default()]));
+        (
+            LinkPort {
+                cable: Rc::clone(&cable),
+                side: 0,
+            },
+            LinkPort { cable, side: 1 },
+        )
+    }
+}
+
+impl SerialDevice for LinkPort {
+    fn exchange(&mut self, out: u8) -> u8 {
+        let mut cable = self.cable.borrow_mut();
+        let peer = &mut cable[1 - self.side];
+        match peer.listening.take() {
+            Some(byte) => {
+                peer.received = Some(out);
+                byte
+            }
+            None => 0xff,
+        }
+    }
+
+    fn listen(&mut self, out: u8) {
+        self.cable.borrow_mut()[self.side].listening = Some(out);
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        self.cable.borrow_mut()[self.side].received.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unconnected_transfer_reads_ff() {
+        let mut serial = Serial::new();
+        let mut interrupts = Interrupts::new();
+        serial.write(0xff02, 0x81);
+        serial.tick(TRANSFER_CYCLES, &mut interrupts);
+        assert!(serial.output().is_empty());
+
+        serial.capture_output(true);
+        serial.write(0xff01, 0x42);
+        serial.write(0xff02, 0x81);
+        serial.tick(TRANSFER_CYCLES - 4, &mut interrupts);
+        assert_eq!(serial.read(0xff02), 0xff);
+        serial.tick(4, &mut interrupts);
+        assert_eq!(serial.read(0xff01), 0xff);
+        assert_eq!(serial.read(0xff02), 0x7f);
+        assert_eq!(interrupts.flags & SERIAL, SERIAL);
+        assert_eq!(serial.output(), &[0x42]);
+    }
+
+    #[test]
+    fn link_cable_swaps_bytes() {
+        let (a, b) = LinkPort::pair();
+        let mut master = Serial::new();
+        let mut slave = Serial::new();
+        master.connect(Box::new(a));
+        slave.connect(Box::new(b));
+        let mut master_interrupts = Interrupts::new();
+        let mut slave_interrupts = Interrupts::new();
+
+        slave.write(0xff01, 0x22);
+        slave.write(0xff02, 0x80);
+        master.write(0xff01, 0x11);
+        master.write(0xff02, 0x81);
+
+        master.tick(TRANSFER_CYCLES, &mut master_interrupts);
+        slave.tick(4, &mut slave_interrupts);
+        assert_eq!(master.read(0xff01), 0x22);
+        assert_eq!(slave.read(0xff01), 0x11);
+        assert_eq!(slave.read(0xff02) & 0x80, 0);
+        assert_eq!(master_interrupts.flags & SERIAL, SERIAL);
+        assert_eq!(slave_interrupts.flags & SERIAL, SERIAL);
+    }
+}
@@ -0,0 +1,49 @@
+use virtual_cpu_core::bytes::*;
+use virtual_cpu_core::{Memory, Stack};
+
+use crate::memory::MemoryGbz80;
+
+#[derive(Default, Debug)]
+pub struct StackGbz80 {
+    sp: u16,
+}
+
+impl StackGbz80 {
+    pub fn new() -> StackGbz80 {
+        StackGbz80::default()
+    }
+}
+
+impl Stack for StackGbz80 {
+    type Address = u16;
+    type Mem = MemoryGbz80;
+
+    fn get_sp(&self) -> u16 {
+        self.sp
+    }
+    fn set_sp(&mut self, val: u16) {
+        self.sp = val;
+    }
+
+    fn pop_byte(&mut self, m: &mut MemoryGbz80) -> u8 {
+        self.sp = self.sp.wrapping_add(1);
+        m.get_byte(self.sp.wrapping_sub(1))
+    }
+
+    fn push_byte(&mut self, m: &mut MemoryGbz80, val: u8) {
+        self.sp = self.sp.wrapping_sub(1);
+        m.set_byte(self.sp, val);
+    }
+
+    fn pop_word(&mut self, m: &mut MemoryGbz80) -> u16 {
+        let low_order = self.pop_byte(m);
+        let high_order = self.pop_byte(m);
+
+        assemble_word(high_order, low_order)
+    }
+
+    fn push_word(&mut self, m: &mut MemoryGbz80, val: u16) {
+        self.push_byte(m, high_order_byte(val));
+        self.push_byte(m, low_order_byte(val));
+    }
+}
@@ -0,0 +1,551 @@
+use virtual_cpu_core::{bytes::*, MappedMemory, Memory, Program, Registers16, Registers8, Stack};
+
+use crate::cartridge::Cartridge;
+use crate::cgb::Model;
+use crate::flags::FlagsGbz80;
+use crate::instructions::{apply_offset, condition_for, word_arg_from};
+use crate::interrupts::Interrupts;
+use crate::memory::MemoryGbz80;
+use crate::program::ProgramGbz80;
+use crate::registers::*;
+use crate::stack::StackGbz80;
+
+#[derive(Debug)]
+pub struct StateGbz80 {
+    pub m: MemoryGbz80,
+    pub s: StackGbz80,
+    pub p: ProgramGbz80,
+    pub r: RegistersGbz80,
+    pub int_enable: bool,
+    pub int_enable_pending: bool,
+    pub halted: bool,
+    pub stopped: bool,
+    pub locked: bool,
+    // T-cycles the current instruction has spent so far
+    cycles: usize,
+}
+
+impl StateGbz80 {
+    pub fn new() -> StateGbz80 {
+        StateGbz80::with_cartridge(Cartridge::default())
+    }
+
+    fn power_on(m: MemoryGbz80) -> StateGbz80 {
+        StateGbz80 {
+            m,
+            s: StackGbz80::new(),
+            p: ProgramGbz80::new(),
+            r: RegistersGbz80::new(),
+            int_enable: false,
+            int_enable_pending: false,
+            halted: false,
+            stopped: false,
+            locked: false,
+            cycles: 0,
+        }
+    }
+
+    pub fn with_cartridge(cartridge: Cartridge) -> StateGbz80 {
+        StateGbz80::with_model(cartridge, Model::Dmg)
+    }
+
+    // Without a boot ROM the machine starts in the state the boot ROM leaves
+    // behind when it hands over to the cartridge. Games tell the models
+    // apart by A.
+    pub fn with_model(cartridge: Cartridge, model: Model) -> StateGbz80 {
+        let mut state = StateGbz80::power_on(MemoryGbz80::with_model(cartridge, model));
+        state.reset_post_boot();
+        state.m.reset_post_boot();
+        state
+    }
+
+    // Runs whatever is mapped into the cartridge slot instead of a cartridge
+    pub fn with_slot(slot: MappedMemory, model: Model) -> StateGbz80 {
+        let mut state = StateGbz80::power_on(MemoryGbz80::with_slot(slot, model));
+        state.reset_post_boot();
+        state.m.reset_post_boot();
+        state
+    }
+
+    // Puts the CPU alone in its post-boot state
+    pub fn reset_post_boot(&mut self) {
+        let (af, bc, de, hl) = match self.m.model() {
+            Model::Dmg => (0x01b0, 0x0013, 0x00d8, 0x014d),
+            Model::Cgb => (0x1180, 0x0000, 0xff56, 0x000d),
+        };
+        self.r.set16(Name16::AF, af);
+        self.r.set16(Name16::BC, bc);
+        self.r.set16(Name16::DE, de);
+        self.r.set16(Name16::HL, hl);
+        self.s.set_sp(0xfffe);
+        self.p.jump(0x0100);
+        self.int_enable = false;
+        self.int_enable_pending = false;
+        self.halted = false;
+        self.stopped = false;
+        self.locked = false;
+    }
+
+    // The boot ROM runs from 0x0000 with everything zeroed. Anything bigger
+    // than the 256 byte DMG boot ROM is taken to be a CGB one.
+    pub fn with_boot_rom(cartridge: Cartridge, boot_rom: Vec<u8>) -> StateGbz80 {
+        let model = if boot_rom.len() > 0x100 {
+            Model::Cgb
+        } else {
+            Model::Dmg
+        };
+        let mut m = MemoryGbz80::with_model(cartridge, model);
+        m.map_boot_rom(boot_rom);
+        StateGbz80::power_on(m)
+    }
+
+    // TIMING
+
+    // Runs the rest of the system for one M-cycle
+    pub fn internal_cycle(&mut self) {
+        self.m.tick(4);
+        self.cycles += 4;
+    }
+
+    pub fn start_instruction(&mut self) {
+        self.cycles = 0;
+    }
+
+    // Runs whatever internal cycles the instruction has after its last
+    // access, returning the T-cycles it took
+    pub fn finish_instruction(&mut self, cycles: usize) -> usize {
+        while self.cycles < cycles {
+            self.internal_cycle();
+        }
+        self.cycles
+    }
+
+    // MEMORY ACCESS
+
+    // Every access takes an M-cycle of its own, landing at the end of it
+    pub fn read8(&mut self, addr: u16) -> u8 {
+        self.internal_cycle();
+        self.m.get_byte(addr)
+    }
+
+    pub fn write8(&mut self, addr: u16, val: u8) {
+        self.internal_cycle();
+        self.m.set_byte(addr, val);
+    }
+
+    // MOV operations
+
+    // MOV register FROM register
+    pub fn mov_rr8(&mut self, dest: Name8, src: Name8) {
+        self.r.set8(dest, self.r.get8(src));
+    }
+
+    // MOV register FROM immediate
+    pub fn mov_ri8(&mut self, dest: Name8, val: u8) {
+        self.r.set8(dest, val);
+    }
+
+    // MOV register FROM pointer (in register)
+    pub fn mov_rp8(&mut self, dest: Name8, src: Name16) {
+        let val = self.read8(self.r.get16(src));
+        self.r.set8(dest, val);
+    }
+
+    // MOV register FROM address (in operand)
+    pub fn mov_ra8(&mut self, dest: Name8, src: u16) {
+        let val = self.read8(src);
+        self.r.set8(dest, val);
+    }
+
+    // MOV pointer FROM register
+    pub fn mov_pr8(&mut self, dest: Name16, src: Name8) {
+        self.write8(self.r.get16(dest), self.r.get8(src));
+    }
+
+    // MOV pointer FROM immediate
+    pub fn mov_pi8(&mut self, dest: Name16, src: u8) {
+        self.write8(self.r.get16(dest), src);
+    }
+
+    // MOV address FROM register
+    pub fn mov_ar8(&mut self, dest: u16, src: Name8) {
+        self.write8(dest, self.r.get8(src));
+    }
+
+    pub fn mov_ri16(&mut self, dest: Name16, val: u16) {
+        self.r.set16(dest, val);
+    }
+
+    // MOV address FROM 16-bit value, low byte first
+    pub fn mov_ai16(&mut self, dest: u16, val: u16) {
+        self.write8(dest, low_order_byte(val));
+        self.write8(dest.wrapping_add(1), high_order_byte(val));
+    }
+
+    // INDIRECT MEMORY ACCESS
+
+    pub fn get_indirect8(&mut self, ptr: Name16) -> u8 {
+        self.read8(self.r.get16(ptr))
+    }
+
+    // CONTROL FLOW
+
+    pub fn test_flags(&self, predicate: impl Fn(&FlagsGbz80) -> bool) -> bool {
+        predicate(&self.r.cc)
+    }
+
+    pub fn jump_a(&mut self, addr: u16) {
+        self.p.jump(addr);
+    }
+
+    pub fn jr_o(&mut self, offset: u8) {
+        self.p.jr(offset);
+    }
+
+    // SP is decremented in an internal cycle before the pushes
+    pub fn call_a(&mut self, addr: u16) {
+        self.internal_cycle();
+        self.push_word(self.p.next_pc());
+        self.p.jump(addr);
+    }
+
+    pub fn ret(&mut self) {
+        let addr = self.pop_word();
+        self.p.jump(addr);
+    }
+
+    pub fn jump_if(&mut self, instruction: &[u8]) {
+        if self.test_flags(condition_for(instruction[0])) {
+            self.jump_a(word_arg_from(instruction));
+        }
+    }
+
+    pub fn jr_if(&mut self, offset: u8, predicate: impl Fn(&FlagsGbz80) -> bool) {
+        if self.test_flags(predicate) {
+            self.jr_o(offset);
+        }
+    }
+
+    pub fn call_if(&mut self, instruction: &[u8]) {
+        if self.test_flags(condition_for(instruction[0])) {
+            self.call_a(word_arg_from(instruction));
+        }
+    }
+
+    // The condition is checked in an internal cycle of its own
+    pub fn ret_if(&mut self, instruction: &[u8]) {
+        self.internal_cycle();
+        if self.test_flags(condition_for(instruction[0])) {
+            self.ret();
+        }
+    }
+
+    // BINARY OPERATIONS
+
+    pub fn add_ri8(&mut self, operand: u8) {
+        self.adc_with(operand, false);
+    }
+
+    pub fn adc_ri8(&mut self, operand: u8) {
+        self.adc_with(operand, self.r.cc.cy);
+    }
+
+    fn adc_with(&mut self, operand: u8, carry: bool) {
+        let a = self.r.a;
+        let result = u16::from(a) + u16::from(operand) + u16::from(carry);
+        let half = (a & 0x0f) + (operand & 0x0f) + carry as u8;
+        self.r.a = low_order_byte(result);
+        self.r
+            .cc
+            .set_all(self.r.a == 0, false, half > 0x0f, result > 0xff);
+    }
+
+    pub fn sub_ri8(&mut self, operand: u8) {
+        self.r.a = self.sbc_with(operand, false);
+    }
+
+    pub fn sbc_ri8(&mut self, operand: u8) {
+        self.r.a = self.sbc_with(operand, self.r.cc.cy);
+    }
+
+    pub fn cmp_ri8(&mut self, operand: u8) {
+        self.sbc_with(operand, false);
+    }
+
+    fn sbc_with(&mut self, operand: u8, carry: bool) -> u8 {
+        let a = self.r.a;
+        let result = a.wrapping_sub(operand).wrapping_sub(carry as u8);
+        let half = (a & 0x0f) < (operand & 0x0f) + carry as u8;
+        let full = u16::from(a) < u16::from(operand) + u16::from(carry);
+        self.r.cc.set_all(result == 0, true, half, full);
+        result
+    }
+
+    pub fn and_ri8(&mut self, operand: u8) {
+        self.r.a &= operand;
+        self.r.cc.set_all(self.r.a == 0, false, true, false);
+    }
+
+    pub fn xor_ri8(&mut self, operand: u8) {
+        self.r.a ^= operand;
+        self.r.cc.set_all(self.r.a == 0, false, false, false);
+    }
+
+    pub fn or_ri8(&mut self, operand: u8) {
+        self.r.a |= operand;
+        self.r.cc.set_all(self.r.a == 0, false, false, false);
+    }
+
+    pub fn add_ri16(&mut self, operand: u16) {
+        let hl = self.r.get16(Name16::HL);
+        let (result, carry) = hl.overflowing_add(operand);
+        self.r.cc.n = false;
+        self.r.cc.h = (hl & 0x0fff) + (operand & 0x0fff) > 0x0fff;
+        self.r.cc.cy = carry;
+        self.r.set16(Name16::HL, result);
+    }
+
+    pub fn add_rr16(&mut self, src: Name16) {
+        self.add_ri16(self.r.get16(src));
+    }
+
+    // SP plus a signed offset, as used by ADD SP,e and LD HL,SP+e. The flags
+    // come from the unsigned addition of the low bytes.
+    pub fn sp_offset(&mut self, offset: u8) -> u16 {
+        let sp = self.s.get_sp();
+        let half = (sp & 0x000f) + u16::from(offset & 0x0f) > 0x000f;
+        let carry = (sp & 0x00ff) + u16::from(offset) > 0x00ff;
+        self.r.cc.set_all(false, false, half, carry);
+        apply_offset(sp, offset)
+    }
+
+    // UNARY OPERATIONS
+
+    pub fn increment8(&mut self, val: u8) -> u8 {
+        let result = val.wrapping_add(1);
+        self.r.cc.z = result == 0;
+        self.r.cc.n = false;
+        self.r.cc.h = (val & 0x0f) == 0x0f;
+        result
+    }
+
+    pub fn decrement8(&mut self, val: u8) -> u8 {
+        let result = val.wrapping_sub(1);
+        self.r.cc.z = result == 0;
+        self.r.cc.n = true;
+        self.r.cc.h = (val & 0x0f) == 0x00;
+        result
+    }
+
+    pub fn inc_r8(&mut self, reg: Name8) {
+        let result = self.increment8(self.r.get8(reg));
+        self.r.set8(reg, result);
+    }
+
+    pub fn dec_r8(&mut self, reg: Name8) {
+        let result = self.decrement8(self.r.get8(reg));
+        self.r.set8(reg, result);
+    }
+
+    // The eight CB-prefixed rotates and shifts, selected by bits 3-5 of the
+    // opcode: RLC RRC RL RR SLA SRA SWAP SRL
+    pub fn rotate_shift(&mut self, opcode: u8, val: u8) -> u8 {
+        let carry_in = self.r.cc.cy as u8;
+        let (result, carry) = match (opcode >> 3) & 0x07 {
+            0x0 => (val.rotate_left(1), val & 0x80 != 0),
+            0x1 => (val.rotate_right(1), val & 0x01 != 0),
+            0x2 => (val << 1 | carry_in, val & 0x80 != 0),
+            0x3 => (val >> 1 | carry_in << 7, val & 0x01 != 0),
+            0x4 => (val << 1, val & 0x80 != 0),
+            0x5 => (val >> 1 | (val & 0x80), val & 0x01 != 0),
+            0x6 => (val.rotate_left(4), false),
+            0x7 => (val >> 1, val & 0x01 != 0),
+            _ => panic!("Shouldn't happen"),
+        };
+        self.r.cc.set_all(result == 0, false, false, carry);
+        result
+    }
+
+    // RLCA, RRCA, RLA and RRA always clear Z
+    pub fn rotate_a(&mut self, opcode: u8) {
+        self.r.a = self.rotate_shift(opcode, self.r.a);
+        self.r.cc.z = false;
+    }
+
+    pub fn bit(&mut self, bit: u8, val: u8) {
+        self.r.cc.z = (val & (1 << bit)) == 0;
+        self.r.cc.n = false;
+        self.r.cc.h = true;
+    }
+
+    pub fn daa(&mut self) {
+        let mut a = self.r.a;
+        let cc = &mut self.r.cc;
+        if !cc.n {
+            if cc.cy || a > 0x99 {
+                a = a.wrapping_add(0x60);
+                cc.cy = true;
+            }
+            if cc.h || (a & 0x0f) > 0x09 {
+                a = a.wrapping_add(0x06);
+            }
+        } else {
+            if cc.cy {
+                a = a.wrapping_sub(0x60);
+            }
+            if cc.h {
+                a = a.wrapping_sub(0x06);
+            }
+        }
+        cc.z = a == 0;
+        cc.h = false;
+        self.r.a = a;
+    }
+
+    pub fn cpl(&mut self) {
+        self.r.a = !self.r.a;
+        self.r.cc.n = true;
+        self.r.cc.h = true;
+    }
+
+    pub fn scf(&mut self) {
+        self.r.cc.n = false;
+        self.r.cc.h = false;
+        self.r.cc.cy = true;
+    }
+
+    pub fn ccf(&mut self) {
+        self.r.cc.n = false;
+        self.r.cc.h = false;
+        self.r.cc.cy = !self.r.cc.cy;
+    }
+
+    // STACK OPERATION
+
+    pub fn push_r16(&mut self, src: Name16) {
+        self.internal_cycle();
+        self.push_word(self.r.get16(src));
+    }
+
+    pub fn pop_r16(&mut self, dest: Name16) {
+        let val = self.pop_word();
+        self.r.set16(dest, val);
+    }
+
+    pub fn pop_word(&mut self) -> u16 {
+        let sp = self.s.get_sp();
+        let low_order = self.read8(sp);
+        let high_order = self.read8(sp.wrapping_add(1));
+        self.s.set_sp(sp.wrapping_add(2));
+        assemble_word(high_order, low_order)
+    }
+
+    pub fn push_word(&mut self, val: u16) {
+        let sp = self.s.get_sp();
+        self.write8(sp.wrapping_sub(1), high_order_byte(val));
+        self.write8(sp.wrapping_sub(2), low_order_byte(val));
+        self.s.set_sp(sp.wrapping_sub(2));
+    }
+
+    // PROGRAM OPERATIONS
+
+    pub fn get_instruction(&mut self) -> Vec<u8> {
+        let opcode = self.read8(self.p.get_pc());
+        let length = self.p.start_fetch(opcode);
+        let mut instruction = vec![opcode];
+        for i in 1..length {
+            let val = self.read8(self.p.operand_address(i));
+            instruction.push(val);
+        }
+        self.p.end_fetch();
+        instruction
+    }
+
+    // INTERRUPTS
+
+    pub fn set_interrupt_flag(&mut self, target: bool) {
+        self.int_enable = target;
+        self.int_enable_pending = false;
+    }
+
+    pub fn get_interrupt_flag(&mut self) -> bool {
+        self.int_enable
+    }
+
+    // EI takes effect after the following instruction
+    pub fn enable_interrupts_delayed(&mut self) {
+        self.int_enable_pending = true;
+    }
+
+    // With IME off and an interrupt already pending HALT is not entered and
+    // the next opcode byte is read twice
+    pub fn halt(&mut self) {
+        if !self.int_enable && self.m.interrupts.pending() != 0 {
+            self.p.repeat_next_byte();
+        } else {
+            self.halted = true;
+        }
+    }
+
+    // STOP switches speed on the CGB once KEY1 is armed, otherwise the
+    // system sleeps until a button is pressed. Either way DIV is reset.
+    pub fn stop(&mut self) {
+        if self.m.speed_switch_armed() {
+            self.m.switch_speed();
+        } else {
+            self.stopped = true;
+        }
+        self.m.timer.write(0xff04, 0);
+    }
+
+    // The unused opcodes hang the CPU for good. Nothing but a reset, not
+    // even an interrupt, gets it going again.
+    pub fn lock(&mut self) {
+        self.locked = true;
+    }
+
+    // Pushes PC and jumps to the vector of the highest priority interrupt,
+    // clearing its request. Takes five M-cycles: two waiting, two pushing
+    // and one jumping. The interrupt is only chosen between the pushes, so
+    // if pushing the high byte overwrote IE and nothing is left the CPU
+    // jumps to 0x0000 instead.
+    pub fn trigger_interrupt(&mut self) {
+        self.int_enable = false;
+        self.internal_cycle();
+        self.internal_cycle();
+        let pc = self.p.get_pc();
+        let sp = self.s.get_sp().wrapping_sub(1);
+        self.write8(sp, high_order_byte(pc));
+        let n = self.m.interrupts.highest_priority();
+        self.write8(sp.wrapping_sub(1), low_order_byte(pc));
+        self.s.set_sp(sp.wrapping_sub(1));
+        match n {
+            Some(n) => {
+                self.m.interrupts.acknowledge(n);
+                self.p.jump(Interrupts::vector(n));
+            }
+            None => self.p.jump(0x0000),
+        }
+        self.internal_cycle();
+    }
+
+    // Any pending interrupt wakes a halted CPU, even with IME off. Returns
+    // the cycles spent dispatching, if an interrupt was taken.
+    pub fn service_interrupts(&mut self) -> Option<usize> {
+        if self.m.interrupts.pending() != 0 {
+            self.halted = false;
+        }
+        if !self.int_enable || self.m.interrupts.highest_priority().is_none() {
+            return None;
+        }
+        self.start_instruction();
+        self.trigger_interrupt();
+        Some(self.cycles)
+    }
+}
+
+impl Default for StateGbz80 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,190 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use virtual_cpu_core::{Memory, Program};
+
+use crate::cartridge::Cartridge;
+use crate::cgb::Model;
+use crate::cpu::emulate_instruction;
+use crate::state::StateGbz80;
+
+// LD B,B, which mooneye tests execute once they are done
+const MOONEYE_BREAKPOINT: u8 = 0x40;
+const MOONEYE_PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TestKind {
+    // Reports "Passed" or "Failed" over the serial port
+    Blargg,
+    // Stops on LD B,B with Fibonacci numbers in B, C, D, E, H and L
+    Mooneye,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TestOutcome {
+    Passed,
+    Failed(String),
+    TimedOut,
+}
+
+#[derive(Debug, Clone)]
+pub struct TestReport {
+    pub path: PathBuf,
+    pub outcome: TestOutcome,
+    pub cycles: u64,
+    pub serial: String,
+}
+
+impl TestReport {
+    pub fn passed(&self) -> bool {
+        self.outcome == TestOutcome::Passed
+    }
+}
+
+impl fmt::Display for TestReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = self.path.file_name().unwrap_or_default().to_string_lossy();
+        match &self.outcome {
+            TestOutcome::Passed => write!(f, "{}: passed in {} cycles", name, self.cycles),
+            TestOutcome::Failed(reason) => write!(f, "{}: failed, {}", name, reason),
+            TestOutcome::TimedOut => write!(f, "{}: timed out after {} cycles", name, self.cycles),
+        }
+    }
+}
+
+// Runs the ROM until it reports a result or the cycle budget runs out
+pub fn run_test_rom(rom: Vec<u8>, kind: TestKind, budget: u64) -> io::Result<TestReport> {
+    let cartridge =
+        Cartridge::new(rom).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let model = Model::for_header(cartridge.header());
+    let mut s = StateGbz80::with_model(cartridge, model);
+    s.m.serial.capture_output(true);
+    let mut cycles = 0;
+    let mut serial = String::new();
+
+    let outcome = loop {
+        if cycles >= budget {
+            break TestOutcome::TimedOut;
+        }
+        if kind == TestKind::Mooneye && s.m.get_byte(s.p.get_pc()) == MOONEYE_BREAKPOINT {
+            break mooneye_outcome(&s);
+        }
+        cycles += emulate_instruction(&mut s) as u64;
+
+        let output = s.m.serial.take_output();
+        if !output.is_empty() {
+            serial.extend(output.iter().map(|&b| b as char));
+            if serial.contains("Passed") {
+                break TestOutcome::Passed;
+            }
+            if serial.contains("Failed") {
+                break TestOutcome::Failed(serial.trim().to_string());
+            }
+        }
+    };
+
+    Ok(TestReport {
+        path: PathBuf::new(),
+        outcome,
+        cycles,
+        serial,
+    })
+}
+
+fn mooneye_outcome(s: &StateGbz80) -> TestOutcome {
+    let registers = [s.r.b, s.r.c, s.r.d, s.r.e, s.r.h, s.r.l];
+    if registers == MOONEYE_PASS {
+        TestOutcome::Passed
+    } else {
+        TestOutcome::Failed(format!("registers {:02x?}", registers))
+    }
+}
+
+pub fn run_test_rom_file<P: AsRef<Path>>(
+    path: P,
+    kind: TestKind,
+    budget: u64,
+) -> io::Result<TestReport> {
+    let mut report = run_test_rom(fs::read(path.as_ref())?, kind, budget)?;
+    report.path = path.as_ref().to_path_buf();
+    Ok(report)
+}
+
+// Runs every .gb file below the directory, in path order
+pub fn run_test_rom_dir<P: AsRef<Path>>(
+    dir: P,
+    kind: TestKind,
+    budget: u64,
+) -> io::Result<Vec<TestReport>> {
+    let mut roms = Vec::new();
+    find_roms(dir.as_ref(), &mut roms)?;
+    roms.sort();
+    roms.iter()
+        .map(|rom| run_test_rom_file(rom, kind, budget))
+        .collect()
+}
+
+fn find_roms(dir: &Path, roms: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_roms(&path, roms)?;
+        } else if path.extension().is_some_and(|ext| ext == "gb") {
+            roms.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom_with(program: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0150..0x0150 + program.len()].copy_from_slice(program);
+        rom[0x0100..0x0104].copy_from_slice(&[0x00, 0xc3, 0x50, 0x01]);
+        rom
+    }
+
+    #[test]
+    fn detects_mooneye_pass() {
+        let rom = rom_with(&[
+            0x06, 3, 0x0e, 5, 0x16, 8, 0x1e, 13, 0x26, 21, 0x2e, 34, 0x40,
+        ]);
+        let report = run_test_rom(rom, TestKind::Mooneye, 1000).unwrap();
+        assert!(report.passed());
+    }
+
+    #[test]
+    fn runs_cgb_roms_on_a_cgb() {
+        // CP 0x11; JR NZ past the passing registers
+        let mut rom = rom_with(&[
+            0xfe, 0x11, 0x20, 12, 0x06, 3, 0x0e, 5, 0x16, 8, 0x1e, 13, 0x26, 21, 0x2e, 34, 0x40,
+        ]);
+        rom[0x143] = 0x80;
+        let report = run_test_rom(rom, TestKind::Mooneye, 1000).unwrap();
+        assert!(report.passed());
+    }
+
+    #[test]
+    fn captures_serial_failure() {
+        // Sends "Failed" one byte at a time: LD A,n; LDH (01),A; LD A,81;
+        // LDH (02),A
+        let program: Vec<u8> = b"Failed"
+            .iter()
+            .flat_map(|&c| vec![0x3e, c, 0xe0, 0x01, 0x3e, 0x81, 0xe0, 0x02])
+            .chain(vec![0x18, 0xfe])
+            .collect();
+        let report = run_test_rom(rom_with(&program), TestKind::Blargg, 100_000).unwrap();
+        assert_eq!(report.outcome, TestOutcome::Failed("Failed".to_string()));
+    }
+
+    #[test]
+    fn gives_up_after_budget() {
+        let report = run_test_rom(rom_with(&[0x18, 0xfe]), TestKind::Blargg, 1000).unwrap();
+        assert_eq!(report.outcome, TestOutcome::TimedOut);
+    }
+}
@@ -0,0 +1,174 @@
+use crate::interrupts::{Interrupts, TIMER};
+
+// DIV is the top byte of a 16-bit counter advancing every T-cycle. TIMA
+// counts falling edges of one counter bit selected by TAC, ANDed with the
+// enable bit, so anything that drops that signal (a DIV reset or a TAC
+// write) can also tick TIMA.
+#[derive(Debug)]
+pub struct Timer {
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    // TIMA reads 0 for one M-cycle after overflowing, then TMA is loaded
+    overflow_pending: bool,
+    reloaded: bool,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer::with_counter(0)
+    }
+
+    pub fn with_counter(counter: u16) -> Timer {
+        Timer {
+            counter,
+            tima: 0,
+            tma: 0,
+            tac: 0xf8,
+            overflow_pending: false,
+            reloaded: false,
+        }
+    }
+
+    pub fn counter(&self) -> u16 {
+        self.counter
+    }
+
+    fn signal(&self) -> bool {
+        let bit = match self.tac & 0x03 {
+            0x0 => 9,
+            0x1 => 3,
+            0x2 => 5,
+            0x3 => 7,
+            _ => panic!("shouldn't happen"),
+        };
+        (self.tac & 0x04) != 0 && (self.counter >> bit) & 0x01 != 0
+    }
+
+    fn detect_falling_edge(&mut self, was_high: bool) {
+        if was_high && !self.signal() {
+            let (tima, overflow) = self.tima.overflowing_add(1);
+            self.tima = tima;
+            if overflow {
+                self.overflow_pending = true;
+            }
+        }
+    }
+
+    // Advances one M-cycle
+    pub fn step(&mut self, interrupts: &mut Interrupts) {
+        self.reloaded = false;
+        if self.overflow_pending {
+            self.overflow_pending = false;
+            self.reloaded = true;
+            self.tima = self.tma;
+            interrupts.request(TIMER);
+        }
+        let was_high = self.signal();
+        self.counter = self.counter.wrapping_add(4);
+        self.detect_falling_edge(was_high);
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xff04 => (self.counter >> 8) as u8,
+            0xff05 => self.tima,
+            0xff06 => self.tma,
+            0xff07 => self.tac | 0xf8,
+            _ => 0xff,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xff04 => {
+                let was_high = self.signal();
+                self.counter = 0;
+                self.detect_falling_edge(was_high);
+            }
+            // A write during the delay cancels the reload, a write on the
+            // reload cycle itself is lost
+            0xff05 if !self.reloaded => {
+                self.tima = val;
+                self.overflow_pending = false;
+            }
+            0xff06 => {
+                self.tma = val;
+                if self.reloaded {
+                    self.tima = val;
+                }
+            }
+            0xff07 => {
+                let was_high = self.signal();
+                self.tac = val | 0xf8;
+                self.detect_falling_edge(was_high);
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(timer: &mut Timer, interrupts: &mut Interrupts, m_cycles: usize) {
+        for _ in 0..m_cycles {
+            timer.step(interrupts);
+        }
+    }
+
+    #[test]
+    fn overflow_reloads_after_delay() {
+        let mut timer = Timer::new();
+        let mut interrupts = Interrupts::new();
+        timer.write(0xff06, 0x80);
+        timer.write(0xff05, 0xff);
+        timer.write(0xff07, 0x05);
+
+        run(&mut timer, &mut interrupts, 4);
+        assert_eq!(timer.read(0xff05), 0x00);
+        assert_eq!(interrupts.flags & TIMER, 0);
+        run(&mut timer, &mut interrupts, 1);
+        assert_eq!(timer.read(0xff05), 0x80);
+        assert_eq!(interrupts.flags & TIMER, TIMER);
+    }
+
+    #[test]
+    fn tima_write_cancels_reload() {
+        let mut timer = Timer::new();
+        let mut interrupts = Interrupts::new();
+        timer.write(0xff06, 0x80);
+        timer.write(0xff05, 0xff);
+        timer.write(0xff07, 0x05);
+
+        run(&mut timer, &mut interrupts, 4);
+        timer.write(0xff05, 0x10);
+        run(&mut timer, &mut interrupts, 1);
+        assert_eq!(timer.read(0xff05), 0x10);
+        assert_eq!(interrupts.flags & TIMER, 0);
+    }
+
+    #[test]
+    fn div_reset_and_tac_change_tick_tima() {
+        let mut timer = Timer::new();
+        let mut interrupts = Interrupts::new();
+        timer.write(0xff07, 0x05);
+        run(&mut timer, &mut interrupts, 2);
+        assert_eq!(timer.read(0xff05), 0);
+
+        timer.write(0xff04, 0);
+        assert_eq!(timer.read(0xff05), 1);
+
+        run(&mut timer, &mut interrupts, 2);
+        timer.write(0xff07, 0x00);
+        assert_eq!(timer.read(0xff05), 2);
+    }
+}
@@ -0,0 +1,166 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::apu::ApuRecording;
+use crate::rtc::CYCLES_PER_SECOND;
+
+// VGM 1.61, the first version with a Game Boy DMG chip. Waits are counted
+// in samples at 44.1kHz whatever the chip clock.
+// https://vgmrips.net/wiki/VGM_Specification
+
+pub const VGM_SAMPLE_RATE: u64 = 44_100;
+const VERSION: u32 = 0x0161;
+const HEADER_SIZE: usize = 0x100;
+
+const LOOP_OFFSET: usize = 0x1c;
+const DATA_OFFSET: usize = 0x34;
+const DMG_CLOCK: usize = 0x80;
+
+const GB_WRITE: u8 = 0xb3;
+const WAIT: u8 = 0x61;
+const WAIT_NTSC_FRAME: u8 = 0x62;
+const WAIT_PAL_FRAME: u8 = 0x63;
+const WAIT_SHORT: u8 = 0x70;
+const END: u8 = 0x66;
+
+fn set_u32(vgm: &mut [u8], offset: usize, val: u32) {
+    vgm[offset..offset + 4].copy_from_slice(&val.to_le_bytes());
+}
+
+fn push_wait(vgm: &mut Vec<u8>, mut samples: u64) {
+    while samples > 0 {
+        match samples {
+            735 => vgm.push(WAIT_NTSC_FRAME),
+            882 => vgm.push(WAIT_PAL_FRAME),
+            1..=16 => vgm.push(WAIT_SHORT + samples as u8 - 1),
+            _ => {
+                let wait = samples.min(0xffff) as u16;
+                vgm.push(WAIT);
+                vgm.extend_from_slice(&wait.to_le_bytes());
+                samples -= u64::from(wait);
+                continue;
+            }
+        }
+        return;
+    }
+}
+
+// Converts a recording to a VGM file. With loop_cycle inside the recording
+// players jump back to that point when they reach the end; cycles count
+// the same way as the recording's, from when the APU was created.
+pub fn to_vgm(recording: &ApuRecording, loop_cycle: Option<u64>) -> Vec<u8> {
+    let start = recording.start;
+    let to_samples = |cycle: u64| (cycle - start) * VGM_SAMPLE_RATE / CYCLES_PER_SECOND as u64;
+    let mut vgm = vec![0; HEADER_SIZE];
+    let mut samples = 0;
+    let mut wait_until = |vgm: &mut Vec<u8>, cycle: u64| {
+        let target = to_samples(cycle);
+        push_wait(vgm, target - samples);
+        samples = target;
+        target
+    };
+
+    let mut loop_cycle = loop_cycle.filter(|&c| c >= start && c < recording.end);
+    let mut loop_point = None;
+    for write in recording.writes.iter() {
+        if let Some(cycle) = loop_cycle.filter(|&c| c <= write.cycle) {
+            let loop_samples = wait_until(&mut vgm, cycle);
+            loop_point = Some((vgm.len(), loop_samples));
+            loop_cycle = None;
+        }
+        wait_until(&mut vgm, write.cycle);
+        vgm.extend_from_slice(&[GB_WRITE, (write.addr - 0xff10) as u8, write.val]);
+    }
+    if let Some(cycle) = loop_cycle {
+        let loop_samples = wait_until(&mut vgm, cycle);
+        loop_point = Some((vgm.len(), loop_samples));
+    }
+    let total = wait_until(&mut vgm, recording.end);
+    vgm.push(END);
+
+    vgm[0..4].copy_from_slice(b"Vgm ");
+    let len = vgm.len();
+    set_u32(&mut vgm, 0x04, (len - 4) as u32);
+    set_u32(&mut vgm, 0x08, VERSION);
+    set_u32(&mut vgm, 0x18, total as u32);
+    if let Some((offset, loop_samples)) = loop_point {
+        set_u32(&mut vgm, LOOP_OFFSET, (offset - LOOP_OFFSET) as u32);
+        set_u32(&mut vgm, 0x20, (total - loop_samples) as u32);
+    }
+    set_u32(&mut vgm, DATA_OFFSET, (HEADER_SIZE - DATA_OFFSET) as u32);
+    set_u32(&mut vgm, DMG_CLOCK, CYCLES_PER_SECOND as u32);
+    vgm
+}
+
+pub fn write_vgm<P: AsRef<Path>>(
+    path: P,
+    recording: &ApuRecording,
+    loop_cycle: Option<u64>,
+) -> io::Result<()> {
+    File::create(path)?.write_all(&to_vgm(recording, loop_cycle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apu::Apu;
+
+    fn u32_at(vgm: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes([
+            vgm[offset],
+            vgm[offset + 1],
+            vgm[offset + 2],
+            vgm[offset + 3],
+        ])
+    }
+
+    #[test]
+    fn header_waits_and_loop() {
+        let mut apu = Apu::new();
+        apu.tick(1000);
+        apu.start_recording();
+        apu.write(0xff26, 0x80);
+        // One 60Hz frame is 735 samples
+        apu.tick(CYCLES_PER_SECOND / 60 + 1);
+        let loop_cycle = apu.cycles();
+        apu.write(0xff12, 0xf0);
+        apu.tick(400);
+        let recording = apu.stop_recording().unwrap();
+        assert!(!apu.is_recording());
+        // NR52, NR10-NR51 and wave RAM, then the writes that were made
+        assert_eq!(recording.writes.len(), 1 + 0x16 + 0x10 + 2);
+
+        let vgm = to_vgm(&recording, Some(loop_cycle));
+        assert_eq!(&vgm[0..4], b"Vgm ");
+        assert_eq!(u32_at(&vgm, 0x04) as usize, vgm.len() - 4);
+        assert_eq!(u32_at(&vgm, 0x08), 0x161);
+        assert_eq!(u32_at(&vgm, DMG_CLOCK), 4_194_304);
+        assert_eq!(u32_at(&vgm, 0x18), 735 + 4);
+
+        let data = HEADER_SIZE + 3 * (recording.writes.len() - 2);
+        assert_eq!(
+            &vgm[data..data + 4],
+            &[GB_WRITE, 0x16, 0x80, WAIT_NTSC_FRAME]
+        );
+        let loop_offset = u32_at(&vgm, LOOP_OFFSET) as usize + LOOP_OFFSET;
+        assert_eq!(loop_offset, data + 4);
+        assert_eq!(&vgm[loop_offset..], &[GB_WRITE, 0x02, 0xf0, 0x73, END]);
+        assert_eq!(u32_at(&vgm, 0x20), 4);
+    }
+
+    #[test]
+    fn long_waits_are_split() {
+        let recording = ApuRecording {
+            start: 0,
+            end: 2 * CYCLES_PER_SECOND as u64,
+            writes: Vec::new(),
+        };
+        let vgm = to_vgm(&recording, None);
+        assert_eq!(u32_at(&vgm, LOOP_OFFSET), 0);
+        assert_eq!(
+            &vgm[HEADER_SIZE..],
+            &[WAIT, 0xff, 0xff, WAIT, 0x89, 0x58, END]
+        );
+    }
+}
@@ -0,0 +1,331 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::cgb::ColorPalettes;
+use crate::image::Image;
+use crate::ppu::{apply_palette, rgb555_to_rgba, Ppu, DMG_SHADES, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+// Debug renderings of what is in VRAM, OAM and the palettes right now.
+// Nothing here advances the PPU, so they're meant for a paused machine.
+
+const TILES_PER_BANK: usize = 384;
+const SHEET_COLUMNS: usize = 16;
+const MAP_SIZE: usize = 256;
+const SWATCH_SIZE: usize = 8;
+
+const VIEWPORT_OUTLINE: [u8; 4] = [0xff, 0x00, 0x00, 0xff];
+const WINDOW_OUTLINE: [u8; 4] = [0x00, 0x80, 0xff, 0xff];
+const TRANSPARENT: [u8; 4] = [0x00, 0x00, 0x00, 0x00];
+
+// Every tile in raw colour indices, 16 to a row. On the CGB the second
+// bank sits to the right of the first.
+pub fn tile_sheet(ppu: &Ppu) -> Image {
+    let banks = if ppu.cgb() { 2 } else { 1 };
+    let rows = TILES_PER_BANK / SHEET_COLUMNS;
+    let mut image = Image::new(banks * SHEET_COLUMNS * 8, rows * 8);
+    for bank in 0..banks {
+        for tile in 0..TILES_PER_BANK {
+            let left = (bank * SHEET_COLUMNS + tile % SHEET_COLUMNS) * 8;
+            let top = tile / SHEET_COLUMNS * 8;
+            for row in 0..8 {
+                let data = ppu.tile_row(bank, 0x8000 + tile * 16, row);
+                for x in 0..8 {
+                    let color = Ppu::tile_color(data, 7 - x);
+                    image.set_pixel(left + x, top + row, DMG_SHADES[color as usize]);
+                }
+            }
+        }
+    }
+    image
+}
+
+// The whole 256x256 map at 0x9800 (or 0x9c00 when high_map is set) through
+// the current tile addressing mode and palettes. The background viewport
+// is outlined in red when this is the background map, wrapping around the
+// edges as it does on screen, and the visible part of the window in blue
+// when this is the window map.
+pub fn tile_map(ppu: &Ppu, high_map: bool) -> Image {
+    let mut image = Image::new(MAP_SIZE, MAP_SIZE);
+    for y in 0..MAP_SIZE {
+        for x in 0..MAP_SIZE {
+            let (color, attributes) = ppu.map_pixel(high_map, x, y);
+            image.set_pixel(x, y, bg_rgba(ppu, attributes, color));
+        }
+    }
+
+    if ((ppu.lcdc & 0x08) != 0) == high_map {
+        let (left, top) = (ppu.scx as usize, ppu.scy as usize);
+        outline(
+            &mut image,
+            left,
+            top,
+            SCREEN_WIDTH,
+            SCREEN_HEIGHT,
+            VIEWPORT_OUTLINE,
+        );
+    }
+    let window_x = ppu.wx as usize;
+    let window_y = ppu.wy as usize;
+    let window_visible =
+        (ppu.lcdc & 0x20) != 0 && window_x < SCREEN_WIDTH + 7 && window_y < SCREEN_HEIGHT;
+    if window_visible && ((ppu.lcdc & 0x40) != 0) == high_map {
+        let width = (SCREEN_WIDTH + 7 - window_x).min(SCREEN_WIDTH);
+        outline(
+            &mut image,
+            0,
+            0,
+            width,
+            SCREEN_HEIGHT - window_y,
+            WINDOW_OUTLINE,
+        );
+    }
+    image
+}
+
+fn bg_rgba(ppu: &Ppu, attributes: u8, color: u8) -> [u8; 4] {
+    if ppu.cgb() {
+        rgb555_to_rgba(ppu.bg_palettes.color(attributes & 0x07, color))
+    } else {
+        DMG_SHADES[apply_palette(ppu.bgp, color) as usize]
+    }
+}
+
+fn outline(image: &mut Image, left: usize, top: usize, width: usize, height: usize, rgba: [u8; 4]) {
+    let wrap = |v: usize| v % MAP_SIZE;
+    for dx in 0..width {
+        image.set_pixel(wrap(left + dx), top, rgba);
+        image.set_pixel(wrap(left + dx), wrap(top + height - 1), rgba);
+    }
+    for dy in 0..height {
+        image.set_pixel(left, wrap(top + dy), rgba);
+        image.set_pixel(wrap(left + width - 1), wrap(top + dy), rgba);
+    }
+}
+
+// One OAM entry with its attributes decoded. x and y are screen
+// coordinates, so sprites partly off screen have negative ones.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sprite {
+    pub index: usize,
+    pub x: i16,
+    pub y: i16,
+    pub tile: u8,
+    pub behind_bg: bool,
+    pub y_flip: bool,
+    pub x_flip: bool,
+    pub dmg_palette: u8,
+    pub bank: u8,
+    pub cgb_palette: u8,
+}
+
+impl Sprite {
+    pub fn from_oam(index: usize, entry: &[u8]) -> Sprite {
+        let attributes = entry[3];
+        Sprite {
+            index,
+            x: i16::from(entry[1]) - 8,
+            y: i16::from(entry[0]) - 16,
+            tile: entry[2],
+            behind_bg: (attributes & 0x80) != 0,
+            y_flip: (attributes & 0x40) != 0,
+            x_flip: (attributes & 0x20) != 0,
+            dmg_palette: (attributes >> 4) & 0x01,
+            bank: (attributes >> 3) & 0x01,
+            cgb_palette: attributes & 0x07,
+        }
+    }
+}
+
+impl fmt::Display for Sprite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |set: bool, c: char| if set { c } else { '-' };
+        write!(
+            f,
+            "{:2} {:4} {:4}  ${:02x} {}{}{} OBP{}    {}   {}",
+            self.index,
+            self.x,
+            self.y,
+            self.tile,
+            flag(self.behind_bg, 'P'),
+            flag(self.y_flip, 'Y'),
+            flag(self.x_flip, 'X'),
+            self.dmg_palette,
+            self.bank,
+            self.cgb_palette
+        )
+    }
+}
+
+pub fn sprites(ppu: &Ppu) -> Vec<Sprite> {
+    ppu.oam
+        .chunks(4)
+        .enumerate()
+        .map(|(i, entry)| Sprite::from_oam(i, entry))
+        .collect()
+}
+
+// The decoded OAM as text, one sprite per line. Flags are P for behind
+// the background and Y/X for the flips.
+pub fn oam_table(ppu: &Ppu) -> String {
+    let mut table = String::from(" #    x    y tile PYX  DMG bank CGB\n");
+    for sprite in sprites(ppu) {
+        table.push_str(&format!("{}\n", sprite));
+    }
+    table
+}
+
+// The 40 sprites drawn 8 to a row with their flips and palettes, colour 0
+// left transparent. Cells are 16 pixels high in 8x16 mode.
+pub fn sprite_sheet(ppu: &Ppu) -> Image {
+    let height = ppu.sprite_height() as usize;
+    let mut image = Image::from_rgba(64, 5 * height, TRANSPARENT.repeat(64 * 5 * height));
+    for sprite in sprites(ppu) {
+        let left = sprite.index % 8 * 8;
+        let top = sprite.index / 8 * height;
+        let tile = if height == 16 {
+            sprite.tile & 0xfe
+        } else {
+            sprite.tile
+        };
+        let bank = if ppu.cgb() { sprite.bank as usize } else { 0 };
+        for row in 0..height {
+            let source_row = if sprite.y_flip { height - 1 - row } else { row };
+            let data = ppu.tile_row(bank, 0x8000 + tile as usize * 16, source_row);
+            for x in 0..8 {
+                let bit = if sprite.x_flip { x } else { 7 - x };
+                let color = Ppu::tile_color(data, bit);
+                if color != 0 {
+                    image.set_pixel(left + x, top + row, obj_rgba(ppu, &sprite, color));
+                }
+            }
+        }
+    }
+    image
+}
+
+fn obj_rgba(ppu: &Ppu, sprite: &Sprite, color: u8) -> [u8; 4] {
+    if ppu.cgb() {
+        rgb555_to_rgba(ppu.obj_palettes.color(sprite.cgb_palette, color))
+    } else {
+        let palette = if sprite.dmg_palette == 1 {
+            ppu.obp1
+        } else {
+            ppu.obp0
+        };
+        DMG_SHADES[apply_palette(palette, color) as usize]
+    }
+}
+
+// One row of four swatches per palette: BGP, OBP0 and OBP1 on the DMG, the
+// eight background then eight object palettes on the CGB
+pub fn palettes(ppu: &Ppu) -> Image {
+    let rows: Vec<[[u8; 4]; 4]> = if ppu.cgb() {
+        let cgb_row = |palettes: &ColorPalettes, palette: u8| {
+            let mut row = [[0; 4]; 4];
+            for (color, rgba) in row.iter_mut().enumerate() {
+                *rgba = rgb555_to_rgba(palettes.color(palette, color as u8));
+            }
+            row
+        };
+        (0..8)
+            .map(|p| cgb_row(&ppu.bg_palettes, p))
+            .chain((0..8).map(|p| cgb_row(&ppu.obj_palettes, p)))
+            .collect()
+    } else {
+        [ppu.bgp, ppu.obp0, ppu.obp1]
+            .iter()
+            .map(|&palette| {
+                let mut row = [[0; 4]; 4];
+                for (color, rgba) in row.iter_mut().enumerate() {
+                    *rgba = DMG_SHADES[apply_palette(palette, color as u8) as usize];
+                }
+                row
+            })
+            .collect()
+    };
+
+    let mut image = Image::new(4 * SWATCH_SIZE, rows.len() * SWATCH_SIZE);
+    for (y, row) in rows.iter().enumerate() {
+        for (x, &rgba) in row.iter().enumerate() {
+            for dy in 0..SWATCH_SIZE {
+                for dx in 0..SWATCH_SIZE {
+                    image.set_pixel(x * SWATCH_SIZE + dx, y * SWATCH_SIZE + dy, rgba);
+                }
+            }
+        }
+    }
+    image
+}
+
+// Writes every view into dir as tiles.png, map-9800.png, map-9c00.png,
+// sprites.png, palettes.png and oam.txt
+pub fn write_views<P: AsRef<Path>>(ppu: &Ppu, dir: P) -> io::Result<()> {
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir)?;
+    tile_sheet(ppu).write_png(dir.join("tiles.png"))?;
+    tile_map(ppu, false).write_png(dir.join("map-9800.png"))?;
+    tile_map(ppu, true).write_png(dir.join("map-9c00.png"))?;
+    sprite_sheet(ppu).write_png(dir.join("sprites.png"))?;
+    palettes(ppu).write_png(dir.join("palettes.png"))?;
+    fs::write(dir.join("oam.txt"), oam_table(ppu))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cgb::Model;
+
+    #[test]
+    fn tiles_and_maps() {
+        let mut ppu = Ppu::new();
+        // Tile 1 is solid colour 3 and fills the top left of the 0x9800 map
+        for i in 0..16 {
+            ppu.vram[0x10 + i] = 0xff;
+        }
+        ppu.vram[0x1800] = 1;
+        ppu.bgp = 0xe4;
+        ppu.lcdc = 0x91;
+        ppu.scx = 200;
+        ppu.scy = 8;
+
+        let sheet = tile_sheet(&ppu);
+        assert_eq!((sheet.width, sheet.height), (128, 192));
+        assert_eq!(sheet.pixel(7, 0), DMG_SHADES[0]);
+        assert_eq!(sheet.pixel(8, 0), DMG_SHADES[3]);
+        assert_eq!(tile_sheet(&Ppu::with_model(Model::Cgb)).width, 256);
+
+        let map = tile_map(&ppu, false);
+        assert_eq!(map.pixel(1, 1), DMG_SHADES[3]);
+        assert_eq!(map.pixel(9, 1), DMG_SHADES[0]);
+        // The viewport wraps from x=200 round to x=103
+        assert_eq!(map.pixel(200, 8), VIEWPORT_OUTLINE);
+        assert_eq!(map.pixel(103, 151), VIEWPORT_OUTLINE);
+        assert_eq!(map.pixel(104, 151), DMG_SHADES[0]);
+        assert_eq!(tile_map(&ppu, true).pixel(200, 8), DMG_SHADES[0]);
+    }
+
+    #[test]
+    fn decodes_oam() {
+        let mut ppu = Ppu::new();
+        ppu.vram[0x20] = 0x80;
+        ppu.obp1 = 0xe4;
+        ppu.oam[4..8].copy_from_slice(&[20, 4, 2, 0xb0]);
+
+        let sprite = sprites(&ppu)[1];
+        assert_eq!((sprite.x, sprite.y, sprite.tile), (-4, 4, 2));
+        assert!(sprite.behind_bg && sprite.x_flip && !sprite.y_flip);
+        assert_eq!(sprite.dmg_palette, 1);
+        assert_eq!(
+            oam_table(&ppu).lines().nth(2).unwrap(),
+            " 1   -4    4  $02 P-X OBP1    0   0"
+        );
+
+        // Flipped, the leftmost pixel of the tile ends up on the right
+        let sheet = sprite_sheet(&ppu);
+        assert_eq!(sheet.pixel(15, 0), DMG_SHADES[1]);
+        assert_eq!(sheet.pixel(8, 0), TRANSPARENT);
+        assert_eq!(palettes(&ppu).height, 3 * SWATCH_SIZE);
+    }
+}
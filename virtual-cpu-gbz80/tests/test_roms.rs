@@ -0,0 +1,42 @@
+// Runs the Blargg and mooneye test ROMs found under tests/roms/blargg and
+// tests/roms/mooneye, or under $GBZ80_TEST_ROMS if set. The ROMs are not
+// part of the repository, so a missing directory is skipped.
+
+use std::env;
+use std::path::PathBuf;
+
+use virtual_cpu_gbz80::test_rom::{run_test_rom_dir, TestKind};
+
+const BLARGG_BUDGET: u64 = 400_000_000;
+const MOONEYE_BUDGET: u64 = 50_000_000;
+
+fn roms_dir(suite: &str) -> PathBuf {
+    env::var_os("GBZ80_TEST_ROMS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/roms"))
+        .join(suite)
+}
+
+fn run_suite(suite: &str, kind: TestKind, budget: u64) {
+    let dir = roms_dir(suite);
+    if !dir.is_dir() {
+        eprintln!("Skipping {}: no ROMs in {}", suite, dir.display());
+        return;
+    }
+    let reports = run_test_rom_dir(&dir, kind, budget).expect("could not run test ROMs");
+    for report in reports.iter() {
+        println!("{}", report);
+    }
+    let failed = reports.iter().filter(|r| !r.passed()).count();
+    assert_eq!(failed, 0, "{} of {} ROMs failed", failed, reports.len());
+}
+
+#[test]
+fn blargg() {
+    run_suite("blargg", TestKind::Blargg, BLARGG_BUDGET);
+}
+
+#[test]
+fn mooneye() {
+    run_suite("mooneye", TestKind::Mooneye, MOONEYE_BUDGET);
+}